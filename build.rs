@@ -0,0 +1,5 @@
+extern crate capnpc;
+
+fn main() {
+    ::capnpc::compile("schema", &["schema/paenko.capnp"]).expect("Unable to compile the schema");
+}
@@ -0,0 +1,667 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use std::thread::spawn;
+
+use capnp::message::{Builder, HeapAllocator, ReaderOptions};
+use capnp_nonblock::MessageStream;
+use futures::{Future, Poll, Async};
+use futures::sync::oneshot;
+use mio;
+use mio::{EventLoop, EventSet, PollOpt, Sender, Token};
+use mio::tcp::TcpStream;
+
+use uuid::Uuid;
+use raft::LogId;
+use raft::TransactionId;
+
+use document::Document;
+use error::ClientError;
+use handler::{Consistency, RequestId, Result};
+use protocol::*;
+use paenko_capnp::{request, response};
+
+/// Writes the body of a request
+type Build = Box<FnMut(request::Builder) + Send>;
+
+/// Completes the future of a request with its response
+type Complete = Box<FnMut(Result<response::Reader>) + Send>;
+
+type Stream = MessageStream<TcpStream, HeapAllocator, Rc<Builder<HeapAllocator>>>;
+
+/// A request which is sent by the event loop of the connection
+struct Call {
+    session: Option<TransactionId>,
+    request_id: Option<RequestId>,
+    build: Build,
+    complete: Complete,
+}
+
+/// The messages of an `AsyncClient` to its event loop
+enum Command {
+    Call(Call),
+    /// The last clone of the client was dropped
+    Shutdown,
+}
+
+fn closed(reason: &str) -> ClientError {
+    ClientError::Io(io::Error::new(ErrorKind::ConnectionAborted, reason.to_string()))
+}
+
+/// The response of a request of an `AsyncClient`
+pub struct ClientFuture<T> {
+    receiver: oneshot::Receiver<Result<T>>,
+}
+
+impl<T> Future for ClientFuture<T> {
+    type Item = T;
+    type Error = ClientError;
+
+    fn poll(&mut self) -> Poll<T, ClientError> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Ok(value))) => Ok(Async::Ready(value)),
+            Ok(Async::Ready(Err(err))) => Err(err),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err(ClientError::Protocol("The client was stopped".to_string())),
+        }
+    }
+}
+
+/// Sends `Shutdown` to the event loop when the last clone of the client is dropped
+struct Stop(Sender<Command>);
+
+impl Drop for Stop {
+    fn drop(&mut self) {
+        let _ = self.0.send(Command::Shutdown);
+    }
+}
+
+/// A client whose methods return futures instead of blocking the calling thread.
+///
+/// All requests are sent on one connection to the Cap'n Proto endpoint of a node, which
+/// is driven by an event loop of the client. Every request is tagged, so the node runs
+/// them concurrently and the responses are matched to their futures in any order. The
+/// node sends the requests to the cluster as `handler::Message` like the blocking
+/// client.
+///
+/// The event loop stops when the last clone of the client is dropped.
+#[derive(Clone)]
+pub struct AsyncClient {
+    calls: Sender<Command>,
+    stop: Arc<Stop>,
+}
+
+impl AsyncClient {
+    /// Connects to the Cap'n Proto endpoint of a node
+    ///
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` of the requests
+    pub fn connect(addr: &SocketAddr,
+                   username: &str,
+                   plain_password: &str,
+                   lid: LogId)
+                   -> Result<AsyncClient> {
+        let addr = *addr;
+        let username = username.to_string();
+        let password = plain_password.to_string();
+        let (started, wait) = channel();
+
+        spawn(move || {
+            let (mut event_loop, stream) = match open(&addr) {
+                Ok(opened) => opened,
+                Err(err) => {
+                    let _ = started.send(Err(err));
+                    return;
+                }
+            };
+
+            let _ = started.send(Ok(event_loop.channel()));
+
+            let mut connection = Connection {
+                stream: MessageStream::new(stream, ReaderOptions::new()),
+                username: username,
+                password: password,
+                lid: lid,
+                next_tag: 0,
+                pending: HashMap::new(),
+                closed: None,
+            };
+
+            if let Err(err) = event_loop.run(&mut connection) {
+                println!("The event loop of the client {} stopped: {}", addr, err);
+            }
+        });
+
+        let calls = try!(try!(wait.recv()
+            .map_err(|_| ClientError::Protocol("The client was stopped".to_string()))));
+
+        Ok(AsyncClient {
+            stop: Arc::new(Stop(calls.clone())),
+            calls: calls,
+        })
+    }
+
+    /// Sends a request. `handle` gets the answer of the response or the error which was
+    /// sent by the node
+    fn send<T, B, H>(&self,
+                     session: Option<TransactionId>,
+                     request_id: Option<RequestId>,
+                     build: B,
+                     handle: H)
+                     -> ClientFuture<T>
+        where T: Send + 'static,
+              B: FnOnce(request::Builder) + Send + 'static,
+              H: FnOnce(Result<response::WhichReader>) -> Result<T> + Send + 'static
+    {
+        let (complete, receiver) = oneshot::channel();
+        let mut build = Some(build);
+        let mut pending = Some((handle, complete));
+
+        let call = Call {
+            session: session,
+            request_id: request_id,
+            build: Box::new(move |request: request::Builder| if let Some(build) = build.take() {
+                build(request)
+            }),
+            complete: Box::new(move |response: Result<response::Reader>| {
+                if let Some((handle, complete)) = pending.take() {
+                    // The future might be dropped already, then the response is discarded
+                    let _ = complete.send(handle(response.and_then(get_response)));
+                }
+            }),
+        };
+
+        // If the event loop stopped, the dropped call cancels the future
+        let _ = self.calls.send(Command::Call(call));
+
+        ClientFuture { receiver: receiver }
+    }
+
+    /// Sends a request and reads the answer of its response with `decode`
+    fn call<T, B, D>(&self,
+                     session: Option<TransactionId>,
+                     request_id: Option<RequestId>,
+                     build: B,
+                     decode: D)
+                     -> ClientFuture<T>
+        where T: Send + 'static,
+              B: FnOnce(request::Builder) + Send + 'static,
+              D: FnOnce(response::WhichReader) -> Result<T> + Send + 'static
+    {
+        self.send(session,
+                  request_id,
+                  build,
+                  move |response: Result<response::WhichReader>| response.and_then(decode))
+    }
+
+    /// Gets a document
+    pub fn get(&self, id: Uuid, consistency: Consistency) -> ClientFuture<Document> {
+        self.call(None,
+                  None,
+                  move |request| {
+                      let mut get = request.init_get();
+                      get.set_id(&id.to_string());
+                      get.set_consistency(from_consistency(consistency));
+                  },
+                  read_document)
+    }
+
+    /// Inserts a new document. Fails if a document with the same id already exists
+    pub fn post(&self, document: Document, request: Option<RequestId>) -> ClientFuture<Uuid> {
+        self.call(None,
+                  request,
+                  move |request| set_document(request.init_post(), &document),
+                  read_id)
+    }
+
+    /// Inserts a document or replaces the payload of the document if the id already exists
+    pub fn upsert(&self, document: Document, request: Option<RequestId>) -> ClientFuture<Uuid> {
+        self.call(None,
+                  request,
+                  move |request| set_document(request.init_upsert(), &document),
+                  read_id)
+    }
+
+    /// Replaces the payload of a document
+    pub fn put(&self,
+               id: Uuid,
+               new_payload: Vec<u8>,
+               request: Option<RequestId>)
+               -> ClientFuture<()> {
+        self.call(None, request, move |request| set_put(request, id, &new_payload), read_ok)
+    }
+
+    /// Removes a document
+    pub fn remove(&self, id: Uuid, request: Option<RequestId>) -> ClientFuture<()> {
+        self.call(None,
+                  request,
+                  move |mut request| request.set_remove(&id.to_string()),
+                  read_ok)
+    }
+
+    /// Begins a new transaction and returns its handle
+    pub fn transaction(&self) -> ClientFuture<AsyncTransaction> {
+        let client = self.clone();
+
+        self.call(Some(TransactionId::new()),
+                  None,
+                  |mut request| request.set_begin_transaction(()),
+                  move |response| {
+            Ok(AsyncTransaction {
+                client: client,
+                session: try!(read_session(response)),
+                finished: false,
+            })
+        })
+    }
+}
+
+/// A transaction of an `AsyncClient`. All writes of the handle belong to the transaction.
+/// It is committed with `commit` and rolled back when it is dropped before.
+pub struct AsyncTransaction {
+    client: AsyncClient,
+    session: TransactionId,
+    finished: bool,
+}
+
+impl AsyncTransaction {
+    /// Returns the `TransactionId` of the transaction
+    pub fn id(&self) -> TransactionId {
+        self.session
+    }
+
+    /// Gets a document. The uncommitted writes of the transaction are visible
+    pub fn get(&self, id: Uuid) -> ClientFuture<Document> {
+        self.client.get(id, Consistency::Leader)
+    }
+
+    /// Inserts a new document. Fails if a document with the same id already exists
+    pub fn post(&self, document: Document) -> ClientFuture<Uuid> {
+        self.client.call(Some(self.session),
+                         None,
+                         move |request| set_document(request.init_post(), &document),
+                         read_id)
+    }
+
+    /// Inserts a document or replaces the payload of the document if the id already exists
+    pub fn upsert(&self, document: Document) -> ClientFuture<Uuid> {
+        self.client.call(Some(self.session),
+                         None,
+                         move |request| set_document(request.init_upsert(), &document),
+                         read_id)
+    }
+
+    /// Replaces the payload of a document
+    pub fn put(&self, id: Uuid, new_payload: Vec<u8>) -> ClientFuture<()> {
+        self.client.call(Some(self.session),
+                         None,
+                         move |request| set_put(request, id, &new_payload),
+                         read_ok)
+    }
+
+    /// Removes a document
+    pub fn remove(&self, id: Uuid) -> ClientFuture<()> {
+        self.client.call(Some(self.session),
+                         None,
+                         move |mut request| request.set_remove(&id.to_string()),
+                         read_ok)
+    }
+
+    /// Commits the transaction. It is rolled back if the commit fails
+    pub fn commit(mut self) -> ClientFuture<()> {
+        let client = self.client.clone();
+        let session = self.session;
+
+        client.send(Some(session),
+                    None,
+                    |mut request| request.set_commit_transaction(()),
+                    move |response: Result<response::WhichReader>| {
+            let result = response.and_then(read_ok);
+            // the handle is dropped with the response and rolls back a failed commit
+            self.finished = result.is_ok();
+            result
+        })
+    }
+
+    /// Rollbacks the transaction
+    pub fn rollback(mut self) -> ClientFuture<()> {
+        self.finished = true;
+        self.client.call(Some(self.session),
+                         None,
+                         |mut request| request.set_rollback_transaction(()),
+                         read_ok)
+    }
+}
+
+impl Drop for AsyncTransaction {
+    fn drop(&mut self) {
+        if !self.finished {
+            // nobody waits for the response of the rollback
+            self.client.call(Some(self.session),
+                             None,
+                             |mut request| request.set_rollback_transaction(()),
+                             read_ok);
+        }
+    }
+}
+
+fn set_put(request: request::Builder, id: Uuid, payload: &[u8]) {
+    let mut put = request.init_put();
+    put.set_id(&id.to_string());
+    put.set_payload(payload);
+}
+
+fn read_ok(response: response::WhichReader) -> Result<()> {
+    match response {
+        response::Which::Ok(()) => Ok(()),
+        _ => Err(unexpected_response()),
+    }
+}
+
+fn read_id(response: response::WhichReader) -> Result<Uuid> {
+    match response {
+        response::Which::Id(id) => parse_id(try!(id)),
+        _ => Err(unexpected_response()),
+    }
+}
+
+fn read_session(response: response::WhichReader) -> Result<TransactionId> {
+    match response {
+        response::Which::Id(id) => parse_session(try!(id)),
+        _ => Err(unexpected_response()),
+    }
+}
+
+fn read_document(response: response::WhichReader) -> Result<Document> {
+    match response {
+        response::Which::Document(document) => get_document(try!(document)),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Connects to the node and registers the connection in a new event loop
+fn open(addr: &SocketAddr) -> io::Result<(EventLoop<Connection>, TcpStream)> {
+    let mut event_loop = try!(EventLoop::new());
+    let stream = try!(TcpStream::connect(addr));
+
+    try!(event_loop.register(&stream, Token(0), EventSet::all(), PollOpt::edge()));
+
+    Ok((event_loop, stream))
+}
+
+/// The connection of an `AsyncClient` which is driven by its event loop
+struct Connection {
+    stream: Stream,
+    username: String,
+    password: String,
+    lid: LogId,
+    next_tag: u64,
+    /// The requests in flight by their tag
+    pending: HashMap<u64, Complete>,
+    /// Why the connection was closed. Later requests fail with the reason
+    closed: Option<String>,
+}
+
+impl Connection {
+    /// Writes a request and keeps its completion until the response with its tag arrives
+    fn send(&mut self, mut call: Call) {
+        if let Some(ref reason) = self.closed {
+            return (call.complete)(Err(closed(reason)));
+        }
+
+        self.next_tag += 1;
+        let tag = self.next_tag;
+
+        let mut message = Builder::new_default();
+        {
+            let mut request = message.init_root::<request::Builder>();
+
+            request.set_tag(tag);
+            request.set_username(&self.username);
+            request.set_password(&self.password);
+            request.set_lid(&self.lid.to_string());
+
+            if let Some(request_id) = call.request_id {
+                request.set_request_id(&request_id.to_string());
+            }
+            if let Some(session) = call.session {
+                request.set_session(&session.to_string());
+            }
+
+            (call.build)(request);
+        }
+
+        match self.stream.write_message(Rc::new(message)) {
+            Ok(_) => {
+                self.pending.insert(tag, call.complete);
+            }
+            Err(err) => (call.complete)(Err(ClientError::from(err))),
+        }
+    }
+
+    /// Completes the requests of all received responses
+    fn receive(&mut self) -> Result<()> {
+        while let Some(message) = try!(self.stream.read_message()) {
+            let response = try!(message.get_root::<response::Reader>());
+            let tag = response.get_tag();
+
+            match self.pending.remove(&tag) {
+                Some(mut complete) => complete(Ok(response)),
+                None => return Err(ClientError::Protocol(format!("Unknown tag {}", tag))),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the connection and fails the requests in flight
+    fn close(&mut self, event_loop: &mut EventLoop<Connection>, reason: String) {
+        let _ = event_loop.deregister(self.stream.inner());
+
+        for (_, mut complete) in self.pending.drain() {
+            complete(Err(closed(&reason)));
+        }
+
+        self.closed = Some(reason);
+    }
+}
+
+impl mio::Handler for Connection {
+    type Timeout = ();
+    type Message = Command;
+
+    fn ready(&mut self, event_loop: &mut EventLoop<Connection>, _: Token, events: EventSet) {
+        if self.closed.is_some() {
+            return;
+        }
+
+        // the responses which arrived before the connection was closed are completed
+        let mut result = Ok(());
+        if events.is_readable() {
+            result = self.receive();
+        }
+        if result.is_ok() && events.is_writable() {
+            result = self.stream.write().map_err(ClientError::from);
+        }
+        if result.is_ok() && (events.is_error() || events.is_hup()) {
+            result = Err(closed("The node closed the connection"));
+        }
+
+        if let Err(err) = result {
+            self.close(event_loop, format!("{}", err));
+        }
+    }
+
+    fn notify(&mut self, event_loop: &mut EventLoop<Connection>, command: Command) {
+        match command {
+            Command::Call(call) => self.send(call),
+            Command::Shutdown => event_loop.shutdown(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use std::collections::HashMap;
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::Path;
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
+
+    use futures::Future;
+    use futures::future::join_all;
+    use self::tempdir::TempDir;
+    use uuid::Uuid;
+
+    use raft::{LogId, Server, ServerId};
+    use raft::auth::Auth;
+    use raft::auth::sha256::Sha256Auth;
+    use raft::auth::credentials::SingleCredentials;
+
+    use capnp_handler;
+    use client::PaenkoClient;
+    use doclog::DocLog;
+    use document::{Document, DocumentError, parse_addr};
+    use error::ClientError;
+    use handler::Consistency;
+    use statemachine::DocumentStateMachine;
+
+    const USERNAME: &'static str = "test";
+    const PASSWORD: &'static str = "secret";
+
+    /// Returns an address on the loopback interface with a port which is free
+    fn free_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    /// Waits until the node elected itself as the leader of the log
+    fn wait_for_leader(addr: SocketAddr, lid: LogId) {
+        let mut client = PaenkoClient::new(vec![addr], USERNAME, PASSWORD, lid);
+
+        for _ in 0..100 {
+            if client.get_usage().is_ok() {
+                return;
+            }
+
+            sleep(Duration::from_millis(100));
+        }
+
+        panic!("The node {} did not become the leader of the log", addr);
+    }
+
+    /// Starts a cluster with a single node in this process and returns the address of its
+    /// Cap'n Proto endpoint when the node is the leader
+    fn start_cluster(dir: &Path) -> (SocketAddr, LogId) {
+        let addr = free_addr();
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+        let path = dir.to_path_buf();
+
+        spawn(move || {
+            // the password is saved like in the config, hashed by the auth of the node
+            let hasher = Sha256Auth::new(SingleCredentials::new(String::new(), String::new()));
+            let credentials = SingleCredentials::new(USERNAME.to_string(),
+                                                     hasher.hash(&PASSWORD.to_string()));
+            let logs = vec![(lid,
+                             DocLog::new(&path, lid),
+                             DocumentStateMachine::new(path.to_str().unwrap()))];
+
+            let (mut server, mut event_loop) = Server::new(ServerId::from(1),
+                                                           addr,
+                                                           &HashMap::new(),
+                                                           "test".to_string(),
+                                                           Sha256Auth::new(credentials),
+                                                           logs)
+                .unwrap();
+
+            server.init(&mut event_loop);
+            event_loop.run(&mut server).unwrap();
+        });
+
+        let capnp_addr = capnp_handler::init(parse_addr("127.0.0.1:0").unwrap(), addr).unwrap();
+        wait_for_leader(addr, lid);
+
+        (capnp_addr, lid)
+    }
+
+    fn document(payload: &[u8]) -> Document {
+        Document {
+            id: Uuid::new_v4(),
+            payload: payload.to_vec(),
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_concurrent_requests() {
+        let dir = TempDir::new("cluster").unwrap();
+        let (addr, lid) = start_cluster(dir.path());
+        let client = AsyncClient::connect(&addr, USERNAME, PASSWORD, lid).unwrap();
+
+        let documents: Vec<Document> = (0..16u8).map(|i| document(&[i])).collect();
+
+        // all requests are in flight on the connection at the same time
+        let ids = join_all(documents.iter()
+                .map(|document| client.post(document.clone(), None))
+                .collect::<Vec<_>>())
+            .wait()
+            .unwrap();
+
+        for (id, document) in ids.into_iter().zip(documents.iter()) {
+            assert_eq!(document.id, id);
+
+            let stored = client.get(id, Consistency::Leader).wait().unwrap();
+            assert_eq!(document.payload, stored.payload);
+        }
+
+        client.remove(documents[0].id, None).wait().unwrap();
+
+        match client.get(documents[0].id, Consistency::Leader).wait() {
+            Err(ClientError::NotFound(DocumentError::NotFound(_))) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_transaction() {
+        let dir = TempDir::new("cluster").unwrap();
+        let (addr, lid) = start_cluster(dir.path());
+        let client = AsyncClient::connect(&addr, USERNAME, PASSWORD, lid).unwrap();
+
+        let committed = document(b"first");
+        let transaction = client.transaction().wait().unwrap();
+        let id = transaction.post(committed).wait().unwrap();
+        transaction.put(id, b"second".to_vec()).wait().unwrap();
+        transaction.commit().wait().unwrap();
+
+        let stored = client.get(id, Consistency::Leader).wait().unwrap();
+        assert_eq!(b"second".to_vec(), stored.payload);
+
+        let rolled_back = document(b"rolled back");
+        let transaction = client.transaction().wait().unwrap();
+        transaction.post(rolled_back.clone()).wait().unwrap();
+        transaction.rollback().wait().unwrap();
+
+        assert!(client.get(rolled_back.id, Consistency::Leader).wait().is_err());
+    }
+
+    #[test]
+    fn test_closed_connection() {
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+        // nothing listens on the address
+        let client = AsyncClient::connect(&free_addr(), USERNAME, PASSWORD, lid).unwrap();
+
+        match client.get(Uuid::new_v4(), Consistency::Leader).wait() {
+            Err(ClientError::Io(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+}
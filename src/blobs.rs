@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+/// The hex encoded SHA-256 hash of a payload
+pub type Hash = String;
+
+pub fn hash(payload: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    hasher.result_str()
+}
+
+#[derive(Debug,Clone,Serialize,Deserialize)]
+struct Blob {
+    payload: Vec<u8>,
+    /// The number of documents and undo records which reference the payload
+    references: usize,
+}
+
+/// Summary of a `BlobStore` which is returned to clients
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct BlobStats {
+    /// The number of stored payloads
+    pub blobs: usize,
+    /// The number of references to the stored payloads
+    pub references: usize,
+    /// The number of bytes of the stored payloads
+    pub bytes: usize,
+    /// The number of bytes which were saved by the deduplication
+    pub saved_bytes: usize,
+}
+
+/// Content-addressed store of payloads. Every payload is stored once by its hash and
+/// counts its references. A payload is dropped as soon as its last reference is released.
+#[derive(Debug,Clone,Serialize,Deserialize,Default)]
+pub struct BlobStore {
+    blobs: HashMap<Hash, Blob>,
+}
+
+impl BlobStore {
+    pub fn new() -> Self {
+        BlobStore { blobs: HashMap::new() }
+    }
+
+    /// Adds a reference to the payload and returns its hash. The payload is only stored
+    /// if there is no equal one.
+    pub fn insert(&mut self, payload: Vec<u8>) -> Hash {
+        let hash = hash(&payload);
+
+        self.blobs
+            .entry(hash.clone())
+            .or_insert_with(|| {
+                Blob {
+                    payload: payload,
+                    references: 0,
+                }
+            })
+            .references += 1;
+
+        hash
+    }
+
+    /// Adds a reference to a stored payload
+    pub fn retain(&mut self, hash: &str) {
+        if let Some(blob) = self.blobs.get_mut(hash) {
+            blob.references += 1;
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blobs.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&[u8]> {
+        self.blobs.get(hash).map(|blob| blob.payload.as_slice())
+    }
+
+    /// Releases a reference to the payload and returns it. The payload is removed from the
+    /// store with its last reference.
+    pub fn release(&mut self, hash: &str) -> Option<Vec<u8>> {
+        let references = match self.blobs.get_mut(hash) {
+            Some(blob) => {
+                blob.references -= 1;
+                blob.references
+            }
+            None => return None,
+        };
+
+        if references == 0 {
+            self.blobs.remove(hash).map(|blob| blob.payload)
+        } else {
+            self.get(hash).map(|payload| payload.to_vec())
+        }
+    }
+
+    pub fn stats(&self) -> BlobStats {
+        let mut stats = BlobStats {
+            blobs: self.blobs.len(),
+            references: 0,
+            bytes: 0,
+            saved_bytes: 0,
+        };
+
+        for blob in self.blobs.values() {
+            stats.references += blob.references;
+            stats.bytes += blob.payload.len();
+            stats.saved_bytes += (blob.references - 1) * blob.payload.len();
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_reference_counting() {
+        let mut store = BlobStore::new();
+
+        let first = store.insert(b"attachment".to_vec());
+        let second = store.insert(b"attachment".to_vec());
+        let other = store.insert(b"other".to_vec());
+
+        assert_eq!(first, second);
+        assert_eq!(2, store.stats().blobs);
+        assert_eq!(3, store.stats().references);
+
+        assert_eq!(Some(b"attachment".to_vec()), store.release(&first));
+        assert_eq!(Some(&b"attachment"[..]), store.get(&first));
+
+        assert_eq!(Some(b"attachment".to_vec()), store.release(&second));
+        assert_eq!(None, store.get(&first));
+
+        store.retain(&other);
+        assert_eq!(Some(b"other".to_vec()), store.release(&other));
+        assert_eq!(Some(b"other".to_vec()), store.release(&other));
+        assert_eq!(0, store.stats().blobs);
+    }
+}
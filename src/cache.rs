@@ -0,0 +1,202 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use raft::LogId;
+
+use document::{Document, DocumentId};
+
+/// Configures the read cache of a `PaenkoClient`
+#[derive(Debug,Clone,PartialEq)]
+pub struct CacheConfig {
+    /// The maximum number of cached documents. The least recently used one is dropped
+    /// first
+    pub capacity: usize,
+    /// A `Stale` read returns a cached document without a request for this duration after
+    /// it was fetched or revalidated. All other reads revalidate it by its version.
+    pub max_staleness: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: 1024,
+            max_staleness: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Entry {
+    document: Document,
+    /// When the version of the document was last confirmed by the cluster
+    validated: Instant,
+    /// The tick of the last use
+    used: u64,
+}
+
+/// A LRU cache of documents by their log
+pub struct ReadCache {
+    config: CacheConfig,
+    entries: HashMap<(LogId, DocumentId), Entry>,
+    /// The keys by the tick of their last use, the oldest first
+    usage: BTreeMap<u64, (LogId, DocumentId)>,
+    tick: u64,
+}
+
+impl ReadCache {
+    pub fn new(config: CacheConfig) -> ReadCache {
+        ReadCache {
+            config: config,
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Returns a cached document and whether it was validated within the maximum staleness
+    pub fn get(&mut self, lid: LogId, id: DocumentId) -> Option<(Document, bool)> {
+        let tick = self.next_tick();
+
+        match self.entries.get_mut(&(lid, id)) {
+            Some(entry) => {
+                self.usage.remove(&entry.used);
+                self.usage.insert(tick, (lid, id));
+                entry.used = tick;
+
+                let fresh = entry.validated.elapsed() <= self.config.max_staleness;
+
+                Some((entry.document.clone(), fresh))
+            }
+            None => None,
+        }
+    }
+
+    /// Caches a document which was just fetched from the cluster
+    pub fn insert(&mut self, lid: LogId, document: Document) {
+        if self.config.capacity == 0 {
+            return;
+        }
+
+        self.remove(lid, document.id);
+
+        while self.entries.len() >= self.config.capacity {
+            let oldest = match self.usage.keys().next() {
+                Some(&tick) => tick,
+                None => break,
+            };
+
+            if let Some(key) = self.usage.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+
+        let tick = self.next_tick();
+
+        self.usage.insert(tick, (lid, document.id));
+        self.entries.insert((lid, document.id),
+                            Entry {
+                                document: document,
+                                validated: Instant::now(),
+                                used: tick,
+                            });
+    }
+
+    /// Marks a cached document as confirmed by the cluster
+    pub fn validate(&mut self, lid: LogId, id: DocumentId) {
+        if let Some(entry) = self.entries.get_mut(&(lid, id)) {
+            entry.validated = Instant::now();
+        }
+    }
+
+    pub fn remove(&mut self, lid: LogId, id: DocumentId) {
+        if let Some(entry) = self.entries.remove(&(lid, id)) {
+            self.usage.remove(&entry.used);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.usage.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use document::Document;
+    use raft::LogId;
+    use uuid::Uuid;
+
+    fn document(payload: &[u8]) -> Document {
+        Document {
+            id: Uuid::new_v4(),
+            payload: payload.to_vec(),
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+        let mut cache = ReadCache::new(CacheConfig {
+            capacity: 2,
+            max_staleness: Duration::from_secs(60),
+        });
+
+        let first = document(b"first");
+        let second = document(b"second");
+        let third = document(b"third");
+
+        cache.insert(lid, first.clone());
+        cache.insert(lid, second.clone());
+
+        // the first document becomes the most recently used one
+        assert_eq!(Some((first.clone(), true)), cache.get(lid, first.id));
+
+        cache.insert(lid, third.clone());
+
+        assert_eq!(2, cache.len());
+        assert!(cache.get(lid, second.id).is_none());
+        assert!(cache.get(lid, first.id).is_some());
+        assert!(cache.get(lid, third.id).is_some());
+
+        cache.remove(lid, first.id);
+        assert!(cache.get(lid, first.id).is_none());
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn test_staleness() {
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+        let other = LogId::from("2275a90d-5884-4498-9ba5-91122e2fe875").unwrap();
+        let mut cache = ReadCache::new(CacheConfig {
+            capacity: 10,
+            max_staleness: Duration::from_secs(0),
+        });
+
+        let doc = document(b"test");
+        cache.insert(lid, doc.clone());
+
+        ::std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(Some((doc.clone(), false)), cache.get(lid, doc.id));
+        assert!(cache.get(other, doc.id).is_none());
+    }
+}
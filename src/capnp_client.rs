@@ -0,0 +1,646 @@
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use capnp::message::{Builder, Reader, ReaderOptions};
+use capnp::serialize::{self, OwnedSegments};
+
+use uuid::Uuid;
+use raft::LogId;
+use raft::TransactionId;
+
+use delta::Delta;
+use document::{Deletion, Document, DocumentError, DocumentId, MultiGet};
+use error::ClientError;
+use handler::{Consistency, RequestId, Result};
+use protocol::*;
+use quota::{Quota, Usage};
+use transaction::TransactionInfo;
+use upload::{UploadId, CHUNK_SIZE};
+use paenko_capnp::{request, response};
+
+/// Returns the response or the error which was sent by the node
+fn read_response<'a>(message: &'a Reader<OwnedSegments>) -> Result<response::WhichReader<'a>> {
+    get_response(try!(message.get_root::<response::Reader>()))
+}
+
+/// Checks the response of a write
+fn read_ok(message: &Reader<OwnedSegments>) -> Result<()> {
+    match try!(read_response(message)) {
+        response::Which::Ok(()) => Ok(()),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Returns the id of the response
+fn read_id(message: &Reader<OwnedSegments>) -> Result<Uuid> {
+    match try!(read_response(message)) {
+        response::Which::Id(id) => parse_id(try!(id)),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// Returns the length of a payload of the response
+fn read_length(message: &Reader<OwnedSegments>) -> Result<u64> {
+    match try!(read_response(message)) {
+        response::Which::Length(length) => Ok(length),
+        _ => Err(unexpected_response()),
+    }
+}
+
+/// A client of the Cap'n Proto endpoint of a node. It is encoded with
+/// `schema/paenko.capnp` like the clients of other languages and backs the `Handler`.
+/// The node forwards the requests to the leader.
+pub struct CapnpClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    username: String,
+    password: String,
+    lid: LogId,
+}
+
+impl CapnpClient {
+    /// Connects to the Cap'n Proto endpoint of a node
+    ///
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` of the requests
+    pub fn connect(addr: &SocketAddr,
+                   username: &str,
+                   plain_password: &str,
+                   lid: LogId)
+                   -> Result<CapnpClient> {
+        let stream = try!(TcpStream::connect(addr));
+
+        Ok(CapnpClient {
+            reader: BufReader::new(try!(stream.try_clone())),
+            writer: BufWriter::new(stream),
+            username: username.to_string(),
+            password: plain_password.to_string(),
+            lid: lid,
+        })
+    }
+
+    /// Returns the `LogId` of the requests
+    pub fn lid(&self) -> LogId {
+        self.lid
+    }
+
+    /// Changes the `LogId` of the requests
+    pub fn set_lid(&mut self, lid: LogId) {
+        self.lid = lid;
+    }
+
+    /// Sends a request and waits for its response
+    fn call<F>(&mut self,
+               session: Option<TransactionId>,
+               request_id: Option<RequestId>,
+               build: F)
+               -> Result<Reader<OwnedSegments>>
+        where F: FnOnce(request::Builder)
+    {
+        let mut message = Builder::new_default();
+        {
+            let mut request = message.init_root::<request::Builder>();
+
+            request.set_username(&self.username);
+            request.set_password(&self.password);
+            request.set_lid(&self.lid.to_string());
+
+            if let Some(request_id) = request_id {
+                request.set_request_id(&request_id.to_string());
+            }
+            if let Some(session) = session {
+                request.set_session(&session.to_string());
+            }
+
+            build(request);
+        }
+
+        try!(serialize::write_message(&mut self.writer, &message));
+        try!(self.writer.flush());
+
+        Ok(try!(serialize::read_message(&mut self.reader, ReaderOptions::new())))
+    }
+
+    /// Gets a document
+    pub fn get(&mut self, id: Uuid, consistency: Consistency) -> Result<Document> {
+        let message = try!(self.call(None, None, |request| {
+            let mut get = request.init_get();
+            get.set_id(&id.to_string());
+            get.set_consistency(from_consistency(consistency));
+        }));
+
+        match try!(read_response(&message)) {
+            response::Which::Document(document) => get_document(try!(document)),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Inserts a new document. Fails if a document with the same id already exists
+    pub fn post(&mut self,
+                document: Document,
+                session: TransactionId,
+                request: Option<RequestId>)
+                -> Result<Uuid> {
+        let message = try!(self.call(Some(session),
+                                     request,
+                                     |request| set_document(request.init_post(), &document)));
+
+        read_id(&message)
+    }
+
+    /// Inserts a document or replaces the payload of the document if the id already exists
+    pub fn upsert(&mut self,
+                  document: Document,
+                  session: TransactionId,
+                  request: Option<RequestId>)
+                  -> Result<Uuid> {
+        let message = try!(self.call(Some(session),
+                                     request,
+                                     |request| set_document(request.init_upsert(), &document)));
+
+        read_id(&message)
+    }
+
+    /// Replaces the payload of a document
+    pub fn put(&mut self,
+               id: Uuid,
+               new_payload: Vec<u8>,
+               session: TransactionId,
+               request: Option<RequestId>)
+               -> Result<()> {
+        let message = try!(self.call(Some(session), request, |request| {
+            let mut put = request.init_put();
+            put.set_id(&id.to_string());
+            put.set_payload(&new_payload);
+        }));
+
+        read_ok(&message)
+    }
+
+    /// Removes a document
+    pub fn remove(&mut self,
+                  id: Uuid,
+                  session: TransactionId,
+                  request: Option<RequestId>)
+                  -> Result<()> {
+        let message = try!(self.call(Some(session),
+                                     request,
+                                     |mut request| request.set_remove(&id.to_string())));
+
+        read_ok(&message)
+    }
+
+    /// Restores a removed document from its tombstone
+    pub fn undelete(&mut self,
+                    id: Uuid,
+                    session: TransactionId,
+                    request: Option<RequestId>)
+                    -> Result<()> {
+        let message = try!(self.call(Some(session),
+                                     request,
+                                     |mut request| request.set_undelete(&id.to_string())));
+
+        read_ok(&message)
+    }
+
+    /// Gets many documents in one round trip
+    pub fn get_many(&mut self, ids: Vec<DocumentId>, consistency: Consistency) -> Result<MultiGet> {
+        let message = try!(self.call(None, None, |request| {
+            let mut get_many = request.init_get_many();
+            set_ids(get_many.borrow().init_ids(ids.len() as u32), &ids);
+            get_many.set_consistency(from_consistency(consistency));
+        }));
+
+        match try!(read_response(&message)) {
+            response::Which::Many(many) => get_multi_get(many),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Changes a byte range of a document and returns the new length of the payload
+    pub fn patch(&mut self,
+                 id: Uuid,
+                 delta: Delta,
+                 session: TransactionId,
+                 request: Option<RequestId>)
+                 -> Result<u64> {
+        let message = try!(self.call(Some(session), request, |request| {
+            let mut patch = request.init_patch();
+            patch.set_id(&id.to_string());
+            set_delta(patch.init_delta(), &delta);
+        }));
+
+        read_length(&message)
+    }
+
+    /// Adds `delta` atomically to a numeric document and returns the new value
+    pub fn increment(&mut self,
+                     id: Uuid,
+                     field: Option<&str>,
+                     delta: i64,
+                     session: TransactionId,
+                     request: Option<RequestId>)
+                     -> Result<i64> {
+        let message = try!(self.call(Some(session), request, |request| {
+            let mut increment = request.init_increment();
+            increment.set_id(&id.to_string());
+            increment.set_field(field.unwrap_or(""));
+            increment.set_amount(delta);
+        }));
+
+        match try!(read_response(&message)) {
+            response::Which::Value(value) => Ok(value),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Returns the next value of a sequence of the log
+    pub fn next_sequence(&mut self, name: &str, request: Option<RequestId>) -> Result<u64> {
+        let message = try!(self.call(None,
+                                     request,
+                                     |mut request| request.set_next_sequence(name)));
+
+        match try!(read_response(&message)) {
+            response::Which::Sequence(value) => Ok(value),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Returns the removed documents which were not purged yet
+    pub fn get_tombstones(&mut self) -> Result<Vec<(Uuid, Deletion)>> {
+        let message = try!(self.call(None, None, |mut request| request.set_get_tombstones(())));
+
+        match try!(read_response(&message)) {
+            response::Which::Tombstones(list) => {
+                let list = try!(list);
+                let mut tombstones = Vec::with_capacity(list.len() as usize);
+
+                for tombstone in list.iter() {
+                    tombstones.push(try!(get_tombstone(tombstone)));
+                }
+
+                Ok(tombstones)
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Removes the tombstones which are older than `retention` seconds. Returns the ids of
+    /// the purged documents
+    pub fn purge(&mut self, retention: u64) -> Result<Vec<Uuid>> {
+        let message = try!(self.call(None, None, |mut request| request.set_purge(retention)));
+
+        match try!(read_response(&message)) {
+            response::Which::Ids(ids) => get_ids(try!(ids)),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Copies a document to the `target` log. Returns the id of the copy
+    pub fn copy_document(&mut self,
+                         id: Uuid,
+                         target: LogId,
+                         keep_id: bool,
+                         keep_version: bool,
+                         request: Option<RequestId>)
+                         -> Result<Uuid> {
+        let message = try!(self.call(None, request, |request| {
+            let mut copy = request.init_copy_document();
+            copy.set_id(&id.to_string());
+            copy.set_target(&target.to_string());
+            copy.set_keep_id(keep_id);
+            copy.set_keep_version(keep_version);
+        }));
+
+        read_id(&message)
+    }
+
+    /// Moves a document to the `target` log like `PaenkoClient::move_document`. Returns the
+    /// id of the document in the target log
+    pub fn move_document(&mut self,
+                         id: Uuid,
+                         target: LogId,
+                         keep_id: bool,
+                         keep_version: bool)
+                         -> Result<Uuid> {
+        let message = try!(self.call(None, None, |request| {
+            let mut move_document = request.init_move_document();
+            move_document.set_id(&id.to_string());
+            move_document.set_target(&target.to_string());
+            move_document.set_keep_id(keep_id);
+            move_document.set_keep_version(keep_version);
+        }));
+
+        read_id(&message)
+    }
+
+    /// Unlocks a document whose move was interrupted
+    pub fn abort_move(&mut self, id: Uuid) -> Result<()> {
+        let message = try!(self.call(None,
+                                     None,
+                                     |mut request| request.set_abort_move(&id.to_string())));
+
+        read_ok(&message)
+    }
+
+    /// Uploads a document from a stream in chunks of `CHUNK_SIZE`. The document is created
+    /// or replaced when all chunks were uploaded. Returns the length of the payload. A
+    /// failed upload is aborted, so its staged chunks are dropped.
+    pub fn upload<R: Read>(&mut self,
+                           id: Uuid,
+                           reader: &mut R,
+                           session: TransactionId)
+                           -> Result<u64> {
+        let upload = try!(self.begin_upload(id));
+
+        let result = self.upload_chunks(upload, reader)
+            .and_then(|_| self.commit_upload(upload, session));
+
+        if result.is_err() {
+            // the error of the upload is returned, pending uploads expire after
+            // `UPLOAD_TTL` if the abort fails as well
+            let _ = self.abort_upload(upload);
+        }
+
+        result
+    }
+
+    /// Reads a stream in chunks of `CHUNK_SIZE` and uploads them to a pending upload
+    fn upload_chunks<R: Read>(&mut self, upload: UploadId, reader: &mut R) -> Result<()> {
+        let mut index = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            try!(reader.by_ref()
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)
+                .map_err(ClientError::Io));
+
+            if chunk.is_empty() && index > 0 {
+                return Ok(());
+            }
+
+            let length = chunk.len();
+            try!(self.upload_chunk(upload, index, chunk));
+            index += 1;
+
+            if length < CHUNK_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Downloads a document in chunks of `CHUNK_SIZE` into a stream. Returns the number
+    /// of written bytes. Fails with a conflict if the document is changed during the
+    /// download, the stream then contains an incomplete payload.
+    pub fn download<W: Write>(&mut self, id: Uuid, writer: &mut W) -> Result<u64> {
+        let mut offset = 0;
+        let mut pinned = None;
+
+        loop {
+            let (version, chunk) = try!(self.get_versioned_range(id, offset, CHUNK_SIZE as u64));
+
+            // a write between two chunks would join the ranges of different payloads
+            match pinned {
+                Some(pinned) if pinned != version => {
+                    return Err(ClientError::from(DocumentError::VersionChanged(id)));
+                }
+                _ => pinned = Some(version),
+            }
+
+            try!(writer.write_all(&chunk).map_err(ClientError::Io));
+            offset += chunk.len() as u64;
+
+            if chunk.len() < CHUNK_SIZE {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /// Starts a chunked upload of a document and returns its `UploadId`
+    pub fn begin_upload(&mut self, id: Uuid) -> Result<UploadId> {
+        let message = try!(self.call(None,
+                                     None,
+                                     |mut request| request.set_begin_upload(&id.to_string())));
+
+        read_id(&message)
+    }
+
+    /// Uploads the chunk with the `index` of a pending upload. Sending a chunk again
+    /// replaces it
+    pub fn upload_chunk(&mut self, upload: UploadId, index: u64, chunk: Vec<u8>) -> Result<()> {
+        let message = try!(self.call(None, None, |request| {
+            let mut upload_chunk = request.init_upload_chunk();
+            upload_chunk.set_upload(&upload.to_string());
+            upload_chunk.set_index(index);
+            upload_chunk.set_chunk(&chunk);
+        }));
+
+        read_ok(&message)
+    }
+
+    /// Creates or replaces the document with the chunks of the upload and returns the
+    /// length of the payload
+    pub fn commit_upload(&mut self, upload: UploadId, session: TransactionId) -> Result<u64> {
+        let message = try!(self.call(Some(session),
+                                     None,
+                                     |mut request| request.set_commit_upload(&upload.to_string())));
+
+        read_length(&message)
+    }
+
+    /// Drops the chunks of a pending upload
+    pub fn abort_upload(&mut self, upload: UploadId) -> Result<()> {
+        let message = try!(self.call(None,
+                                     None,
+                                     |mut request| request.set_abort_upload(&upload.to_string())));
+
+        read_ok(&message)
+    }
+
+    /// Returns at most `length` bytes of the payload of a document from the `offset`
+    pub fn get_range(&mut self, id: Uuid, offset: u64, length: u64) -> Result<Vec<u8>> {
+        self.get_versioned_range(id, offset, length).map(|(_, bytes)| bytes)
+    }
+
+    /// Like `get_range`, but returns the version of the document together with the bytes
+    pub fn get_versioned_range(&mut self,
+                               id: Uuid,
+                               offset: u64,
+                               length: u64)
+                               -> Result<(usize, Vec<u8>)> {
+        let message = try!(self.call(None, None, |request| {
+            let mut range = request.init_get_range();
+            range.set_id(&id.to_string());
+            range.set_offset(offset);
+            range.set_length(length);
+        }));
+
+        match try!(read_response(&message)) {
+            response::Which::Range(range) => {
+                Ok((range.get_version() as usize, try!(range.get_bytes()).to_vec()))
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Begins a transaction and returns its id
+    pub fn begin_transaction(&mut self, session: TransactionId) -> Result<String> {
+        let message = try!(self.call(Some(session),
+                                     None,
+                                     |mut request| request.set_begin_transaction(())));
+
+        match try!(read_response(&message)) {
+            response::Which::Id(id) => Ok(try!(id).to_string()),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Commits a transaction
+    pub fn commit_transaction(&mut self, session: TransactionId) -> Result<()> {
+        let message = try!(self.call(Some(session),
+                                     None,
+                                     |mut request| request.set_commit_transaction(())));
+
+        read_ok(&message)
+    }
+
+    /// Rollbacks a transaction
+    pub fn rollback_transaction(&mut self, session: TransactionId) -> Result<()> {
+        let message = try!(self.call(Some(session),
+                                     None,
+                                     |mut request| request.set_rollback_transaction(())));
+
+        read_ok(&message)
+    }
+
+    /// Creates a savepoint in a transaction
+    pub fn create_savepoint(&mut self, session: TransactionId, name: &str) -> Result<()> {
+        let message = try!(self.call(Some(session),
+                                     None,
+                                     |mut request| request.set_create_savepoint(name)));
+
+        read_ok(&message)
+    }
+
+    /// Rollbacks a transaction to a savepoint
+    pub fn rollback_to_savepoint(&mut self, session: TransactionId, name: &str) -> Result<()> {
+        let message = try!(self.call(Some(session),
+                                     None,
+                                     |mut request| request.set_rollback_to_savepoint(name)));
+
+        read_ok(&message)
+    }
+
+    /// Returns all open transactions of the log
+    pub fn get_transactions(&mut self) -> Result<Vec<TransactionInfo>> {
+        let message = try!(self.call(None,
+                                     None,
+                                     |mut request| request.set_get_transactions(())));
+
+        match try!(read_response(&message)) {
+            response::Which::Transactions(list) => {
+                let list = try!(list);
+                let mut transactions = Vec::with_capacity(list.len() as usize);
+
+                for info in list.iter() {
+                    transactions.push(try!(get_transaction_info(info)));
+                }
+
+                Ok(transactions)
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Returns the schema of the log
+    pub fn get_schema(&mut self) -> Result<Option<String>> {
+        let message = try!(self.call(None, None, |mut request| request.set_get_schema(())));
+
+        match try!(read_response(&message)) {
+            response::Which::Schema(schema) => {
+                let schema = try!(schema);
+
+                if schema.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(schema.to_string()))
+                }
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Sets the schema of the log or drops it with `None`
+    pub fn set_schema(&mut self, schema: Option<String>) -> Result<()> {
+        let message = try!(self.call(None, None, |mut request| match schema {
+            Some(ref schema) => request.set_set_schema(schema),
+            None => request.set_drop_schema(()),
+        }));
+
+        read_ok(&message)
+    }
+
+    /// Keeps removed documents as tombstones, so they can be restored with `undelete`
+    pub fn set_soft_delete(&mut self, enabled: bool) -> Result<()> {
+        let message = try!(self.call(None,
+                                     None,
+                                     |mut request| request.set_set_soft_delete(enabled)));
+
+        read_ok(&message)
+    }
+
+    /// Replaces the limits of the log
+    pub fn set_quota(&mut self, quota: Quota) -> Result<()> {
+        let message = try!(self.call(None,
+                                     None,
+                                     |request| set_quota(request.init_set_quota(), &quota)));
+
+        read_ok(&message)
+    }
+
+    /// Reports whether the volume of the `node` is below its free space watermark
+    pub fn set_storage_full(&mut self, node: String, full: bool) -> Result<()> {
+        let message = try!(self.call(None, None, |request| {
+            let mut storage = request.init_set_storage_full();
+            storage.set_node(&node);
+            storage.set_full(full);
+        }));
+
+        read_ok(&message)
+    }
+
+    /// Returns the usage and the quota of the log
+    pub fn get_usage(&mut self) -> Result<Usage> {
+        let message = try!(self.call(None, None, |mut request| request.set_get_usage(())));
+
+        match try!(read_response(&message)) {
+            response::Which::Usage(usage) => Ok(get_usage(try!(usage))),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Returns the addresses of the members of the cluster
+    pub fn get_peers(&mut self) -> Result<Vec<SocketAddr>> {
+        let message = try!(self.call(None, None, |mut request| request.set_get_peers(())));
+
+        match try!(read_response(&message)) {
+            response::Which::Peers(list) => {
+                let list = try!(list);
+                let mut peers = Vec::with_capacity(list.len() as usize);
+
+                for i in 0..list.len() {
+                    let peer = try!(list.get(i));
+
+                    peers.push(try!(peer.parse()
+                        .map_err(|_| ClientError::Protocol(format!("Invalid address {}", peer)))));
+                }
+
+                Ok(peers)
+            }
+            _ => Err(unexpected_response()),
+        }
+    }
+}
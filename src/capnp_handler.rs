@@ -0,0 +1,391 @@
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, sync_channel};
+use std::thread::spawn;
+
+use capnp::message::{Builder, Reader, ReaderOptions};
+use capnp::serialize::{self, OwnedSegments};
+
+use raft::LogId;
+use raft::TransactionId;
+
+use client::PaenkoClient;
+use document::document_id_from_key;
+use error::ClientError;
+use handler::Result;
+use protocol::*;
+use paenko_capnp::{request, response};
+
+/// The user and the log of a request
+type Key = (String, String, LogId);
+
+/// The idle clients of a connection for every user and log. A request takes a client
+/// from the pool, so concurrent requests are sent with their own clients.
+type Clients = Arc<Mutex<HashMap<Key, Vec<PaenkoClient>>>>;
+
+type Writer = Arc<Mutex<BufWriter<TcpStream>>>;
+
+/// The tagged requests of a connection which wait for a worker
+type Queue = Arc<Mutex<Receiver<Reader<OwnedSegments>>>>;
+
+/// The maximum number of threads which run the tagged requests of a connection
+const WORKERS: usize = 8;
+
+/// The number of tagged requests of a connection which wait for a worker. The connection
+/// is not read while the queue is full.
+const QUEUE_LEN: usize = 64;
+
+/// Serves the client protocol of `schema/paenko.capnp` on `binding_addr` and returns the
+/// bound address. Every connection is handled by its own thread, its tagged requests by
+/// at most `WORKERS` more. The requests are sent to the cluster with the credentials of
+/// the request, starting with the node at `node_addr`.
+pub fn init(binding_addr: SocketAddr, node_addr: SocketAddr) -> io::Result<SocketAddr> {
+    let listener = try!(TcpListener::bind(binding_addr));
+    let local_addr = try!(listener.local_addr());
+
+    spawn(move || for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                spawn(move || serve(stream, node_addr));
+            }
+            Err(err) => println!("Unable to accept a Cap'n Proto connection: {}", err),
+        }
+    });
+
+    Ok(local_addr)
+}
+
+/// Answers the requests of a connection until it is closed
+fn serve(stream: TcpStream, node_addr: SocketAddr) {
+    let mut reader = match stream.try_clone() {
+        Ok(stream) => BufReader::new(stream),
+        Err(_) => return,
+    };
+    let writer = Arc::new(Mutex::new(BufWriter::new(stream)));
+    let clients = Arc::new(Mutex::new(HashMap::new()));
+
+    // the workers stop when the sender is dropped with the connection
+    let (sender, receiver) = sync_channel(QUEUE_LEN);
+    let queue = Arc::new(Mutex::new(receiver));
+    let mut workers = 0;
+
+    loop {
+        // fails as well when the client closed the connection
+        let message = match serialize::read_message(&mut reader, ReaderOptions::new()) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+
+        let tag = message.get_root::<request::Reader>().map(|request| request.get_tag());
+
+        match tag {
+            Ok(tag) if tag != 0 => {
+                if workers < WORKERS {
+                    let queue = queue.clone();
+                    let clients = clients.clone();
+                    let writer = writer.clone();
+
+                    spawn(move || work(&queue, node_addr, &clients, &writer));
+                    workers += 1;
+                }
+
+                // blocks while the queue is full
+                if sender.send(message).is_err() {
+                    return;
+                }
+            }
+            _ => {
+                if !answer(message, node_addr, &clients, &writer) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Answers the tagged requests of a connection until it is closed
+fn work(queue: &Queue, node_addr: SocketAddr, clients: &Clients, writer: &Writer) {
+    loop {
+        let message = match queue.lock().unwrap().recv() {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+
+        // a connection which cannot be written is closed by `serve`
+        answer(message, node_addr, clients, writer);
+    }
+}
+
+/// Runs a request and writes its response. Returns false if the response cannot be sent
+fn answer(message: Reader<OwnedSegments>,
+          node_addr: SocketAddr,
+          clients: &Clients,
+          writer: &Writer)
+          -> bool {
+    let mut answer = Builder::new_default();
+    {
+        let mut root = answer.init_root::<response::Builder>();
+
+        let result = message.get_root::<request::Reader>()
+            .map_err(ClientError::from)
+            .and_then(|request| {
+                root.set_tag(request.get_tag());
+
+                let key = try!(get_key(request));
+                let mut client = take_client(clients, &key, node_addr);
+                let result = handle(request, &mut client, root.borrow());
+
+                clients.lock().unwrap().entry(key).or_insert_with(Vec::new).push(client);
+
+                result
+            });
+
+        if let Err(err) = result {
+            set_error(root.init_error(), &err);
+        }
+    }
+
+    let mut writer = writer.lock().unwrap();
+
+    serialize::write_message(&mut *writer, &answer).and_then(|_| writer.flush()).is_ok()
+}
+
+/// Returns the user and the log of a request
+fn get_key(request: request::Reader) -> Result<Key> {
+    let lid = try!(parse_lid(try!(request.get_lid())));
+
+    Ok((try!(request.get_username()).to_string(), try!(request.get_password()).to_string(), lid))
+}
+
+/// Takes an idle client of the user and the log from the pool or creates a new one
+fn take_client(clients: &Clients, key: &Key, node_addr: SocketAddr) -> PaenkoClient {
+    let idle = {
+        let mut clients = clients.lock().unwrap();
+        let idle = clients.get_mut(key).and_then(|pool| pool.pop());
+        idle
+    };
+
+    match idle {
+        Some(client) => client,
+        None => {
+            let (ref username, ref password, lid) = *key;
+            PaenkoClient::new(vec![node_addr], username, password, lid)
+        }
+    }
+}
+
+/// Returns the transaction of a transaction command
+fn required(session: Option<TransactionId>) -> Result<TransactionId> {
+    session.ok_or_else(|| ClientError::Protocol("The request has no session".to_string()))
+}
+
+/// Runs a request with the `client` of its user and writes the result to the `response`
+fn handle(request: request::Reader,
+          client: &mut PaenkoClient,
+          mut response: response::Builder)
+          -> Result<()> {
+    let request_id = try!(parse_optional_id(try!(request.get_request_id())));
+
+    let session = match try!(request.get_session()) {
+        "" => None,
+        session => Some(try!(parse_session(session))),
+    };
+    // writes outside of a transaction are committed on their own
+    let write_session = session.unwrap_or_else(TransactionId::new);
+
+    match try!(request.which()) {
+        request::Which::Get(get) => {
+            let id = document_id_from_key(try!(get.get_id()));
+            let consistency = to_consistency(try!(get.get_consistency()));
+
+            let document = try!(client.get(id, consistency));
+            set_document(response.init_document(), &document);
+        }
+        request::Which::Post(document) => {
+            let document = try!(get_keyed_document(try!(document)));
+
+            let id = try!(client.post(document, write_session, request_id));
+            response.set_id(&id.to_string());
+        }
+        request::Which::Upsert(document) => {
+            let document = try!(get_keyed_document(try!(document)));
+
+            let id = try!(client.upsert(document, write_session, request_id));
+            response.set_id(&id.to_string());
+        }
+        request::Which::Put(put) => {
+            let id = document_id_from_key(try!(put.get_id()));
+            let payload = try!(put.get_payload()).to_vec();
+
+            try!(client.put(id, payload, write_session, request_id));
+            response.set_ok(());
+        }
+        request::Which::Remove(id) => {
+            try!(client.remove(document_id_from_key(try!(id)), write_session, request_id));
+            response.set_ok(());
+        }
+        request::Which::Undelete(id) => {
+            try!(client.undelete(document_id_from_key(try!(id)), write_session, request_id));
+            response.set_ok(());
+        }
+        request::Which::BeginTransaction(()) => {
+            let id = try!(client.begin_transaction(write_session));
+            response.set_id(&id);
+        }
+        request::Which::CommitTransaction(()) => {
+            try!(client.commit_transaction(try!(required(session))));
+            response.set_ok(());
+        }
+        request::Which::RollbackTransaction(()) => {
+            try!(client.rollback_transaction(try!(required(session))));
+            response.set_ok(());
+        }
+        request::Which::CreateSavepoint(name) => {
+            try!(client.create_savepoint(try!(required(session)), try!(name)));
+            response.set_ok(());
+        }
+        request::Which::RollbackToSavepoint(name) => {
+            try!(client.rollback_to_savepoint(try!(required(session)), try!(name)));
+            response.set_ok(());
+        }
+        request::Which::GetTransactions(()) => {
+            let transactions = try!(client.get_transactions());
+
+            let mut list = response.init_transactions(transactions.len() as u32);
+            for (i, info) in transactions.iter().enumerate() {
+                set_transaction_info(list.borrow().get(i as u32), info);
+            }
+        }
+        request::Which::GetSchema(()) => {
+            let schema = try!(client.get_schema());
+            response.set_schema(&schema.unwrap_or_default());
+        }
+        request::Which::SetSchema(schema) => {
+            try!(client.set_schema(Some(try!(schema).to_string())));
+            response.set_ok(());
+        }
+        request::Which::DropSchema(()) => {
+            try!(client.set_schema(None));
+            response.set_ok(());
+        }
+        request::Which::GetUsage(()) => {
+            let usage = try!(client.get_usage());
+            set_usage(response.init_usage(), &usage);
+        }
+        request::Which::GetPeers(()) => {
+            let peers = try!(client.discover());
+
+            let mut list = response.init_peers(peers.len() as u32);
+            for (i, peer) in peers.iter().enumerate() {
+                list.set(i as u32, &peer.to_string());
+            }
+        }
+        request::Which::GetMany(get_many) => {
+            let ids = try!(get_keys(try!(get_many.get_ids())));
+            let consistency = to_consistency(try!(get_many.get_consistency()));
+
+            let result = try!(client.get_many(ids, consistency));
+            set_multi_get(response.init_many(), &result);
+        }
+        request::Which::Patch(patch) => {
+            let id = document_id_from_key(try!(patch.get_id()));
+            let delta = try!(get_delta(try!(patch.get_delta())));
+
+            let length = try!(client.patch(id, delta, write_session, request_id));
+            response.set_length(length);
+        }
+        request::Which::Increment(increment) => {
+            let id = document_id_from_key(try!(increment.get_id()));
+            let field = match try!(increment.get_field()) {
+                "" => None,
+                field => Some(field),
+            };
+
+            let value = try!(client.increment(id,
+                                              field,
+                                              increment.get_amount(),
+                                              write_session,
+                                              request_id));
+            response.set_value(value);
+        }
+        request::Which::NextSequence(name) => {
+            let value = try!(client.next_sequence(try!(name), request_id));
+            response.set_sequence(value);
+        }
+        request::Which::GetTombstones(()) => {
+            let tombstones = try!(client.get_tombstones());
+
+            let mut list = response.init_tombstones(tombstones.len() as u32);
+            for (i, &(ref id, ref deletion)) in tombstones.iter().enumerate() {
+                set_tombstone(list.borrow().get(i as u32), id, deletion);
+            }
+        }
+        request::Which::Purge(retention) => {
+            let ids = try!(client.purge(retention));
+            set_ids(response.init_ids(ids.len() as u32), &ids);
+        }
+        request::Which::CopyDocument(copy) => {
+            let id = try!(client.copy_document(document_id_from_key(try!(copy.get_id())),
+                                               try!(parse_lid(try!(copy.get_target()))),
+                                               copy.get_keep_id(),
+                                               copy.get_keep_version(),
+                                               request_id));
+            response.set_id(&id.to_string());
+        }
+        request::Which::MoveDocument(move_document) => {
+            let id = try!(client.move_document(document_id_from_key(try!(move_document.get_id())),
+                                               try!(parse_lid(try!(move_document.get_target()))),
+                                               move_document.get_keep_id(),
+                                               move_document.get_keep_version()));
+            response.set_id(&id.to_string());
+        }
+        request::Which::AbortMove(id) => {
+            try!(client.abort_move(document_id_from_key(try!(id))));
+            response.set_ok(());
+        }
+        request::Which::BeginUpload(id) => {
+            let upload = try!(client.begin_upload(document_id_from_key(try!(id))));
+            response.set_id(&upload.to_string());
+        }
+        request::Which::UploadChunk(chunk) => {
+            try!(client.upload_chunk(try!(parse_id(try!(chunk.get_upload()))),
+                                     chunk.get_index(),
+                                     try!(chunk.get_chunk()).to_vec()));
+            response.set_ok(());
+        }
+        request::Which::CommitUpload(upload) => {
+            let length = try!(client.commit_upload(try!(parse_id(try!(upload))), write_session));
+            response.set_length(length);
+        }
+        request::Which::AbortUpload(upload) => {
+            try!(client.abort_upload(try!(parse_id(try!(upload)))));
+            response.set_ok(());
+        }
+        request::Which::GetRange(range) => {
+            let (version, bytes) = try!(client.get_versioned_range(
+                document_id_from_key(try!(range.get_id())),
+                range.get_offset(),
+                range.get_length()));
+
+            let mut builder = response.init_range();
+            builder.set_version(version as u64);
+            builder.set_bytes(&bytes);
+        }
+        request::Which::SetSoftDelete(enabled) => {
+            try!(client.set_soft_delete(enabled));
+            response.set_ok(());
+        }
+        request::Which::SetQuota(quota) => {
+            try!(client.set_quota(get_quota(try!(quota))));
+            response.set_ok(());
+        }
+        request::Which::SetStorageFull(storage) => {
+            try!(client.set_storage_full(try!(storage.get_node()).to_string(), storage.get_full()));
+            response.set_ok(());
+        }
+    }
+
+    Ok(())
+}
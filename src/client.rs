@@ -0,0 +1,1151 @@
+use document::*;
+use handler::{Message, Consistency, RequestId, Result};
+use error::ClientError;
+use retry::RetryPolicy;
+use cache::{CacheConfig, ReadCache};
+use wire;
+use transaction::TransactionInfo;
+use quota::{Quota, Usage};
+use delta::Delta;
+use upload::{UploadId, CHUNK_SIZE};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::str::from_utf8;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use raft::Client;
+use raft::LogId;
+use raft::TransactionId;
+use raft::Error as RError;
+use raft::RaftError;
+
+use bincode::serde::deserialize as decode;
+use serde::Deserialize;
+
+use raft::auth::credentials::SingleCredentials;
+use raft::auth::simple::SimpleAuth;
+
+/// Encodes a command for the state machine
+fn encode_message(message: &Message) -> Result<Vec<u8>> {
+    wire::encode_command(message)
+        .map_err(|err| ClientError::Protocol(format!("Cannot encode the request: {}", err)))
+}
+
+/// Decodes a value which was encoded by the state machine
+fn decode_value<T: Deserialize>(response: &[u8]) -> Result<T> {
+    let body = try!(wire::decode_response(response)
+        .map_err(|err| ClientError::Protocol(format!("Cannot decode the response: {}", err))));
+
+    decode(body)
+        .map_err(|err| ClientError::Protocol(format!("Cannot decode the response: {}", err)))
+}
+
+/// Decodes the response of a command which the state machine might reject
+fn decode_response<T: Deserialize>(response: &[u8]) -> Result<T> {
+    let response: ::std::result::Result<T, DocumentError> = try!(decode_value(response));
+
+    response.map_err(ClientError::from)
+}
+
+/// Decodes the text response of the raft layer
+fn decode_text(response: &[u8]) -> Result<String> {
+    from_utf8(response)
+        .map(|text| text.to_string())
+        .map_err(|err| ClientError::Protocol(format!("Cannot decode the response: {}", err)))
+}
+
+/// The default interval between two fetches of the members of the cluster
+pub const DISCOVERY_INTERVAL: u64 = 60;
+
+/// Returns a HashSet with a single Peer entry
+fn to_hashset(addr: SocketAddr) -> HashSet<SocketAddr> {
+    let mut hashset: HashSet<SocketAddr> = HashSet::new();
+    hashset.insert(addr);
+    hashset
+}
+
+/// A client which is connected to a cluster. It is created once with the credentials and
+/// keeps its connections and the leader of every log across calls, so only the first
+/// request of a log has to be redirected to the leader. Redirects and retries are limited
+/// by its `RetryPolicy`.
+///
+/// The client fetches the members of the cluster from any reachable node and refreshes
+/// them periodically. If a node can not be reached, the request is retried with the next
+/// member, so the client keeps working as long as one node is up.
+///
+/// All methods work on the default log of the client. Use `set_lid` to switch the log.
+pub struct PaenkoClient {
+    seeds: Vec<SocketAddr>,
+    username: String,
+    password: String,
+    lid: LogId,
+    policy: RetryPolicy,
+    /// The members of the cluster which were fetched last
+    members: Vec<SocketAddr>,
+    /// The interval between two fetches of the members. `None` disables the discovery
+    discovery: Option<Duration>,
+    /// When the members were fetched last
+    discovered: Option<Instant>,
+    /// The index of the node in `nodes` which is asked if the leader of a log is unknown
+    next_node: usize,
+    /// The last known leader of every log
+    leaders: HashMap<LogId, SocketAddr>,
+    /// The open connections by node and log
+    clients: HashMap<(SocketAddr, LogId), Client>,
+    /// The cached documents. `None` disables the cache
+    cache: Option<ReadCache>,
+}
+
+impl PaenkoClient {
+    /// Creates a new client. The first request of a log is sent to the first seed and
+    /// redirected to the leader from there.
+    ///
+    /// # Arguments
+    /// * `seeds` - The `SocketAddr`s of known nodes
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The default `LogId` of the requests
+    pub fn new(seeds: Vec<SocketAddr>,
+               username: &str,
+               plain_password: &str,
+               lid: LogId)
+               -> PaenkoClient {
+        PaenkoClient {
+            seeds: seeds,
+            username: username.to_string(),
+            password: plain_password.to_string(),
+            lid: lid,
+            policy: RetryPolicy::default(),
+            members: Vec::new(),
+            discovery: Some(Duration::from_secs(DISCOVERY_INTERVAL)),
+            discovered: None,
+            next_node: 0,
+            leaders: HashMap::new(),
+            clients: HashMap::new(),
+            cache: None,
+        }
+    }
+
+    /// Returns the default `LogId` of the requests
+    pub fn lid(&self) -> LogId {
+        self.lid
+    }
+
+    /// Changes the default `LogId` of the requests. The connections to the other logs
+    /// are kept
+    pub fn set_lid(&mut self, lid: LogId) {
+        self.lid = lid;
+    }
+
+    /// Replaces the `RetryPolicy` of the client
+    pub fn set_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Enables the read cache with the given configuration or disables it with `None`.
+    ///
+    /// Cached documents are revalidated by their version, which is cheaper than fetching
+    /// the payload again. `Stale` reads skip the revalidation within the maximum
+    /// staleness. `Linearizable` reads always fetch the document. Writes of this client
+    /// drop the cached copy of the document. A document which was removed and posted again
+    /// with the same version between two reads is not noticed.
+    pub fn set_cache(&mut self, config: Option<CacheConfig>) {
+        self.cache = config.map(ReadCache::new);
+    }
+
+    /// Returns the known leader of the default log
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.leaders.get(&self.lid).cloned()
+    }
+
+    /// Sets the interval between two fetches of the members of the cluster. `None`
+    /// disables the discovery, then only the seeds are asked
+    pub fn set_discovery(&mut self, interval: Option<Duration>) {
+        self.discovery = interval;
+    }
+
+    /// Returns the known nodes of the cluster. These are the fetched members followed by
+    /// the seeds which are no members
+    pub fn nodes(&self) -> Vec<SocketAddr> {
+        let mut nodes = self.members.clone();
+
+        for seed in &self.seeds {
+            if !nodes.contains(seed) {
+                nodes.push(*seed);
+            }
+        }
+
+        nodes
+    }
+
+    /// Fetches the members of the cluster from a reachable node and returns them
+    pub fn discover(&mut self) -> Result<Vec<SocketAddr>> {
+        // Set before the request, so the request itself does not start a discovery
+        self.discovered = Some(Instant::now());
+
+        let response = try!(self.query(&Message::GetPeers));
+        let members: Vec<SocketAddr> = try!(decode_value(response.as_slice()));
+
+        if !members.is_empty() {
+            self.members = members.clone();
+        }
+
+        Ok(members)
+    }
+
+    /// Returns true if the members should be fetched again
+    fn discovery_due(&self) -> bool {
+        match (self.discovery, self.discovered) {
+            (Some(_), None) => true,
+            (Some(interval), Some(discovered)) => discovered.elapsed() >= interval,
+            (None, _) => false,
+        }
+    }
+
+    /// Returns the node to which the next request of the log is sent
+    fn node(&self, lid: LogId) -> Option<SocketAddr> {
+        match self.leaders.get(&lid) {
+            Some(leader) => Some(*leader),
+            None => {
+                let nodes = self.nodes();
+
+                if nodes.is_empty() {
+                    None
+                } else {
+                    Some(nodes[self.next_node % nodes.len()])
+                }
+            }
+        }
+    }
+
+    /// Returns the connection to the node for the log and opens it if needed
+    fn connection(&mut self, addr: SocketAddr, lid: LogId) -> &mut Client {
+        let username = self.username.clone();
+        let password = self.password.clone();
+
+        self.clients.entry((addr, lid)).or_insert_with(|| {
+            Client::new::<SimpleAuth<SingleCredentials>>(to_hashset(addr), username, password, lid)
+        })
+    }
+
+    /// Sends a request to the leader of the log. Redirects update the known leader. A
+    /// failed connection is dropped and the request is retried with the next node after a
+    /// backoff
+    fn send<F>(&mut self, lid: LogId, mut request: F) -> Result<Vec<u8>>
+        where F: FnMut(&mut Client) -> ::std::result::Result<Vec<u8>, RError>
+    {
+        if self.discovery_due() {
+            // A failed discovery keeps the known members
+            let _ = self.discover();
+        }
+
+        let started = Instant::now();
+        let mut redirects = 0;
+        let mut retries = 0;
+
+        loop {
+            if let Some(deadline) = self.policy.deadline {
+                if started.elapsed() >= deadline {
+                    return Err(ClientError::Timeout);
+                }
+            }
+
+            let addr = match self.node(lid) {
+                Some(addr) => addr,
+                None => return Err(ClientError::NoLeader("no known nodes".to_string())),
+            };
+            let result = request(self.connection(addr, lid));
+
+            let err = match result {
+                Ok(response) => {
+                    self.leaders.insert(lid, addr);
+                    return Ok(response);
+                }
+                Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
+                    redirects += 1;
+
+                    if redirects > self.policy.max_redirects {
+                        self.leaders.remove(&lid);
+                        return Err(ClientError::NoLeader(format!("redirected more than {} times",
+                                                                 self.policy.max_redirects)));
+                    }
+
+                    match parse_addr(leader_str) {
+                        Some(leader) => self.leaders.insert(lid, leader),
+                        None => {
+                            self.leaders.remove(&lid);
+                            return Err(ClientError::NoLeader(format!("invalid leader address {}",
+                                                                     leader_str)));
+                        }
+                    };
+
+                    continue;
+                }
+                Err(err @ RError::Io(_)) |
+                Err(err @ RError::Raft(RaftError::LeaderSearchExhausted)) => err,
+                Err(err) => return Err(ClientError::from(err)),
+            };
+
+            // The node is not reachable or knows no leader, so the next node is asked
+            self.clients.remove(&(addr, lid));
+            self.leaders.remove(&lid);
+            self.next_node += 1;
+
+            retries += 1;
+            if retries > self.policy.max_retries {
+                return Err(ClientError::from(err));
+            }
+
+            let backoff = self.policy.backoff(retries);
+
+            if let Some(deadline) = self.policy.deadline {
+                if started.elapsed() + backoff >= deadline {
+                    return Err(ClientError::Timeout);
+                }
+            }
+
+            sleep(backoff);
+        }
+    }
+
+    fn query_log(&mut self, lid: LogId, message: &Message) -> Result<Vec<u8>> {
+        let payload = try!(encode_message(message));
+
+        self.send(lid, |client| client.query(payload.as_slice()))
+    }
+
+    fn propose_log(&mut self,
+                   lid: LogId,
+                   message: Message,
+                   session: TransactionId,
+                   request: Option<RequestId>)
+                   -> Result<Vec<u8>> {
+        if let (Some(cache), Some(id)) = (self.cache.as_mut(), message.get_document_id()) {
+            cache.remove(lid, id);
+        }
+
+        let message = Message::Request(request.unwrap_or_else(Uuid::new_v4), Box::new(message));
+
+        let payload = try!(encode_message(&message));
+
+        self.send(lid, |client| client.propose(session, payload.as_slice()))
+    }
+
+    /// Sends a query to the leader of the default log and returns the encoded response
+    pub fn query(&mut self, message: &Message) -> Result<Vec<u8>> {
+        let lid = self.lid;
+        self.query_log(lid, message)
+    }
+
+    /// Proposes a write command to the default log and returns the encoded response. The
+    /// command is sent with a `RequestId`, so the retries of a request are applied only once.
+    pub fn propose(&mut self,
+                   message: Message,
+                   session: TransactionId,
+                   request: Option<RequestId>)
+                   -> Result<Vec<u8>> {
+        let lid = self.lid;
+        self.propose_log(lid, message, session, request)
+    }
+
+    /// Sends a read with the given `consistency`
+    fn read_log(&mut self,
+                lid: LogId,
+                message: &Message,
+                consistency: Consistency)
+                -> Result<Vec<u8>> {
+        let payload = try!(encode_message(message));
+
+        match consistency {
+            Consistency::Linearizable => {
+                self.send(lid,
+                          |client| client.propose(TransactionId::new(), payload.as_slice()))
+            }
+            Consistency::Leader | Consistency::Stale => {
+                self.send(lid, |client| client.query(payload.as_slice()))
+            }
+        }
+    }
+
+    fn get_version_log(&mut self, lid: LogId, id: Uuid) -> Result<usize> {
+        let response = try!(self.query_log(lid, &Message::GetVersion(id)));
+
+        decode_response(response.as_slice())
+    }
+
+    fn get_log(&mut self, lid: LogId, id: Uuid, consistency: Consistency) -> Result<Document> {
+        let cached = match consistency {
+            Consistency::Linearizable => None,
+            Consistency::Leader | Consistency::Stale => {
+                self.cache.as_mut().and_then(|cache| cache.get(lid, id))
+            }
+        };
+
+        if let Some((document, fresh)) = cached {
+            if fresh && consistency == Consistency::Stale {
+                return Ok(document);
+            }
+
+            match self.get_version_log(lid, id) {
+                Ok(version) if version == document.version => {
+                    if let Some(cache) = self.cache.as_mut() {
+                        cache.validate(lid, id);
+                    }
+
+                    return Ok(document);
+                }
+                Ok(_) => {}
+                Err(err @ ClientError::NotFound(_)) => {
+                    if let Some(cache) = self.cache.as_mut() {
+                        cache.remove(lid, id);
+                    }
+
+                    return Err(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let response = try!(self.read_log(lid, &Message::Get(id), consistency));
+        let result: Result<Document> = decode_response(response.as_slice());
+
+        if let Some(cache) = self.cache.as_mut() {
+            match result {
+                Ok(ref document) => cache.insert(lid, document.clone()),
+                Err(_) => cache.remove(lid, id),
+            }
+        }
+
+        result
+    }
+
+    fn post_log(&mut self,
+                lid: LogId,
+                document: Document,
+                session: TransactionId,
+                request: Option<RequestId>)
+                -> Result<Uuid> {
+        let response = try!(self.propose_log(lid,
+                                             Message::Post(session, document),
+                                             session,
+                                             request));
+
+        let document: Document = try!(decode_response(response.as_slice()));
+
+        Ok(document.id)
+    }
+
+    /// Gets a document. The client has no replica of its own, therefore `Stale` reads are
+    /// answered by the leader like `Leader` reads, unless the read cache is enabled
+    pub fn get(&mut self, id: Uuid, consistency: Consistency) -> Result<Document> {
+        let lid = self.lid;
+        self.get_log(lid, id, consistency)
+    }
+
+    /// Returns the version of a document without its payload
+    pub fn get_version(&mut self, id: Uuid) -> Result<usize> {
+        let lid = self.lid;
+        self.get_version_log(lid, id)
+    }
+
+    /// Gets many documents in one request. Returns the documents which were found and the
+    /// ids which do not exist
+    pub fn get_many(&mut self, ids: Vec<DocumentId>, consistency: Consistency) -> Result<MultiGet> {
+        let lid = self.lid;
+        let response = try!(self.read_log(lid, &Message::GetMany(ids), consistency));
+
+        decode_value(response.as_slice())
+    }
+
+    /// Inserts a new document. Fails if a document with the same id already exists
+    pub fn post(&mut self,
+                document: Document,
+                session: TransactionId,
+                request: Option<RequestId>)
+                -> Result<Uuid> {
+        let lid = self.lid;
+        self.post_log(lid, document, session, request)
+    }
+
+    /// Inserts a document or replaces the payload of the document if the id already exists
+    pub fn upsert(&mut self,
+                  document: Document,
+                  session: TransactionId,
+                  request: Option<RequestId>)
+                  -> Result<Uuid> {
+        let response = try!(self.propose(Message::Upsert(session, document), session, request));
+
+        let document: Document = try!(decode_response(response.as_slice()));
+
+        Ok(document.id)
+    }
+
+    /// Replaces the payload of a document
+    pub fn put(&mut self,
+               id: Uuid,
+               new_payload: Vec<u8>,
+               session: TransactionId,
+               request: Option<RequestId>)
+               -> Result<()> {
+        let response = try!(self.propose(Message::Put(session, id, new_payload), session, request));
+
+        let _: Document = try!(decode_response(response.as_slice()));
+
+        Ok(())
+    }
+
+    /// Removes a document
+    pub fn remove(&mut self,
+                  id: Uuid,
+                  session: TransactionId,
+                  request: Option<RequestId>)
+                  -> Result<()> {
+        let message = Message::Remove(session, id, self.username.clone(), timestamp());
+
+        let response = try!(self.propose(message, session, request));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Restores a removed document of a log with soft delete
+    pub fn undelete(&mut self,
+                    id: Uuid,
+                    session: TransactionId,
+                    request: Option<RequestId>)
+                    -> Result<()> {
+        let response = try!(self.propose(Message::Undelete(session, id), session, request));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Removes the tombstones which are older than `retention` seconds. Returns the ids of
+    /// the purged documents
+    pub fn purge(&mut self, retention: u64) -> Result<Vec<Uuid>> {
+        let response = try!(self.propose(Message::Purge(retention, timestamp()),
+                                         TransactionId::new(),
+                                         None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Returns the removed documents which were not purged yet
+    pub fn get_tombstones(&mut self) -> Result<Vec<(Uuid, Deletion)>> {
+        let response = try!(self.query(&Message::GetTombstones));
+
+        decode_value(response.as_slice())
+    }
+
+    /// Copies a document to the `target` log. Returns the id of the copy
+    pub fn copy_document(&mut self,
+                         id: Uuid,
+                         target: LogId,
+                         keep_id: bool,
+                         keep_version: bool,
+                         request: Option<RequestId>)
+                         -> Result<Uuid> {
+        let document = try!(self.get(id, Consistency::Leader));
+
+        let copy = Document {
+            id: if keep_id { document.id } else { Uuid::new_v4() },
+            payload: document.payload,
+            version: if keep_version { document.version } else { 1 },
+        };
+
+        self.post_log(target, copy, TransactionId::new(), request)
+    }
+
+    /// Moves a document to the `target` log. Returns the id of the document in the target
+    /// log.
+    ///
+    /// The move is done in two steps. First the document is locked in the source log and
+    /// the move is saved there. Then the document is inserted in the target log and removed
+    /// from the source log. If the move is interrupted, the document stays locked in the
+    /// source log and calling `move_document` again resumes the saved move, so the document
+    /// is neither lost nor inserted twice. `abort_move` unlocks the document instead.
+    pub fn move_document(&mut self,
+                         id: Uuid,
+                         target: LogId,
+                         keep_id: bool,
+                         keep_version: bool)
+                         -> Result<Uuid> {
+        let pending = PendingMove {
+            id: Uuid::new_v4(),
+            target: format!("{}", target),
+            target_id: if keep_id { id } else { Uuid::new_v4() },
+            keep_version: keep_version,
+        };
+
+        let response = try!(self.propose(Message::PrepareMove(id, pending),
+                                         TransactionId::new(),
+                                         None));
+
+        let (pending, document): (PendingMove, Document) =
+            try!(decode_response(response.as_slice()));
+
+        let moved = Document {
+            id: pending.target_id,
+            payload: document.payload,
+            version: if pending.keep_version {
+                document.version
+            } else {
+                1
+            },
+        };
+
+        // The id of the move is used as `RequestId`, so a resumed move inserts the
+        // document only once
+        match self.post_log(target, moved.clone(), TransactionId::new(), Some(pending.id)) {
+            Ok(_) => {}
+            Err(ClientError::Conflict(DocumentError::AlreadyExists(_))) => {
+                // The document was inserted by an earlier attempt if the payload is equal
+                let existing = try!(self.get_log(target, moved.id, Consistency::Leader));
+
+                if existing.payload != moved.payload {
+                    try!(self.abort_pending_move(id, pending.id));
+                    return Err(ClientError::from(DocumentError::AlreadyExists(moved.id)));
+                }
+            }
+            Err(err @ ClientError::NotFound(_)) |
+            Err(err @ ClientError::Conflict(_)) |
+            Err(err @ ClientError::Rejected(_)) => {
+                try!(self.abort_pending_move(id, pending.id));
+                return Err(err);
+            }
+            Err(err) => return Err(err),
+        }
+
+        let response = try!(self.propose(Message::CompleteMove(id, pending.id),
+                                         TransactionId::new(),
+                                         None));
+
+        try!(decode_response::<()>(response.as_slice()));
+
+        Ok(moved.id)
+    }
+
+    /// Unlocks a document whose move was interrupted, e.g. by a lost connection. If the
+    /// document was already inserted in the target log, it stays there. Aborting a
+    /// document which is not being moved has no effect.
+    pub fn abort_move(&mut self, id: Uuid) -> Result<()> {
+        let response = try!(self.query(&Message::GetMove(id)));
+
+        match try!(decode_value::<Option<PendingMove>>(response.as_slice())) {
+            Some(pending) => self.abort_pending_move(id, pending.id),
+            None => Ok(()),
+        }
+    }
+
+    fn abort_pending_move(&mut self, id: Uuid, move_id: Uuid) -> Result<()> {
+        let response = try!(self.propose(Message::AbortMove(id, move_id),
+                                         TransactionId::new(),
+                                         None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Changes a byte range of a document without sending the complete payload. Returns the
+    /// new length of the payload
+    pub fn patch(&mut self,
+                 id: Uuid,
+                 delta: Delta,
+                 session: TransactionId,
+                 request: Option<RequestId>)
+                 -> Result<u64> {
+        let response = try!(self.propose(Message::Patch(session, id, delta), session, request));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Uploads a document from a stream in chunks of `CHUNK_SIZE`. The document is created
+    /// or replaced when all chunks were uploaded. Returns the length of the payload. A
+    /// failed upload is aborted, so its staged chunks are dropped.
+    pub fn upload<R: Read>(&mut self,
+                           id: Uuid,
+                           reader: &mut R,
+                           session: TransactionId)
+                           -> Result<u64> {
+        let upload = try!(self.begin_upload(id));
+
+        let result = self.upload_chunks(upload, reader)
+            .and_then(|_| self.commit_upload(upload, session));
+
+        if result.is_err() {
+            // the error of the upload is returned, pending uploads expire after
+            // `UPLOAD_TTL` if the abort fails as well
+            let _ = self.abort_upload(upload);
+        }
+
+        result
+    }
+
+    /// Reads a stream in chunks of `CHUNK_SIZE` and uploads them to a pending upload
+    fn upload_chunks<R: Read>(&mut self, upload: UploadId, reader: &mut R) -> Result<()> {
+        let mut index = 0;
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            try!(reader.by_ref()
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)
+                .map_err(ClientError::Io));
+
+            if chunk.is_empty() && index > 0 {
+                return Ok(());
+            }
+
+            let length = chunk.len();
+            try!(self.upload_chunk(upload, index, chunk));
+            index += 1;
+
+            if length < CHUNK_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Downloads a document in chunks of `CHUNK_SIZE` into a stream. Returns the number
+    /// of written bytes. Fails with a conflict if the document is changed during the
+    /// download, the stream then contains an incomplete payload.
+    pub fn download<W: Write>(&mut self, id: Uuid, writer: &mut W) -> Result<u64> {
+        let mut offset = 0;
+        let mut pinned = None;
+
+        loop {
+            let (version, chunk) = try!(self.get_versioned_range(id, offset, CHUNK_SIZE as u64));
+
+            // a write between two chunks would join the ranges of different payloads
+            match pinned {
+                Some(pinned) if pinned != version => {
+                    return Err(ClientError::from(DocumentError::VersionChanged(id)));
+                }
+                _ => pinned = Some(version),
+            }
+
+            try!(writer.write_all(&chunk).map_err(ClientError::Io));
+            offset += chunk.len() as u64;
+
+            if chunk.len() < CHUNK_SIZE {
+                return Ok(offset);
+            }
+        }
+    }
+
+    /// Starts a chunked upload of a document and returns its `UploadId`
+    pub fn begin_upload(&mut self, id: Uuid) -> Result<UploadId> {
+        let upload = Uuid::new_v4();
+
+        let response = try!(self.propose(Message::BeginTimedUpload(upload, id, timestamp()),
+                                         TransactionId::new(),
+                                         None));
+
+        try!(decode_response::<()>(response.as_slice()));
+
+        Ok(upload)
+    }
+
+    /// Uploads the chunk with the `index` of a pending upload. Sending a chunk again
+    /// replaces it
+    pub fn upload_chunk(&mut self, upload: UploadId, index: u64, chunk: Vec<u8>) -> Result<()> {
+        let response = try!(self.propose(Message::UploadChunk(upload, index, chunk),
+                                         TransactionId::new(),
+                                         None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Creates or replaces the document with the chunks of the upload and returns the
+    /// length of the payload
+    pub fn commit_upload(&mut self, upload: UploadId, session: TransactionId) -> Result<u64> {
+        let response = try!(self.propose(Message::CommitUpload(session, upload), session, None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Drops the chunks of a pending upload
+    pub fn abort_upload(&mut self, upload: UploadId) -> Result<()> {
+        let response = try!(self.propose(Message::AbortUpload(upload), TransactionId::new(), None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Returns at most `length` bytes of the payload of a document from the `offset`
+    pub fn get_range(&mut self, id: Uuid, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let response = try!(self.query(&Message::GetRange(id, offset, length)));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Like `get_range`, but returns the version of the document together with the bytes
+    pub fn get_versioned_range(&mut self,
+                               id: Uuid,
+                               offset: u64,
+                               length: u64)
+                               -> Result<(usize, Vec<u8>)> {
+        let response = try!(self.query(&Message::GetVersionedRange(id, offset, length)));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Sets the JSON Schema which every payload of the log has to match. `None` removes
+    /// the schema
+    pub fn set_schema(&mut self, schema: Option<String>) -> Result<()> {
+        let response = try!(self.propose(Message::SetSchema(schema), TransactionId::new(), None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Returns the JSON Schema of the log
+    pub fn get_schema(&mut self) -> Result<Option<String>> {
+        let response = try!(self.query(&Message::GetSchema));
+
+        decode_value(response.as_slice())
+    }
+
+    /// Keeps removed documents as tombstones, so they can be restored with `undelete`
+    pub fn set_soft_delete(&mut self, enabled: bool) -> Result<()> {
+        let response = try!(self.propose(Message::SetSoftDelete(enabled),
+                                         TransactionId::new(),
+                                         None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Replaces the limits of the log
+    pub fn set_quota(&mut self, quota: Quota) -> Result<()> {
+        let response = try!(self.propose(Message::SetQuota(quota), TransactionId::new(), None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Returns the number of documents and bytes of the log together with its quota
+    pub fn get_usage(&mut self) -> Result<Usage> {
+        let response = try!(self.query(&Message::GetUsage));
+
+        decode_value(response.as_slice())
+    }
+
+    /// Reports whether the volume of the `node` is below its free space watermark
+    pub fn set_storage_full(&mut self, node: String, full: bool) -> Result<()> {
+        let response = try!(self.propose(Message::SetStorageFull(node, full),
+                                         TransactionId::new(),
+                                         None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Proposes a heartbeat with the current time. Replicas which applied it report their
+    /// staleness relative to it
+    pub fn heartbeat(&mut self) -> Result<()> {
+        let response = try!(self.propose(Message::Heartbeat(timestamp()),
+                                         TransactionId::new(),
+                                         None));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Adds `delta` atomically to a numeric document and returns the new value
+    pub fn increment(&mut self,
+                     id: Uuid,
+                     field: Option<&str>,
+                     delta: i64,
+                     session: TransactionId,
+                     request: Option<RequestId>)
+                     -> Result<i64> {
+        let message = Message::Increment(session, id, field.map(|f| f.to_string()), delta);
+
+        let response = try!(self.propose(message, session, request));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Returns the next value of a sequence of the log
+    pub fn next_sequence(&mut self, name: &str, request: Option<RequestId>) -> Result<u64> {
+        let response = try!(self.propose(Message::NextSequence(name.to_string()),
+                                         TransactionId::new(),
+                                         request));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Begins a new transaction and returns its id
+    pub fn begin_transaction(&mut self, session: TransactionId) -> Result<String> {
+        let lid = self.lid;
+
+        let res = try!(self.send(lid, |client| client.begin_transaction(session)));
+
+        let id = try!(Uuid::from_bytes(res.as_slice())
+                .map_err(|err| ClientError::Protocol(format!("Invalid transaction id: {}", err))))
+            .hyphenated()
+            .to_string();
+        let tid = try!(TransactionId::from(&id)
+            .map_err(|_| ClientError::Protocol(format!("Invalid transaction id: {}", id))));
+
+        // Registers the transaction in the state machine to track its writes
+        let payload = try!(encode_message(&Message::BeginTransaction(tid,
+                                                                     self.username.clone(),
+                                                                     timestamp())));
+        if let Err(err) = self.send(lid, |client| client.propose(tid, payload.as_slice())) {
+            // the raft transaction is open although the state machine does not know it
+            if let Err(rollback_err) = self.rollback_transaction(tid) {
+                println!("Cannot rollback the transaction {}: {}", tid, rollback_err);
+            }
+
+            return Err(err);
+        }
+
+        Ok(id)
+    }
+
+    /// Begins a new transaction and returns its handle. The transaction is rolled back when
+    /// the handle is dropped without `commit`
+    pub fn transaction(&mut self) -> Result<Transaction> {
+        let id = try!(self.begin_transaction(TransactionId::new()));
+
+        let session = try!(TransactionId::from(&id)
+            .map_err(|_| ClientError::Protocol(format!("Invalid transaction id: {}", id))));
+
+        Ok(Transaction {
+            client: self,
+            session: session,
+            finished: false,
+        })
+    }
+
+    /// Runs `operations` in a new transaction and commits it. If a document is locked by
+    /// another transaction, the transaction is rolled back and `operations` are run again
+    /// in a new one, at most `max_retries` times of the `RetryPolicy`
+    pub fn with_transaction<T, F>(&mut self, mut operations: F) -> Result<T>
+        where F: FnMut(&mut Transaction) -> Result<T>
+    {
+        let mut retries = 0;
+
+        loop {
+            let result = {
+                let mut transaction = try!(self.transaction());
+
+                match operations(&mut transaction) {
+                    Ok(value) => transaction.commit().map(|_| value),
+                    Err(err) => Err(err),
+                }
+            };
+
+            match result {
+                Err(ClientError::Conflict(DocumentError::Conflict(_, _)))
+                    if retries < self.policy.max_retries => {
+                    retries += 1;
+                    sleep(self.policy.backoff(retries));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Commits a transaction. The state machine keeps the undo records until raft ended
+    /// the transaction, so a failed end is rolled back completely.
+    pub fn commit_transaction(&mut self, session: TransactionId) -> Result<String> {
+        let lid = self.lid;
+
+        let res = try!(self.send(lid, |client| client.end_transaction(session)));
+
+        // the transaction is ended, so the command is proposed outside of it
+        let payload = try!(encode_message(&Message::CommitTransaction(session)));
+        try!(self.send(lid, |client| client.propose(TransactionId::new(), payload.as_slice())));
+
+        decode_text(res.as_slice())
+    }
+
+    /// Rollbacks a transaction
+    pub fn rollback_transaction(&mut self, session: TransactionId) -> Result<String> {
+        let lid = self.lid;
+
+        let res = try!(self.send(lid, |client| client.rollback_transaction(session)));
+
+        // the cache might hold writes of the transaction
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+
+        decode_text(res.as_slice())
+    }
+
+    /// Returns all open transactions of the log
+    pub fn get_transactions(&mut self) -> Result<Vec<TransactionInfo>> {
+        let response = try!(self.query(&Message::GetTransactions));
+
+        decode_value(response.as_slice())
+    }
+
+    /// Creates a named savepoint in a transaction
+    pub fn create_savepoint(&mut self, session: TransactionId, name: &str) -> Result<()> {
+        let lid = self.lid;
+
+        let payload = try!(encode_message(&Message::CreateSavepoint(session, name.to_string())));
+
+        let response = try!(self.send(lid, |client| client.propose(session, payload.as_slice())));
+
+        decode_response(response.as_slice())
+    }
+
+    /// Undoes all changes of a transaction after the savepoint. The transaction stays open.
+    pub fn rollback_to_savepoint(&mut self, session: TransactionId, name: &str) -> Result<()> {
+        let lid = self.lid;
+
+        let payload = try!(encode_message(&Message::RollbackToSavepoint(session,
+                                                                        name.to_string())));
+
+        let response = try!(self.send(lid, |client| client.propose(session, payload.as_slice())));
+
+        if let Some(cache) = self.cache.as_mut() {
+            cache.clear();
+        }
+
+        decode_response(response.as_slice())
+    }
+}
+
+/// A transaction of a `PaenkoClient`. All writes of the handle belong to the transaction.
+/// It is committed with `commit` and rolled back when it is dropped before.
+pub struct Transaction<'a> {
+    client: &'a mut PaenkoClient,
+    session: TransactionId,
+    finished: bool,
+}
+
+impl<'a> Transaction<'a> {
+    /// Returns the `TransactionId` of the transaction
+    pub fn id(&self) -> TransactionId {
+        self.session
+    }
+
+    /// Gets a document. The uncommitted writes of the transaction are visible
+    pub fn get(&mut self, id: Uuid) -> Result<Document> {
+        self.client.get(id, Consistency::Leader)
+    }
+
+    /// Inserts a new document. Fails if a document with the same id already exists
+    pub fn post(&mut self, document: Document) -> Result<Uuid> {
+        self.client.post(document, self.session, None)
+    }
+
+    /// Inserts a document or replaces the payload of the document if the id already exists
+    pub fn upsert(&mut self, document: Document) -> Result<Uuid> {
+        self.client.upsert(document, self.session, None)
+    }
+
+    /// Replaces the payload of a document
+    pub fn put(&mut self, id: Uuid, new_payload: Vec<u8>) -> Result<()> {
+        self.client.put(id, new_payload, self.session, None)
+    }
+
+    /// Removes a document
+    pub fn remove(&mut self, id: Uuid) -> Result<()> {
+        self.client.remove(id, self.session, None)
+    }
+
+    /// Creates a named savepoint
+    pub fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.client.create_savepoint(self.session, name)
+    }
+
+    /// Undoes all changes after the savepoint. The transaction stays open.
+    pub fn rollback_to(&mut self, name: &str) -> Result<()> {
+        self.client.rollback_to_savepoint(self.session, name)
+    }
+
+    /// Commits the transaction. It is rolled back if the commit fails
+    pub fn commit(mut self) -> Result<String> {
+        let result = self.client.commit_transaction(self.session);
+        self.finished = result.is_ok();
+        result
+    }
+
+    /// Rollbacks the transaction
+    pub fn rollback(mut self) -> Result<String> {
+        self.finished = true;
+        self.client.rollback_transaction(self.session)
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Err(err) = self.client.rollback_transaction(self.session) {
+                println!("Cannot rollback the transaction {}: {}", self.session, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use raft::LogId;
+    use document::parse_addr;
+    use error::ClientError;
+
+    #[test]
+    fn test_leader_per_log() {
+        let seed = parse_addr("127.0.0.1:9000").unwrap();
+        let leader = parse_addr("127.0.0.1:9001").unwrap();
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+        let other = LogId::from("51b2c2b6-ac72-4a12-bd4e-8a1fc3e4b4ef").unwrap();
+
+        let mut client = PaenkoClient::new(vec![seed], "user", "password", lid);
+        assert_eq!(Some(seed), client.node(lid));
+
+        client.leaders.insert(lid, leader);
+        assert_eq!(Some(leader), client.node(lid));
+        assert_eq!(Some(leader), client.leader());
+
+        // the leader of another log is discovered on its own
+        assert_eq!(Some(seed), client.node(other));
+        client.set_lid(other);
+        assert_eq!(None, client.leader());
+    }
+    #[test]
+    fn test_nodes() {
+        let seed = parse_addr("127.0.0.1:9000").unwrap();
+        let member = parse_addr("127.0.0.1:9001").unwrap();
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+
+        let mut client = PaenkoClient::new(vec![seed], "user", "password", lid);
+        assert_eq!(vec![seed], client.nodes());
+
+        client.members = vec![member, seed];
+        assert_eq!(vec![member, seed], client.nodes());
+
+        // a failed node is skipped by the next request
+        assert_eq!(Some(member), client.node(lid));
+        client.next_node += 1;
+        assert_eq!(Some(seed), client.node(lid));
+    }
+
+    #[test]
+    fn test_no_seeds() {
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+        let mut client = PaenkoClient::new(Vec::new(), "user", "password", lid);
+
+        match client.get_usage() {
+            Err(ClientError::NoLeader(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+    #[test]
+    fn test_transaction_without_seeds() {
+        let lid = LogId::from("3d30aa56-98b2-4891-aec5-847cee6e1703").unwrap();
+        let mut client = PaenkoClient::new(Vec::new(), "user", "password", lid);
+        let mut calls = 0;
+
+        let result = client.with_transaction(|_| {
+            calls += 1;
+            Ok(())
+        });
+
+        match result {
+            Err(ClientError::NoLeader(_)) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(0, calls);
+    }
+}
@@ -24,6 +24,8 @@ pub struct ServerConfig {
     pub node_address: String,
     pub community_string: String,
     pub binding_addr: String,
+    /// The address of the Cap'n Proto endpoint. It is not started if it is not set
+    pub capnp_addr: Option<String>,
 }
 
 #[derive(Debug,Deserialize,Clone)]
@@ -45,6 +47,8 @@ pub struct PeerConfig {
 pub struct LogConfig {
     pub path: String,
     pub lid: String,
+    /// The log becomes read-only while the volume has less free bytes
+    pub min_free_space: Option<u64>,
 }
 
 #[derive(Debug,Deserialize,Clone)]
@@ -74,6 +78,13 @@ impl Config {
         self.server.binding_addr.parse().expect("Binding address is invalid")
     }
 
+    pub fn get_capnp_addr(&self) -> Option<SocketAddr> {
+        self.server
+            .capnp_addr
+            .as_ref()
+            .map(|addr| addr.parse().expect("Cap'n Proto address is invalid"))
+    }
+
     pub fn get_dynamic_peering(&self) -> Option<(ServerId, SocketAddr)> {
         match self.dynamic_peer {
             Some(ref dpeer) => {
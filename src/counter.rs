@@ -0,0 +1,103 @@
+use serde_json;
+use serde_json::Value;
+
+use document::{DocumentId, DocumentError};
+
+/// Adds `delta` to the number in the payload and returns the new payload and value.
+/// If a `field` is given, the payload is a JSON object and the number is found by the
+/// path of the field, e.g. `stats.orders`. Missing fields start at zero.
+pub fn increment(id: DocumentId,
+                 payload: &[u8],
+                 field: Option<&str>,
+                 delta: i64)
+                 -> Result<(Vec<u8>, i64), DocumentError> {
+    match field {
+        None => {
+            let current = try!(::std::str::from_utf8(payload)
+                .ok()
+                .and_then(|p| p.trim().parse::<i64>().ok())
+                .ok_or(DocumentError::NotNumeric(id)));
+
+            let value = try!(current.checked_add(delta).ok_or(DocumentError::Overflow(id)));
+
+            Ok((value.to_string().into_bytes(), value))
+        }
+        Some(field) => {
+            let mut json: Value = try!(serde_json::from_slice(payload)
+                .map_err(|_| DocumentError::NotNumeric(id)));
+
+            let value = {
+                let number = try!(find_field(&mut json, field)
+                    .ok_or(DocumentError::NotNumeric(id)));
+
+                let current = match *number {
+                    Value::Null => 0,
+                    Value::I64(n) => n,
+                    Value::U64(n) if n <= i64::max_value() as u64 => n as i64,
+                    _ => return Err(DocumentError::NotNumeric(id)),
+                };
+
+                let value = try!(current.checked_add(delta).ok_or(DocumentError::Overflow(id)));
+                *number = Value::I64(value);
+
+                value
+            };
+
+            Ok((serde_json::to_vec(&json).unwrap(), value))
+        }
+    }
+}
+
+/// Returns the value of the field path. Missing fields are created with `Null`
+fn find_field<'a>(json: &'a mut Value, field: &str) -> Option<&'a mut Value> {
+    let mut current = json;
+
+    for key in field.split('.') {
+        let object = match current.as_object_mut() {
+            Some(object) => object,
+            None => return None,
+        };
+
+        current = object.entry(key.to_string()).or_insert(Value::Null);
+    }
+
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use counter::increment;
+    use document::DocumentError;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_increment_payload() {
+        let id = Uuid::new_v4();
+
+        let (payload, value) = increment(id, b"41", None, 1).unwrap();
+        assert_eq!(42, value);
+        assert_eq!(b"42".to_vec(), payload);
+
+        let (_, value) = increment(id, b"42", None, -50).unwrap();
+        assert_eq!(-8, value);
+
+        assert_eq!(Err(DocumentError::NotNumeric(id)),
+                   increment(id, b"forty-two", None, 1));
+    }
+
+    #[test]
+    fn test_increment_field() {
+        let id = Uuid::new_v4();
+
+        let payload = br#"{"stats":{"orders":9}}"#;
+
+        let (payload, value) = increment(id, payload, Some("stats.orders"), 1).unwrap();
+        assert_eq!(10, value);
+
+        let (_, value) = increment(id, &payload, Some("stats.returns"), 2).unwrap();
+        assert_eq!(2, value);
+
+        assert_eq!(Err(DocumentError::NotNumeric(id)),
+                   increment(id, br#"{"stats":"none"}"#, Some("stats.orders"), 1));
+    }
+}
@@ -0,0 +1,111 @@
+use document::{DocumentId, DocumentError};
+
+/// A change of a byte range of a payload. Deltas are replicated instead of the complete
+/// payload, so small edits of large documents keep the log small.
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub enum Delta {
+    /// Appends the bytes to the payload
+    Append(Vec<u8>),
+    /// Overwrites the payload at the offset. The payload grows if the bytes exceed its end,
+    /// but the offset must not be behind the end.
+    Write(u64, Vec<u8>),
+    /// Shortens the payload to the length
+    Truncate(u64),
+}
+
+impl Delta {
+    /// Applies the delta to the payload and returns the deltas which undo it, in the order
+    /// in which they have to be applied. The undo deltas only contain the overwritten bytes.
+    pub fn apply(&self,
+                 id: DocumentId,
+                 payload: &mut Vec<u8>)
+                 -> Result<Vec<Delta>, DocumentError> {
+        let length = payload.len();
+
+        match *self {
+            Delta::Append(ref bytes) => {
+                payload.extend_from_slice(bytes);
+
+                Ok(vec![Delta::Truncate(length as u64)])
+            }
+            Delta::Write(offset, ref bytes) => {
+                if offset > length as u64 {
+                    return Err(DocumentError::InvalidRange(id));
+                }
+
+                let offset = offset as usize;
+                let end = offset + bytes.len();
+                let overwritten = payload[offset..::std::cmp::min(end, length)].to_vec();
+
+                if end > length {
+                    payload.resize(end, 0);
+                }
+                payload[offset..end].copy_from_slice(bytes);
+
+                let mut undo = Vec::new();
+                if end > length {
+                    undo.push(Delta::Truncate(length as u64));
+                }
+                undo.push(Delta::Write(offset as u64, overwritten));
+
+                Ok(undo)
+            }
+            Delta::Truncate(new_length) => {
+                if new_length > length as u64 {
+                    return Err(DocumentError::InvalidRange(id));
+                }
+
+                let removed = payload.split_off(new_length as usize);
+
+                Ok(vec![Delta::Append(removed)])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn undo(id: Uuid, payload: &mut Vec<u8>, deltas: Vec<Delta>) {
+        for delta in deltas {
+            delta.apply(id, payload).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_apply_and_undo() {
+        let id = Uuid::new_v4();
+        let cases: Vec<(&[u8], Delta, &[u8])> =
+            vec![(&b"hello"[..], Delta::Append(b" world".to_vec()), &b"hello world"[..]),
+                 (&b"hello world"[..], Delta::Write(0, b"j".to_vec()), &b"jello world"[..]),
+                 (&b"hello world"[..],
+                  Delta::Write(6, b"universe".to_vec()),
+                  &b"hello universe"[..]),
+                 (&b"hello"[..], Delta::Write(5, b"!".to_vec()), &b"hello!"[..]),
+                 (&b"hello world"[..], Delta::Truncate(5), &b"hello"[..])];
+
+        for (original, delta, expected) in cases {
+            let mut payload = original.to_vec();
+
+            let inverse = delta.apply(id, &mut payload).unwrap();
+            assert_eq!(expected, payload.as_slice());
+
+            undo(id, &mut payload, inverse);
+            assert_eq!(original, payload.as_slice());
+        }
+    }
+
+    #[test]
+    fn test_invalid_range() {
+        let id = Uuid::new_v4();
+        let mut payload = b"hello".to_vec();
+
+        assert_eq!(Err(DocumentError::InvalidRange(id)),
+                   Delta::Write(6, b"x".to_vec()).apply(id, &mut payload));
+        assert_eq!(Err(DocumentError::InvalidRange(id)),
+                   Delta::Truncate(6).apply(id, &mut payload));
+        assert_eq!(b"hello".to_vec(), payload);
+    }
+}
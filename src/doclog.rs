@@ -1,5 +1,6 @@
 use std::{error, fmt, result};
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use bincode::SizeLimit;
 use bincode::serde::serialize as encode;
@@ -7,6 +8,7 @@ use bincode::serde::deserialize_from as decode_from;
 use bincode::serde::serialize_into as encode_into;
 use std::fs::OpenOptions;
 use std::path::{Path, PathBuf};
+use serde::Serialize;
 
 use raft::persistent_log::Log;
 use raft::LogIndex;
@@ -21,24 +23,43 @@ pub struct DocLog {
     path: PathBuf,
 }
 
-/// Non-instantiable error type for MemLog
-pub enum Error { }
+/// Errors of saving or reading the log on its volume
+#[derive(Debug)]
+pub enum Error {
+    /// The volume cannot be read or written, e.g. because it is full
+    Io(io::Error),
+    /// The saved state cannot be encoded or decoded
+    Encoding(String),
+}
 
-impl fmt::Display for Error {
-    fn fmt(&self, _fmt: &mut fmt::Formatter) -> fmt::Result {
-        unreachable!()
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
     }
 }
 
-impl fmt::Debug for Error {
-    fn fmt(&self, _fmt: &mut fmt::Formatter) -> fmt::Result {
-        unreachable!()
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error of the log: {}", err),
+            Error::Encoding(ref reason) => write!(f, "Cannot encode the log: {}", reason),
+        }
     }
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
-        unreachable!()
+        match *self {
+            Error::Io(_) => "I/O error of the log",
+            Error::Encoding(_) => "cannot encode the log",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::Encoding(_) => None,
+        }
     }
 }
 
@@ -70,21 +91,39 @@ impl DocLog {
         self.path.to_str()
     }
 
-    // TODO implement Result
     /// Saves all LogEntries on the disk
-    pub fn snapshot(&self) {
-        let mut handler = OpenOptions::new()
+    pub fn snapshot(&self) -> ::std::io::Result<()> {
+        let mut handler = try!(OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
-            .open(format!("{}/{}_log", self.path.to_str().unwrap(), self.logid))
-            .expect(&format!("Filehandler cannot open {}/{}_{}",
-                             self.path.to_str().unwrap(),
-                             self.logid,
-                             "log"));
+            .open(format!("{}/{}_log", self.path.to_str().unwrap(), self.logid)));
 
         encode_into(&mut handler, &self.entries.as_slice(), SizeLimit::Infinite)
-            .expect("Log serialize_into failed");
+            .map_err(|err| ::std::io::Error::new(::std::io::ErrorKind::Other, format!("{}", err)))
+    }
+
+    /// Returns the path of a file of the log
+    fn file(&self, name: &str) -> String {
+        format!("{}/{}_{}", self.path.to_str().unwrap(), self.logid, name)
+    }
+
+    /// Overwrites a file of the log with the value and syncs it to the volume
+    fn save<T: Serialize>(&self, name: &str, value: &T) -> result::Result<(), Error> {
+        let bytes = try!(encode(value, SizeLimit::Infinite)
+            .map_err(|err| Error::Encoding(format!("{}", err))));
+
+        let mut handler = try!(OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.file(name)));
+
+        try!(handler.write_all(bytes.as_slice()));
+        try!(handler.flush());
+        try!(handler.sync_all());
+
+        Ok(())
     }
 
     /// Restores all LogEntries from disk
@@ -104,87 +143,38 @@ impl DocLog {
     }
 }
 
-// TODO error handling for IO
 impl Log for DocLog {
     type Error = Error;
 
     fn current_term(&self) -> result::Result<Term, Error> {
-        let mut term_handler =
-            File::open(format!("{}/{}_{}", self.path.to_str().unwrap(), self.logid, "term"))
-                .expect("Unable to read current_term");
-
-        let term: Term = decode_from(&mut term_handler, SizeLimit::Infinite).unwrap();
+        let mut term_handler = try!(File::open(self.file("term")));
 
-        Ok(term)
+        decode_from(&mut term_handler, SizeLimit::Infinite)
+            .map_err(|err| Error::Encoding(format!("{}", err)))
     }
 
     fn set_current_term(&mut self, term: Term) -> result::Result<(), Error> {
-        let mut term_handler = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!("{}/{}_{}", self.path.to_str().unwrap(), self.logid, "term"))
-            .expect(&format!("Filehandler cannot open {}/{}_{}",
-                             self.path.to_str().unwrap(),
-                             self.logid,
-                             "term"));
-
-        let bytes = encode(&term, SizeLimit::Infinite).expect("Cannot encode term");
+        try!(self.save("term", &term));
 
-        term_handler.write_all(bytes.as_slice()).expect("Unable to save the current term");
-
-        term_handler.flush().expect("Flushing failed");
-
-        term_handler.sync_all();
-
-        self.set_voted_for(None).unwrap();
-
-        Ok(())
+        self.set_voted_for(None)
     }
 
     fn inc_current_term(&mut self) -> result::Result<Term, Error> {
-        self.set_voted_for(None).unwrap();
-        let new_term = self.current_term().unwrap() + 1;
-        self.set_current_term(new_term).unwrap();
+        try!(self.set_voted_for(None));
+        let new_term = try!(self.current_term()) + 1;
+        try!(self.set_current_term(new_term));
         self.current_term()
     }
 
     fn voted_for(&self) -> result::Result<Option<ServerId>, Error> {
-        let mut voted_for_handler = File::open(format!("{}/{}_{}",
-                                                       self.path.to_str().unwrap(),
-                                                       self.logid,
-                                                       "voted_for"))
-            .expect("Unable to read voted_for");
-
-        let voted_for: Option<ServerId> = decode_from(&mut voted_for_handler, SizeLimit::Infinite)
-            .unwrap();
+        let mut voted_for_handler = try!(File::open(self.file("voted_for")));
 
-        Ok(voted_for)
+        decode_from(&mut voted_for_handler, SizeLimit::Infinite)
+            .map_err(|err| Error::Encoding(format!("{}", err)))
     }
 
     fn set_voted_for(&mut self, address: Option<ServerId>) -> result::Result<(), Error> {
-        let mut voted_for_handler = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!("{}/{}_{}",
-                          self.path.to_str().unwrap(),
-                          self.logid,
-                          "voted_for"))
-            .expect(&format!("Filehandler cannot open {}/{}_{}",
-                             self.path.to_str().unwrap(),
-                             self.logid,
-                             "voted_for"));
-
-        let bytes = encode(&address, SizeLimit::Infinite).expect("Cannot encode voted_for");
-
-        voted_for_handler.write_all(bytes.as_slice()).expect("Unable to save the server vote");
-
-        voted_for_handler.flush().expect("Flushing failed");
-
-        voted_for_handler.sync_all();
-
-        Ok(())
+        self.save("voted_for", &address)
     }
 
     fn latest_log_index(&self) -> result::Result<LogIndex, Error> {
@@ -211,8 +201,10 @@ impl Log for DocLog {
                       -> result::Result<(), Error> {
         assert!(self.latest_log_index().unwrap() + 1 >= from);
         self.entries.truncate((from - 1).as_u64() as usize);
-        self.snapshot();
-        Ok(self.entries.extend(entries.iter().map(|&(term, command)| (term, command.to_vec()))))
+
+        self.entries.extend(entries.iter().map(|&(term, command)| (term, command.to_vec())));
+
+        self.snapshot().map_err(Error::from)
     }
 
     fn truncate(&mut self, lo: LogIndex) -> result::Result<(), Error> {
@@ -326,4 +318,18 @@ mod test {
             dir.close().unwrap();
         }
     }
+
+    #[test]
+    fn test_missing_volume() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let volume = dir.path().join("volume");
+            let mut store = DocLog::new(&volume, *lid);
+            ::std::fs::remove_dir_all(&volume).unwrap();
+
+            assert!(store.set_current_term(Term::from(1)).is_err());
+            assert!(store.set_voted_for(None).is_err());
+            assert!(store.append_entries(LogIndex::from(1), &[(Term::from(0), &[1])]).is_err());
+            dir.close().unwrap();
+        }
+    }
 }
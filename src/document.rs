@@ -1,6 +1,13 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::net::ToSocketAddrs;
 use std::net::SocketAddr;
-use uuid::Uuid;
+use uuid::{Uuid, NAMESPACE_OID};
+use raft::TransactionId;
+use delta::Delta;
+use schema::SchemaViolation;
+use quota::QuotaLimit;
+use blobs::Hash;
 
 pub type DocumentId = Uuid;
 
@@ -19,12 +26,165 @@ impl Document {
     }
 }
 
-pub fn parse_addr(addr: &str) -> SocketAddr {
+/// A document in the state machine. Its payload is kept in the `BlobStore` of the log
+#[derive(Serialize,Deserialize,Debug,Clone,Eq,PartialEq)]
+pub struct StoredDocument {
+    pub version: usize,
+    /// The hash of the payload in the `BlobStore`
+    pub hash: Hash,
+}
+
+/// The response of a multi-get
+#[derive(Serialize,Deserialize,Debug,Clone,Eq,PartialEq)]
+pub struct MultiGet {
+    /// The documents which were found, in the order of the request
+    pub documents: Vec<Document>,
+    /// The ids which do not exist
+    pub missing: Vec<DocumentId>,
+}
+
+/// Errors which are returned by the state machine when a command was rejected
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub enum DocumentError {
+    /// The document does not exist
+    NotFound(DocumentId),
+    /// The document was already written by another open transaction
+    Conflict(DocumentId, TransactionId),
+    /// A document with this id already exists
+    AlreadyExists(DocumentId),
+    /// The payload or the field of the document is not an integer
+    NotNumeric(DocumentId),
+    /// The counter of the document would overflow
+    Overflow(DocumentId),
+    /// There is no open transaction with this id
+    TransactionNotFound(TransactionId),
+    /// The transaction has no savepoint with this name
+    SavepointNotFound(String),
+    /// The document is being moved to another log
+    Moving(DocumentId),
+    /// The byte range is outside of the payload
+    InvalidRange(DocumentId),
+    /// There is no pending upload with this id
+    UploadNotFound(Uuid),
+    /// The chunk with this index was not uploaded
+    IncompleteUpload(Uuid, u64),
+    /// The payload does not match the schema of the log
+    InvalidDocument(DocumentId, Vec<SchemaViolation>),
+    /// The schema is not a valid JSON object
+    InvalidSchema(String),
+    /// The write would exceed the quota of the log
+    QuotaExceeded(DocumentId, QuotaLimit),
+    /// The log is read-only, because a node ran out of space
+    ReadOnly,
+    /// The command has an unknown format version or cannot be decoded
+    InvalidCommand(String),
+    /// The document was changed while it was read in several ranges
+    VersionChanged(DocumentId),
+}
+
+impl fmt::Display for DocumentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DocumentError::NotFound(ref id) => write!(f, "Document {} was not found", id),
+            DocumentError::Conflict(ref id, ref session) => {
+                write!(f,
+                       "Document {} is locked by the transaction {}",
+                       id,
+                       session)
+            }
+            DocumentError::AlreadyExists(ref id) => write!(f, "Document {} already exists", id),
+            DocumentError::NotNumeric(ref id) => write!(f, "Document {} is not numeric", id),
+            DocumentError::Overflow(ref id) => write!(f, "The counter of {} would overflow", id),
+            DocumentError::TransactionNotFound(ref session) => {
+                write!(f, "Transaction {} is not open", session)
+            }
+            DocumentError::SavepointNotFound(ref name) => {
+                write!(f, "Savepoint {} does not exist", name)
+            }
+            DocumentError::Moving(ref id) => {
+                write!(f, "Document {} is being moved to another log", id)
+            }
+            DocumentError::InvalidRange(ref id) => {
+                write!(f, "The range is outside of the payload of {}", id)
+            }
+            DocumentError::UploadNotFound(ref id) => write!(f, "Upload {} is not pending", id),
+            DocumentError::IncompleteUpload(ref id, index) => {
+                write!(f, "Chunk {} of upload {} is missing", index, id)
+            }
+            DocumentError::InvalidDocument(ref id, ref violations) => {
+                try!(write!(f, "Document {} does not match the schema:", id));
+
+                for violation in violations {
+                    try!(write!(f, " {};", violation));
+                }
+
+                Ok(())
+            }
+            DocumentError::InvalidSchema(ref reason) => write!(f, "Invalid schema: {}", reason),
+            DocumentError::QuotaExceeded(ref id, ref limit) => {
+                write!(f, "Document {} exceeds the quota: {}", id, limit)
+            }
+            DocumentError::ReadOnly => write!(f, "The log is read-only, a node ran out of space"),
+            DocumentError::InvalidCommand(ref reason) => {
+                write!(f, "The command cannot be decoded: {}", reason)
+            }
+            DocumentError::VersionChanged(ref id) => {
+                write!(f, "Document {} was changed while it was read", id)
+            }
+        }
+    }
+}
+
+/// Parses a socket address or resolves a host name. Returns `None` if the address is invalid
+pub fn parse_addr(addr: &str) -> Option<SocketAddr> {
     addr.to_socket_addrs()
         .ok()
-        .expect(&format!("unable to parse socket address: {}", addr))
-        .next()
-        .unwrap()
+        .and_then(|mut addrs| addrs.next())
+}
+
+/// Returns the `DocumentId` of a key which was chosen by the client. Keys which are
+/// already an uuid are used directly, all other keys are mapped to a name-based uuid.
+pub fn document_id_from_key(key: &str) -> DocumentId {
+    match Uuid::parse_str(key) {
+        Ok(id) => id,
+        Err(_) => Uuid::new_v5(&NAMESPACE_OID, key),
+    }
+}
+
+/// Returns the current unix timestamp in seconds
+pub fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Who removed a document and when
+#[derive(Serialize,Deserialize,Debug,Clone,Eq,PartialEq)]
+pub struct Deletion {
+    pub author: String,
+    /// Unix timestamp in seconds of the removal
+    pub deleted_at: u64,
+}
+
+/// A removed document which is kept in logs with soft delete
+#[derive(Serialize,Deserialize,Debug,Clone,Eq,PartialEq)]
+pub struct Tombstone {
+    pub document: StoredDocument,
+    pub deletion: Deletion,
+}
+
+/// A move of a document to another log which was prepared in the source log. The
+/// document is locked until the move is completed or aborted.
+#[derive(Serialize,Deserialize,Debug,Clone,Eq,PartialEq)]
+pub struct PendingMove {
+    pub id: Uuid,
+    /// The `LogId` of the target log
+    pub target: String,
+    /// The `DocumentId` of the document in the target log
+    pub target_id: DocumentId,
+    /// Keeps the version of the document in the target log
+    pub keep_version: bool,
 }
 
 #[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
@@ -33,6 +193,8 @@ pub enum ActionType {
     Put,
     Post,
     Remove,
+    Undelete,
+    Patch,
 }
 
 // TODO make method private
@@ -41,7 +203,10 @@ pub struct DocumentRecord {
     id: DocumentId,
     path: String,
     pub method: ActionType,
-    old: Option<Vec<u8>>,
+    /// The hash of the old payload in the `BlobStore`
+    old: Option<Hash>,
+    deletion: Option<Deletion>,
+    undo_deltas: Option<Vec<Delta>>,
 }
 
 impl DocumentRecord {
@@ -51,10 +216,12 @@ impl DocumentRecord {
             path: path,
             method: method,
             old: None,
+            deletion: None,
+            undo_deltas: None,
         }
     }
 
-    pub fn set_old_payload(&mut self, old: Vec<u8>) {
+    pub fn set_old_hash(&mut self, old: Hash) {
         self.old = Some(old);
     }
 
@@ -62,7 +229,25 @@ impl DocumentRecord {
         self.id
     }
 
-    pub fn get_old_payload(&self) -> Option<Vec<u8>> {
+    pub fn get_old_hash(&self) -> Option<Hash> {
         self.old.clone()
     }
+
+    /// Saves the deletion of a tombstone which was restored
+    pub fn set_deletion(&mut self, deletion: Deletion) {
+        self.deletion = Some(deletion);
+    }
+
+    pub fn get_deletion(&self) -> Option<Deletion> {
+        self.deletion.clone()
+    }
+
+    /// Saves the deltas which undo a patch instead of the complete old payload
+    pub fn set_undo_deltas(&mut self, deltas: Vec<Delta>) {
+        self.undo_deltas = Some(deltas);
+    }
+
+    pub fn get_undo_deltas(&self) -> Option<Vec<Delta>> {
+        self.undo_deltas.clone()
+    }
 }
@@ -0,0 +1,153 @@
+use std::error;
+use std::fmt;
+use std::io::Error as IoError;
+
+use raft::Error as RError;
+use raft::RaftError;
+
+use capnp::Error as CapnpError;
+use capnp::NotInSchema;
+
+use document::DocumentError;
+
+/// Errors which are returned by the client methods
+#[derive(Debug)]
+pub enum ClientError {
+    /// The document, upload, transaction or savepoint does not exist
+    NotFound(DocumentError),
+    /// The cluster rejected the credentials
+    Unauthorized,
+    /// The write conflicts with an open transaction, an existing document or a pending move
+    Conflict(DocumentError),
+    /// The state machine rejected the request for another reason, e.g. the schema or the
+    /// quota of the log
+    Rejected(DocumentError),
+    /// No leader could be reached. The message tells why
+    NoLeader(String),
+    /// The request did not succeed before the deadline of the `RetryPolicy`
+    Timeout,
+    /// The response could not be decoded or the raft layer failed
+    Protocol(String),
+    /// The connection or a stream of an upload or download failed
+    Io(IoError),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::NotFound(ref err) |
+            ClientError::Conflict(ref err) |
+            ClientError::Rejected(ref err) => write!(f, "{}", err),
+            ClientError::Unauthorized => write!(f, "The credentials were rejected"),
+            ClientError::NoLeader(ref reason) => write!(f, "No leader was found: {}", reason),
+            ClientError::Timeout => write!(f, "The request timed out"),
+            ClientError::Protocol(ref reason) => write!(f, "Protocol error: {}", reason),
+            ClientError::Io(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for ClientError {
+    fn description(&self) -> &str {
+        match *self {
+            ClientError::NotFound(_) => "not found",
+            ClientError::Unauthorized => "unauthorized",
+            ClientError::Conflict(_) => "conflict",
+            ClientError::Rejected(_) => "rejected",
+            ClientError::NoLeader(_) => "no leader",
+            ClientError::Timeout => "timeout",
+            ClientError::Protocol(_) => "protocol error",
+            ClientError::Io(ref err) => err.description(),
+        }
+    }
+}
+
+impl From<DocumentError> for ClientError {
+    fn from(err: DocumentError) -> ClientError {
+        match err {
+            DocumentError::NotFound(_) |
+            DocumentError::TransactionNotFound(_) |
+            DocumentError::SavepointNotFound(_) |
+            DocumentError::UploadNotFound(_) => ClientError::NotFound(err),
+            DocumentError::Conflict(_, _) |
+            DocumentError::AlreadyExists(_) |
+            DocumentError::Moving(_) |
+            DocumentError::VersionChanged(_) => ClientError::Conflict(err),
+            _ => ClientError::Rejected(err),
+        }
+    }
+}
+
+impl From<IoError> for ClientError {
+    fn from(err: IoError) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+impl From<CapnpError> for ClientError {
+    fn from(err: CapnpError) -> ClientError {
+        ClientError::Protocol(format!("Invalid message: {}", err))
+    }
+}
+
+impl From<NotInSchema> for ClientError {
+    fn from(NotInSchema(value): NotInSchema) -> ClientError {
+        ClientError::Protocol(format!("Unknown value {} in the message", value))
+    }
+}
+
+impl From<RError> for ClientError {
+    fn from(err: RError) -> ClientError {
+        match err {
+            RError::Io(err) => ClientError::Io(err),
+            RError::Raft(RaftError::ClusterViolation(leader)) => {
+                ClientError::NoLeader(format!("redirected to {}", leader))
+            }
+            RError::Raft(RaftError::LeaderSearchExhausted) => {
+                ClientError::NoLeader("the nodes know no leader".to_string())
+            }
+            RError::Raft(RaftError::Unauthorized) => ClientError::Unauthorized,
+            err => ClientError::Protocol(format!("{}", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use document::DocumentError;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_from_document_error() {
+        let id = Uuid::new_v4();
+
+        match ClientError::from(DocumentError::NotFound(id)) {
+            ClientError::NotFound(DocumentError::NotFound(not_found)) => assert_eq!(id, not_found),
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        match ClientError::from(DocumentError::AlreadyExists(id)) {
+            ClientError::Conflict(_) => {}
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        match ClientError::from(DocumentError::ReadOnly) {
+            ClientError::Rejected(DocumentError::ReadOnly) => {}
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_from_raft_error() {
+        match ClientError::from(RError::Raft(RaftError::Unauthorized)) {
+            ClientError::Unauthorized => {}
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        match ClientError::from(RError::Raft(RaftError::LeaderSearchExhausted)) {
+            ClientError::NoLeader(_) => {}
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+}
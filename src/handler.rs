@@ -1,165 +1,420 @@
 use document::*;
+use capnp_client::CapnpClient;
+use error::ClientError;
+use transaction::TransactionInfo;
+use quota::{Quota, Usage};
+use delta::Delta;
+use upload::UploadId;
+use std::io::{Read, Write};
+use std::str::FromStr;
 use std::net::SocketAddr;
 use uuid::Uuid;
-use raft::Result;
-use raft::Client;
 use raft::LogId;
 use raft::TransactionId;
-use raft::Error as RError;
-use raft::RaftError;
 
-use bincode::serde::serialize as encode;
-use bincode::serde::deserialize as decode;
-use bincode::SizeLimit;
-use std::collections::HashSet;
-use std::str::from_utf8;
-
-use raft::auth::credentials::SingleCredentials;
-use raft::auth::simple::SimpleAuth;
+/// The id of a client request. A write which is proposed again with the same id is
+/// applied only once
+pub type RequestId = Uuid;
 
+/// A command of the state machine. It is sent and stored in the logs with the envelope
+/// of `wire`. New variants have to be appended, any other change of the layout needs a
+/// new `wire::FORMAT_VERSION`.
 #[derive(Debug,Serialize,Deserialize)]
 pub enum Message {
     Get(Uuid),
-    Post(Document),
-    Remove(Uuid),
-    Put(Uuid, Vec<u8>),
+    Post(TransactionId, Document),
+    Remove(TransactionId, Uuid, String, u64),
+    Undelete(TransactionId, Uuid),
+    Purge(u64, u64),
+    GetTombstones,
+    PrepareMove(Uuid, PendingMove),
+    CompleteMove(Uuid, Uuid),
+    AbortMove(Uuid, Uuid),
+    Put(TransactionId, Uuid, Vec<u8>),
+    Patch(TransactionId, Uuid, Delta),
+    BeginUpload(UploadId, Uuid),
+    UploadChunk(UploadId, u64, Vec<u8>),
+    CommitUpload(TransactionId, UploadId),
+    AbortUpload(UploadId),
+    GetRange(Uuid, u64, u64),
+    SetSchema(Option<String>),
+    GetSchema,
+    GetUsage,
+    SetStorageFull(String, bool),
+    GetStorageState,
+    GetPeers,
+    Upsert(TransactionId, Document),
+    Increment(TransactionId, Uuid, Option<String>, i64),
+    NextSequence(String),
+    GetTransactions,
+    BeginTransaction(TransactionId, String, u64),
+    CommitTransaction(TransactionId),
+    CreateSavepoint(TransactionId, String),
+    RollbackToSavepoint(TransactionId, String),
+    Request(RequestId, Box<Message>),
+    GetMany(Vec<DocumentId>),
+    GetVersion(DocumentId),
+    SetSoftDelete(bool),
+    SetQuota(Quota),
+    GetMove(DocumentId),
+    GetVersionedRange(DocumentId, u64, u64),
+    BeginTimedUpload(UploadId, DocumentId, u64),
+    Heartbeat(u64),
 }
 
-pub struct Handler;
+impl Message {
+    /// Returns the `TransactionId` of a write command
+    pub fn get_session(&self) -> Option<TransactionId> {
+        match *self {
+            Message::Post(session, _) |
+            Message::Remove(session, _, _, _) |
+            Message::Undelete(session, _) |
+            Message::Put(session, _, _) |
+            Message::Patch(session, _, _) |
+            Message::CommitUpload(session, _) |
+            Message::Upsert(session, _) |
+            Message::Increment(session, _, _, _) |
+            Message::CreateSavepoint(session, _) |
+            Message::RollbackToSavepoint(session, _) => Some(session),
+            Message::Request(_, ref message) => message.get_session(),
+            _ => None,
+        }
+    }
 
-impl Handler {
-    /// Returns a HashSet with a single Peer entry
-    fn to_hashset(addr: SocketAddr) -> HashSet<SocketAddr> {
-        let mut hashset: HashSet<SocketAddr> = HashSet::new();
-        hashset.insert(addr);
-        hashset
+    /// Returns the `DocumentId` of the document which is written by the command
+    pub fn get_document_id(&self) -> Option<DocumentId> {
+        match *self {
+            Message::Post(_, ref document) |
+            Message::Upsert(_, ref document) => Some(document.id),
+            Message::Remove(_, id, _, _) |
+            Message::Undelete(_, id) |
+            Message::Put(_, id, _) |
+            Message::Patch(_, id, _) |
+            Message::Increment(_, id, _, _) |
+            Message::PrepareMove(id, _) |
+            Message::CompleteMove(id, _) |
+            Message::AbortMove(id, _) => Some(id),
+            Message::Request(_, ref message) => message.get_document_id(),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the command writes new data. These commands are rejected while the
+    /// log is read-only. Removing documents and aborting pending operations free space,
+    /// so they are still accepted.
+    pub fn is_write(&self) -> bool {
+        match *self {
+            Message::Post(_, _) |
+            Message::Undelete(_, _) |
+            Message::Put(_, _, _) |
+            Message::Patch(_, _, _) |
+            Message::BeginUpload(_, _) |
+            Message::BeginTimedUpload(_, _, _) |
+            Message::UploadChunk(_, _, _) |
+            Message::CommitUpload(_, _) |
+            Message::Upsert(_, _) |
+            Message::Increment(_, _, _, _) |
+            Message::NextSequence(_) |
+            Message::PrepareMove(_, _) => true,
+            Message::Request(_, ref message) => message.is_write(),
+            _ => false,
+        }
+    }
+}
+
+/// The consistency level of a read
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Consistency {
+    /// The read is replicated through the log, so the leader confirms it with a quorum
+    Linearizable,
+    /// The read is answered by the leader from its state machine
+    Leader,
+    /// The read is answered by any replica from its local state machine. It might miss
+    /// the latest writes.
+    Stale,
+}
+
+impl Default for Consistency {
+    fn default() -> Self {
+        Consistency::Leader
     }
+}
 
-    /// Creates a template client
-    fn new_client(addr: &SocketAddr, username: &str, password: &str, lid: LogId) -> Client {
-        Client::new::<SimpleAuth<SingleCredentials>>(Self::to_hashset(*addr),
-                                                     username.to_string(),
-                                                     password.to_string(),
-                                                     lid)
+impl FromStr for Consistency {
+    type Err = String;
+
+    fn from_str(s: &str) -> ::std::result::Result<Self, Self::Err> {
+        match s {
+            "linearizable" => Ok(Consistency::Linearizable),
+            "leader" => Ok(Consistency::Leader),
+            "stale" => Ok(Consistency::Stale),
+            _ => Err(format!("{} is not a valid consistency level", s)),
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, ClientError>;
+
+/// Functions which connect to the Cap'n Proto endpoint of a node for a single call. The
+/// requests are encoded with the generated bindings of `schema/paenko.capnp` by a
+/// `CapnpClient`. Use `PaenkoClient` to keep the connections and the leader across calls.
+pub struct Handler;
+
+impl Handler {
+    /// Connects a client for a single call
+    fn connect(addr: &SocketAddr,
+               username: &str,
+               plain_password: &str,
+               lid: LogId)
+               -> Result<CapnpClient> {
+        CapnpClient::connect(addr, username, plain_password, lid)
     }
 
     /// Gets a document
     /// 
     /// # Arguments
-    /// * `addr` - The `SocketAddr` of the node
+    /// * `addr` - The `capnp_addr` of the node
     /// * `username` - The `username` of the user
     /// * `plain_password` - The `password` of the user in plain text
     /// * `id` - The `DocumentId` which will be requested
     /// * `lid` - The `LogId` in which the document should be
+    /// * `consistency` - The `Consistency` of the read. The client has no replica of its own,
+    /// therefore `Stale` reads are answered by the leader like `Leader` reads
     pub fn get(addr: &SocketAddr,
                username: &str,
                plain_password: &str,
                id: Uuid,
-               lid: LogId)
+               lid: LogId,
+               consistency: Consistency)
                -> Result<Document> {
+        try!(Self::connect(addr, username, plain_password, lid)).get(id, consistency)
+    }
 
-        let mut client = Self::new_client(addr, username, plain_password, lid);
-
-        let payload = encode(&Message::Get(id), SizeLimit::Infinite).unwrap();
-
-        let response = match client.query(payload.as_slice()) {
-            Ok(res) => res,
-            Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
-                return Handler::get(&parse_addr(&leader_str),
-                                    &username,
-                                    &plain_password,
-                                    id,
-                                    lid);
-            } 
-            Err(err) => return Err(err),
-        };
-
-        let document: Document = decode(response.as_slice()).unwrap();
-
-        Ok(document)
+    /// Gets many documents in one round trip
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `ids` - The `DocumentId`s which will be requested
+    /// * `lid` - The `LogId` in which the documents should be
+    /// * `consistency` - The `Consistency` of the read
+    pub fn get_many(addr: &SocketAddr,
+                    username: &str,
+                    plain_password: &str,
+                    ids: Vec<DocumentId>,
+                    lid: LogId,
+                    consistency: Consistency)
+                    -> Result<MultiGet> {
+        try!(Self::connect(addr, username, plain_password, lid)).get_many(ids, consistency)
     }
 
-    /// Inserts a new document
+    /// Inserts a new document. Fails if a document with the same id already exists
     /// 
     /// # Arguments
-    /// * `addr` - The `SocketAddr` of the node
+    /// * `addr` - The `capnp_addr` of the node
     /// * `username` - The `username` of the user
     /// * `plain_password` - The `password` of the user in plain text
     /// * `document` - The document which will be inserted 
     /// * `session` - The `TransactionId` of the current transaction. If no transaction is
     /// currently running, this might be random because it will be ignored
     /// * `lid` - The `LogId` in which the document should be
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
     pub fn post(addr: &SocketAddr,
                 username: &str,
                 plain_password: &str,
                 document: Document,
                 session: TransactionId,
-                lid: LogId)
+                lid: LogId,
+                request: Option<RequestId>)
                 -> Result<Uuid> {
+        try!(Self::connect(addr, username, plain_password, lid)).post(document, session, request)
+    }
 
-        let mut client = Self::new_client(addr, username, plain_password, lid);
-
-        let payload = encode(&Message::Post(document.clone()), SizeLimit::Infinite).unwrap();
-
-        let response = match client.propose(session, payload.as_slice()) {
-            Ok(res) => res,
-            Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
-                return Handler::post(&parse_addr(&leader_str),
-                                     &username,
-                                     &plain_password,
-                                     document,
-                                     session,
-                                     lid);
-            } 
-            Err(err) => return Err(err),
-        };
-
-        let uid: Uuid = decode(response.as_slice()).unwrap();
-
-        Ok(uid)
+    /// Inserts a document or replaces the payload of the document if the id already exists
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `document` - The document which will be inserted or updated
+    /// * `session` - The `TransactionId` of the current transaction. If no transaction is
+    /// currently running, this might be random because it will be ignored
+    /// * `lid` - The `LogId` in which the document should be
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
+    pub fn upsert(addr: &SocketAddr,
+                  username: &str,
+                  plain_password: &str,
+                  document: Document,
+                  session: TransactionId,
+                  lid: LogId,
+                  request: Option<RequestId>)
+                  -> Result<Uuid> {
+        try!(Self::connect(addr, username, plain_password, lid)).upsert(document, session, request)
     }
 
-    /// Removes a document
+    /// Removes a document. If the log has soft delete enabled, the document is kept as
+    /// tombstone and can be restored with `undelete`
     /// 
     /// # Arguments
-    /// * `addr` - The `SocketAddr` of the node
+    /// * `addr` - The `capnp_addr` of the node
     /// * `username` - The `username` of the user
     /// * `plain_password` - The `password` of the user in plain text
     /// * `id` - The `DocumentId` which will be requested
     /// * `session` - The `TransactionId` of the current transaction. If no transaction is
     /// currently running, this might be random because it will be ignored
     /// * `lid` - The `LogId` in which the document should be
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
     pub fn remove(addr: &SocketAddr,
                   username: &str,
                   plain_password: &str,
                   id: Uuid,
                   session: TransactionId,
-                  lid: LogId)
+                  lid: LogId,
+                  request: Option<RequestId>)
                   -> Result<()> {
-        let mut client = Self::new_client(addr, username, plain_password, lid);
+        try!(Self::connect(addr, username, plain_password, lid)).remove(id, session, request)
+    }
 
-        let payload = encode(&Message::Remove(id), SizeLimit::Infinite).unwrap();
+    /// Restores a removed document of a log with soft delete
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the removed document
+    /// * `session` - The `TransactionId` of the current transaction. If no transaction is
+    /// currently running, this might be random because it will be ignored
+    /// * `lid` - The `LogId` in which the document should be
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
+    pub fn undelete(addr: &SocketAddr,
+                    username: &str,
+                    plain_password: &str,
+                    id: Uuid,
+                    session: TransactionId,
+                    lid: LogId,
+                    request: Option<RequestId>)
+                    -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).undelete(id, session, request)
+    }
 
-        match client.propose(session, payload.as_slice()) {
-            Ok(res) => res,
-            Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
-                return Handler::remove(&parse_addr(&leader_str),
-                                       &username,
-                                       &plain_password,
-                                       id,
-                                       session,
-                                       lid);
-            } 
-            Err(err) => return Err(err),
-        };
+    /// Removes the tombstones which are older than the `retention` period. Returns the
+    /// ids of the purged documents
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `retention` - The retention period in seconds
+    /// * `lid` - The `LogId` of the tombstones
+    pub fn purge(addr: &SocketAddr,
+                 username: &str,
+                 plain_password: &str,
+                 retention: u64,
+                 lid: LogId)
+                 -> Result<Vec<Uuid>> {
+        try!(Self::connect(addr, username, plain_password, lid)).purge(retention)
+    }
 
-        Ok(())
+    /// Returns the removed documents of a log which were not purged yet
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` of the tombstones
+    pub fn get_tombstones(addr: &SocketAddr,
+                          username: &str,
+                          plain_password: &str,
+                          lid: LogId)
+                          -> Result<Vec<(Uuid, Deletion)>> {
+        try!(Self::connect(addr, username, plain_password, lid)).get_tombstones()
+    }
+
+    /// Copies a document to another log. Returns the id of the copy
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the document
+    /// * `source` - The `LogId` in which the document is
+    /// * `target` - The `LogId` in which the copy will be inserted
+    /// * `keep_id` - Inserts the copy with the same id. Otherwise a new id will be generated
+    /// * `keep_version` - Inserts the copy with the same version
+    /// * `request` - The `RequestId` of the insert. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
+    pub fn copy_document(addr: &SocketAddr,
+                         username: &str,
+                         plain_password: &str,
+                         id: Uuid,
+                         source: LogId,
+                         target: LogId,
+                         keep_id: bool,
+                         keep_version: bool,
+                         request: Option<RequestId>)
+                         -> Result<Uuid> {
+        try!(Self::connect(addr, username, plain_password, source))
+            .copy_document(id, target, keep_id, keep_version, request)
+    }
+
+    /// Moves a document to another log. Returns the id of the document in the target log.
+    ///
+    /// The move is done in two steps. First the document is locked in the source log and
+    /// the move is saved there. Then the document is inserted in the target log and removed
+    /// from the source log. If the move is interrupted, the document stays locked in the
+    /// source log and calling `move_document` again resumes the saved move, so the document
+    /// is neither lost nor inserted twice. `abort_move` unlocks the document instead.
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the document
+    /// * `source` - The `LogId` in which the document is
+    /// * `target` - The `LogId` to which the document will be moved
+    /// * `keep_id` - Keeps the id of the document. Otherwise a new id will be generated
+    /// * `keep_version` - Keeps the version of the document
+    pub fn move_document(addr: &SocketAddr,
+                         username: &str,
+                         plain_password: &str,
+                         id: Uuid,
+                         source: LogId,
+                         target: LogId,
+                         keep_id: bool,
+                         keep_version: bool)
+                         -> Result<Uuid> {
+        try!(Self::connect(addr, username, plain_password, source))
+            .move_document(id, target, keep_id, keep_version)
+    }
+
+    /// Unlocks a document whose move was interrupted. If the document was already inserted
+    /// in the target log, it stays there, so the document exists in both logs afterwards.
+    /// Aborting a document which is not being moved has no effect.
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the document
+    /// * `lid` - The `LogId` in which the document is
+    pub fn abort_move(addr: &SocketAddr,
+                      username: &str,
+                      plain_password: &str,
+                      id: Uuid,
+                      lid: LogId)
+                      -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).abort_move(id)
     }
 
     /// Updates a document
     /// 
     /// # Arguments
-    /// * `addr` - The `SocketAddr` of the node
+    /// * `addr` - The `capnp_addr` of the node
     /// * `username` - The `username` of the user
     /// * `plain_password` - The `password` of the user in plain text
     /// * `id` - The `DocumentId` which will be requested
@@ -167,40 +422,306 @@ impl Handler {
     /// * `session` - The `TransactionId` of the current transaction. If no transaction is
     /// currently running, this might be random because it will be ignored
     /// * `lid` - The `LogId` in which the document should be
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
     pub fn put(addr: &SocketAddr,
                username: &str,
                plain_password: &str,
                id: Uuid,
                new_payload: Vec<u8>,
                session: TransactionId,
-               lid: LogId)
+               lid: LogId,
+               request: Option<RequestId>)
                -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid))
+            .put(id, new_payload, session, request)
+    }
+
+    /// Changes a byte range of a document without sending the complete payload. Returns the
+    /// new length of the payload
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the document
+    /// * `delta` - The `Delta` which appends, overwrites or truncates the payload
+    /// * `session` - The `TransactionId` of the current transaction. If no transaction is
+    /// currently running, this might be random because it will be ignored
+    /// * `lid` - The `LogId` in which the document is
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
+    pub fn patch(addr: &SocketAddr,
+                 username: &str,
+                 plain_password: &str,
+                 id: Uuid,
+                 delta: Delta,
+                 session: TransactionId,
+                 lid: LogId,
+                 request: Option<RequestId>)
+                 -> Result<u64> {
+        try!(Self::connect(addr, username, plain_password, lid)).patch(id, delta, session, request)
+    }
+
+    /// Uploads a document from a stream in chunks of `CHUNK_SIZE`. Every chunk is replicated
+    /// as its own entry and the document is created or replaced when all chunks were
+    /// uploaded. Returns the length of the payload
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the document
+    /// * `reader` - The stream of the payload
+    /// * `session` - The `TransactionId` of the current transaction. If no transaction is
+    /// currently running, this might be random because it will be ignored
+    /// * `lid` - The `LogId` in which the document should be
+    pub fn upload<R: Read>(addr: &SocketAddr,
+                           username: &str,
+                           plain_password: &str,
+                           id: Uuid,
+                           reader: &mut R,
+                           session: TransactionId,
+                           lid: LogId)
+                           -> Result<u64> {
+        try!(Self::connect(addr, username, plain_password, lid)).upload(id, reader, session)
+    }
+
+    /// Downloads a document in chunks of `CHUNK_SIZE` into a stream. Returns the number
+    /// of written bytes. Fails with a conflict if the document is changed during the
+    /// download, the stream then contains an incomplete payload.
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the document
+    /// * `lid` - The `LogId` in which the document is
+    /// * `writer` - The stream to which the payload is written
+    pub fn download<W: Write>(addr: &SocketAddr,
+                              username: &str,
+                              plain_password: &str,
+                              id: Uuid,
+                              lid: LogId,
+                              writer: &mut W)
+                              -> Result<u64> {
+        try!(Self::connect(addr, username, plain_password, lid)).download(id, writer)
+    }
+
+    /// Starts a chunked upload of a document and returns its `UploadId`
+    pub fn begin_upload(addr: &SocketAddr,
+                        username: &str,
+                        plain_password: &str,
+                        id: Uuid,
+                        lid: LogId)
+                        -> Result<UploadId> {
+        try!(Self::connect(addr, username, plain_password, lid)).begin_upload(id)
+    }
+
+    /// Uploads the chunk with the `index` of a pending upload. Sending a chunk again
+    /// replaces it
+    pub fn upload_chunk(addr: &SocketAddr,
+                        username: &str,
+                        plain_password: &str,
+                        upload: UploadId,
+                        index: u64,
+                        chunk: Vec<u8>,
+                        lid: LogId)
+                        -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).upload_chunk(upload, index, chunk)
+    }
+
+    /// Creates or replaces the document with the chunks of the upload and returns the
+    /// length of the payload
+    pub fn commit_upload(addr: &SocketAddr,
+                         username: &str,
+                         plain_password: &str,
+                         upload: UploadId,
+                         session: TransactionId,
+                         lid: LogId)
+                         -> Result<u64> {
+        try!(Self::connect(addr, username, plain_password, lid)).commit_upload(upload, session)
+    }
+
+    /// Drops the chunks of a pending upload
+    pub fn abort_upload(addr: &SocketAddr,
+                        username: &str,
+                        plain_password: &str,
+                        upload: UploadId,
+                        lid: LogId)
+                        -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).abort_upload(upload)
+    }
+
+    /// Returns at most `length` bytes of the payload of a document from the `offset`
+    pub fn get_range(addr: &SocketAddr,
+                     username: &str,
+                     plain_password: &str,
+                     id: Uuid,
+                     offset: u64,
+                     length: u64,
+                     lid: LogId)
+                     -> Result<Vec<u8>> {
+        try!(Self::connect(addr, username, plain_password, lid)).get_range(id, offset, length)
+    }
+
+    /// Sets the JSON Schema which every payload of the log has to match. `None` removes
+    /// the schema
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `schema` - The JSON text of the schema
+    /// * `lid` - The `LogId` of the schema
+    pub fn set_schema(addr: &SocketAddr,
+                      username: &str,
+                      plain_password: &str,
+                      schema: Option<String>,
+                      lid: LogId)
+                      -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).set_schema(schema)
+    }
+
+    /// Returns the JSON Schema of a log
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` of the schema
+    pub fn get_schema(addr: &SocketAddr,
+                      username: &str,
+                      plain_password: &str,
+                      lid: LogId)
+                      -> Result<Option<String>> {
+        try!(Self::connect(addr, username, plain_password, lid)).get_schema()
+    }
+
+    /// Keeps removed documents of a log as tombstones, so they can be restored
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `enabled` - False removes documents permanently
+    /// * `lid` - The `LogId` of the log
+    pub fn set_soft_delete(addr: &SocketAddr,
+                           username: &str,
+                           plain_password: &str,
+                           enabled: bool,
+                           lid: LogId)
+                           -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).set_soft_delete(enabled)
+    }
 
-        let mut client = Self::new_client(addr, username, plain_password, lid);
+    /// Replaces the limits of a log
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `quota` - The new limits, `None` is unlimited
+    /// * `lid` - The `LogId` of the log
+    pub fn set_quota(addr: &SocketAddr,
+                     username: &str,
+                     plain_password: &str,
+                     quota: Quota,
+                     lid: LogId)
+                     -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).set_quota(quota)
+    }
 
-        let payload = encode(&Message::Put(id, new_payload.clone()), SizeLimit::Infinite).unwrap();
+    /// Returns the number of documents and bytes of a log together with its quota
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` of the log
+    pub fn get_usage(addr: &SocketAddr,
+                     username: &str,
+                     plain_password: &str,
+                     lid: LogId)
+                     -> Result<Usage> {
+        try!(Self::connect(addr, username, plain_password, lid)).get_usage()
+    }
 
-        match client.propose(session, payload.as_slice()) {
-            Ok(res) => res,
-            Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
-                return Handler::put(&parse_addr(&leader_str),
-                                    &username,
-                                    &plain_password,
-                                    id,
-                                    new_payload,
-                                    session,
-                                    lid);
-            } 
-            Err(err) => return Err(err),
-        };
+    /// Reports whether the volume of a node is below its free space watermark. The log is
+    /// read-only while any node is full
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` of the log
+    /// * `node` - The address of the node whose volume was checked
+    /// * `full` - True if the volume is below the watermark
+    pub fn set_storage_full(addr: &SocketAddr,
+                            username: &str,
+                            plain_password: &str,
+                            lid: LogId,
+                            node: String,
+                            full: bool)
+                            -> Result<()> {
+        try!(Self::connect(addr, username, plain_password, lid)).set_storage_full(node, full)
+    }
 
-        Ok(())
+    /// Adds `delta` atomically to a numeric document and returns the new value. Use a
+    /// negative `delta` to decrement
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `id` - The `DocumentId` of the counter
+    /// * `field` - The path of a numeric field, e.g. `stats.orders`, if the payload is a
+    /// JSON object. If `None`, the whole payload is the number
+    /// * `delta` - The value which will be added
+    /// * `session` - The `TransactionId` of the current transaction. If no transaction is
+    /// currently running, this might be random because it will be ignored
+    /// * `lid` - The `LogId` in which the document should be
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
+    pub fn increment(addr: &SocketAddr,
+                     username: &str,
+                     plain_password: &str,
+                     id: Uuid,
+                     field: Option<&str>,
+                     delta: i64,
+                     session: TransactionId,
+                     lid: LogId,
+                     request: Option<RequestId>)
+                     -> Result<i64> {
+        try!(Self::connect(addr, username, plain_password, lid))
+            .increment(id, field, delta, session, request)
+    }
+
+    /// Returns the next value of a sequence of the log. The values are unique and
+    /// increasing, even if the transaction which requested them is rolled back
+    /// 
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `name` - The name of the sequence
+    /// * `lid` - The `LogId` of the sequence
+    /// * `request` - The `RequestId` of the write. A retry with the same id is applied only
+    /// once. If `None`, a new id will be generated
+    pub fn next_sequence(addr: &SocketAddr,
+                         username: &str,
+                         plain_password: &str,
+                         name: &str,
+                         lid: LogId,
+                         request: Option<RequestId>)
+                         -> Result<u64> {
+        try!(Self::connect(addr, username, plain_password, lid)).next_sequence(name, request)
     }
 
     /// Begins a new transaction 
     /// 
     /// # Arguments
-    /// * `addr` - The `SocketAddr` of the node
+    /// * `addr` - The `capnp_addr` of the node
     /// * `username` - The `username` of the user
     /// * `plain_password` - The `password` of the user in plain text
     /// * `session` - The `TransactionId` of the current transaction. If no transaction is
@@ -212,25 +733,13 @@ impl Handler {
                              session: TransactionId,
                              lid: LogId)
                              -> Result<String> {
-        let mut client = Self::new_client(addr, username, password, lid);
-
-        match client.begin_transaction(session) {
-            Ok(res) => Ok(Uuid::from_bytes(res.as_slice()).unwrap().hyphenated().to_string()),
-            Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
-                return Handler::begin_transaction(&parse_addr(&leader_str),
-                                                  &username,
-                                                  &password,
-                                                  session,
-                                                  lid);
-            } 
-            Err(err) => return Err(err),
-        }
+        try!(Self::connect(addr, username, password, lid)).begin_transaction(session)
     }
-    
+
     /// Commits a transaction 
     /// 
     /// # Arguments
-    /// * `addr` - The `SocketAddr` of the node
+    /// * `addr` - The `capnp_addr` of the node
     /// * `username` - The `username` of the user
     /// * `plain_password` - The `password` of the user in plain text
     /// * `session` - The `TransactionId` of the current transaction. If no transaction is
@@ -241,26 +750,14 @@ impl Handler {
                               password: &str,
                               lid: LogId,
                               session: TransactionId)
-                              -> Result<String> {
-        let mut client = Self::new_client(addr, username, password, lid);
-
-        match client.end_transaction(session) {
-            Ok(res) => Ok(from_utf8(res.as_slice()).unwrap().to_string()),
-            Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
-                return Handler::commit_transaction(&parse_addr(&leader_str),
-                                                   &username,
-                                                   &password,
-                                                   lid,
-                                                   session);
-            } 
-            Err(err) => return Err(err),
-        }
+                              -> Result<()> {
+        try!(Self::connect(addr, username, password, lid)).commit_transaction(session)
     }
 
     /// Rollbacks a transaction 
     /// 
     /// # Arguments
-    /// * `addr` - The `SocketAddr` of the node
+    /// * `addr` - The `capnp_addr` of the node
     /// * `username` - The `username` of the user
     /// * `plain_password` - The `password` of the user in plain text
     /// * `session` - The `TransactionId` of the current transaction. If no transaction is
@@ -271,22 +768,60 @@ impl Handler {
                                 password: &str,
                                 lid: LogId,
                                 session: TransactionId)
-                                -> Result<String> {
-        let mut client = Self::new_client(addr, username, password, lid);
-
+                                -> Result<()> {
+        try!(Self::connect(addr, username, password, lid)).rollback_transaction(session)
+    }
 
+    /// Returns all open transactions of a log
+    ///
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` of the log
+    pub fn get_transactions(addr: &SocketAddr,
+                            username: &str,
+                            password: &str,
+                            lid: LogId)
+                            -> Result<Vec<TransactionInfo>> {
+        try!(Self::connect(addr, username, password, lid)).get_transactions()
+    }
 
-        match client.rollback_transaction(session) {
-            Ok(res) => Ok(from_utf8(res.as_slice()).unwrap().to_string()),
-            Err(RError::Raft(RaftError::ClusterViolation(ref leader_str))) => {
-                return Handler::rollback_transaction(&parse_addr(&leader_str),
-                                                     &username,
-                                                     &password,
-                                                     lid,
-                                                     session);
-            } 
-            Err(err) => return Err(err),
-        }
+    /// Creates a named savepoint in a transaction
+    ///
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` in which the transaction is running
+    /// * `session` - The `TransactionId` of the current transaction
+    /// * `name` - The name of the savepoint
+    pub fn create_savepoint(addr: &SocketAddr,
+                            username: &str,
+                            password: &str,
+                            lid: LogId,
+                            session: TransactionId,
+                            name: &str)
+                            -> Result<()> {
+        try!(Self::connect(addr, username, password, lid)).create_savepoint(session, name)
+    }
 
+    /// Undoes all changes of a transaction after the savepoint. The transaction stays open.
+    ///
+    /// # Arguments
+    /// * `addr` - The `capnp_addr` of the node
+    /// * `username` - The `username` of the user
+    /// * `plain_password` - The `password` of the user in plain text
+    /// * `lid` - The `LogId` in which the transaction is running
+    /// * `session` - The `TransactionId` of the current transaction
+    /// * `name` - The name of the savepoint
+    pub fn rollback_to_savepoint(addr: &SocketAddr,
+                                 username: &str,
+                                 password: &str,
+                                 lid: LogId,
+                                 session: TransactionId,
+                                 name: &str)
+                                 -> Result<()> {
+        try!(Self::connect(addr, username, password, lid)).rollback_to_savepoint(session, name)
     }
 }
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use raft::LogId;
+
+use client::PaenkoClient;
+use document::timestamp;
+use statemachine::DocumentStateMachine;
+
+/// Seconds between two heartbeats of a log
+pub const HEARTBEAT_INTERVAL: u64 = 5;
+
+/// Proposes a heartbeat to every log whose local replica did not apply one within the
+/// `HEARTBEAT_INTERVAL`. A heartbeat is committed after every write which was committed
+/// before it was sent, so its age bounds the staleness of the replicas which applied it.
+/// Every node sends heartbeats, but a node skips a log while the heartbeats of the other
+/// nodes keep its replica synced.
+pub fn run(addr: SocketAddr,
+           username: String,
+           password: String,
+           state_machines: HashMap<LogId, Arc<RwLock<DocumentStateMachine>>>) {
+    spawn(move || {
+        let mut client = match state_machines.keys().next() {
+            Some(lid) => PaenkoClient::new(vec![addr], &username, &password, *lid),
+            None => return,
+        };
+
+        loop {
+            for (lid, state_machine) in &state_machines {
+                let staleness = state_machine.read().unwrap().get_staleness(timestamp());
+
+                if staleness >= HEARTBEAT_INTERVAL {
+                    // A failed heartbeat only delays the next one
+                    client.set_lid(*lid);
+
+                    if let Err(err) = client.heartbeat() {
+                        println!("Cannot send the heartbeat of {}: {}", lid, err);
+                    }
+                }
+            }
+
+            sleep(Duration::from_secs(HEARTBEAT_INTERVAL));
+        }
+    });
+}
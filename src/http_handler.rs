@@ -4,6 +4,7 @@ use iron::status;
 use router::Router;
 use iron::prelude::*;
 use bodyparser;
+use params::{Params, Value};
 
 use iron_sessionstorage::traits::*;
 use iron_sessionstorage::SessionStorage;
@@ -13,7 +14,11 @@ use uuid::Uuid;
 use std::net::{SocketAddr,  SocketAddrV4};
 
 use document::*;
-use handler::Handler;
+use handler::{Consistency, RequestId};
+use client::PaenkoClient;
+use error::ClientError;
+use delta::Delta;
+use quota::Quota;
 use statemachine::DocumentStateMachine;
 
 use std::thread::spawn;
@@ -30,6 +35,10 @@ use raft::StateInformation;
 use login::Login;
 
 use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use iron::response::WriteBody;
+use std::str::from_utf8;
 use std::sync::{Arc, RwLock};
 
 use rustc_serialize::base64::{ToBase64, FromBase64, STANDARD};
@@ -41,6 +50,68 @@ use serde_json::to_string as to_json;
 struct http_Response {
     payload: String,
     version: usize,
+    /// The maximum staleness in seconds of a stale read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    staleness: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct http_Document {
+    id: String,
+    payload: String,
+    version: usize,
+}
+
+/// The response of a multi-get
+#[derive(Serialize)]
+struct http_MultiGet {
+    documents: Vec<http_Document>,
+    missing: Vec<String>,
+    /// The maximum staleness in seconds of a stale read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    staleness: Option<u64>,
+}
+
+impl http_MultiGet {
+    fn new(result: MultiGet, staleness: Option<u64>) -> Self {
+        http_MultiGet {
+            documents: result.documents
+                .into_iter()
+                .map(|document| {
+                    http_Document {
+                        id: document.id.to_string(),
+                        payload: document.payload.as_slice().to_base64(STANDARD),
+                        version: document.version,
+                    }
+                })
+                .collect(),
+            missing: result.missing.iter().map(|id| id.to_string()).collect(),
+            staleness: staleness,
+        }
+    }
+}
+
+/// Answers a stale read of a document from the local replica at the unix timestamp `now`
+fn stale_get(state_machine: &DocumentStateMachine,
+             id: DocumentId,
+             now: u64)
+             -> Option<http_Response> {
+    state_machine.get_document(id).map(|document| {
+        http_Response {
+            version: document.version,
+            payload: document.payload.as_slice().to_base64(STANDARD),
+            staleness: Some(state_machine.get_staleness(now)),
+        }
+    })
+}
+
+/// Answers a stale multi-get from the local replica at the unix timestamp `now`
+fn stale_get_many(state_machine: &DocumentStateMachine,
+                  ids: &[DocumentId],
+                  now: u64)
+                  -> http_MultiGet {
+    http_MultiGet::new(state_machine.get_many(ids),
+                       Some(state_machine.get_staleness(now)))
 }
 
 #[derive(Clone,Copy)]
@@ -48,6 +119,60 @@ struct Context {
     node_addr: SocketAddrV4,
 }
 
+impl Context {
+    /// Creates a client of the node of the HTTP API for a single request
+    fn client(&self, username: &str, password: &str, lid: LogId) -> PaenkoClient {
+        connect(&SocketAddr::V4(self.node_addr), username, password, lid)
+    }
+}
+
+/// Creates a client which sends its requests to `addr` only. The HTTP API runs on the node,
+/// so it uses the raft client of the node instead of the Cap'n Proto endpoint of `Handler`
+fn connect(addr: &SocketAddr, username: &str, password: &str, lid: LogId) -> PaenkoClient {
+    let mut client = PaenkoClient::new(vec![*addr], username, password, lid);
+    client.set_discovery(None);
+    client
+}
+
+/// Returns the `RequestId` of the `X-Request-Id` header. Clients which retry a write with
+/// the same id get the response of the first attempt
+fn get_request_id(req: &Request) -> Option<RequestId> {
+    req.headers
+        .get_raw("X-Request-Id")
+        .and_then(|values| values.first())
+        .and_then(|value| from_utf8(value).ok())
+        .and_then(|value| Uuid::parse_str(value.trim()).ok())
+}
+
+/// Creates the response for a failed request. Rejections of the state machine are
+/// returned with their reason, all other errors with the given `message`
+fn error_response(error: &ClientError, code: status::Status, message: &str) -> Response {
+    match *error {
+        ClientError::NotFound(ref err) => Response::with((status::NotFound, format!("{}", err))),
+        ClientError::Unauthorized => Response::with((status::Unauthorized, format!("{}", error))),
+        ClientError::Conflict(ref err) => Response::with((status::Conflict, format!("{}", err))),
+        ClientError::Rejected(ref err @ DocumentError::QuotaExceeded(_, _)) => {
+            Response::with((status::PayloadTooLarge, format!("{}", err)))
+        }
+        ClientError::Rejected(ref err @ DocumentError::ReadOnly) => {
+            Response::with((status::InsufficientStorage, format!("{}", err)))
+        }
+        ClientError::Rejected(DocumentError::InvalidDocument(_, ref violations)) => {
+            match to_json(violations) {
+                Ok(json) => Response::with((status::UnprocessableEntity, json)),
+                Err(_) => Response::with((status::UnprocessableEntity, format!("{}", error))),
+            }
+        }
+        ClientError::Rejected(ref err) => Response::with((status::BadRequest, format!("{}", err))),
+        ClientError::NoLeader(_) => {
+            Response::with((status::ServiceUnavailable, format!("{}", error)))
+        }
+        ClientError::Timeout => Response::with((status::GatewayTimeout, format!("{}", error))),
+        ClientError::Protocol(_) |
+        ClientError::Io(_) => Response::with((code, message.to_string())),
+    }
+}
+
 pub fn init(binding_addr: SocketAddr,
             node_addr: SocketAddrV4,
             states: HashMap<LogId,StateInformation>,
@@ -74,27 +199,88 @@ pub fn init(binding_addr: SocketAddr,
                 move |request: &mut Request| http_logout(request),
                 "logout");
 
-    router.get("/document/:lid/:id",
-               move |request: &mut Request| http_get(request, &context),
-               "get_document");
+    {
+        let state_machines = state_machines.clone();
+        router.get("/document/:lid/:id",
+                   move |request: &mut Request| {
+                       http_get(request, &context, state_machines.clone())
+                   },
+                   "get_document");
+    }
+    {
+        let state_machines = state_machines.clone();
+        router.post("/document/:lid/_mget",
+                    move |request: &mut Request| {
+                        http_get_many(request, &context, state_machines.clone())
+                    },
+                    "get_many_documents");
+    }
     router.post("/document/:lid",
                 move |request: &mut Request| http_post(request, &context),
                 "post_document");
+    router.post("/document/:lid/:key",
+                move |request: &mut Request| http_post_key(request, &context, false),
+                "post_key_document");
+    router.put("/document/:lid/:key",
+               move |request: &mut Request| http_post_key(request, &context, true),
+               "upsert_document");
     router.post("/document/:lid/transaction/:session",
                 move |request: &mut Request| http_trans_post(request, &context),
                 "post_trans_document");
     router.delete("/document/:lid/:id",
                   move |request: &mut Request| http_delete(request, &context),
                   "delete_document");
+    router.post("/document/:lid/:id/undelete",
+                move |request: &mut Request| http_undelete(request, &context),
+                "undelete_document");
+    router.post("/document/:lid/:id/copy/:target",
+                move |request: &mut Request| http_transfer(request, &context, false),
+                "copy_document");
+    router.post("/document/:lid/:id/move/:target",
+                move |request: &mut Request| http_transfer(request, &context, true),
+                "move_document");
+    router.delete("/document/:lid/:id/move",
+                  move |request: &mut Request| http_abort_move(request, &context),
+                  "abort_move");
+    router.post("/tombstones/:lid/purge/:retention",
+                move |request: &mut Request| http_purge(request, &context),
+                "purge_tombstones");
     router.delete("/document/:lid/:id/transaction/:session",
                   move |request: &mut Request| http_trans_delete(request, &context),
                   "delete_trans_document");
     router.put("/document/:lid/document/:id",
                move |request: &mut Request| http_put(request, &context),
                "put_document");
+    router.patch("/document/:lid/document/:id",
+                 move |request: &mut Request| http_patch(request, &context),
+                 "patch_document");
+    router.get("/document/:lid/:id/stream",
+               move |request: &mut Request| http_download(request, &context),
+               "download_document");
+    router.put("/document/:lid/:id/stream",
+               move |request: &mut Request| http_upload(request, &context),
+               "upload_document");
+    router.post("/upload/:lid/:id",
+                move |request: &mut Request| http_begin_upload(request, &context),
+                "begin_upload");
+    router.put("/upload/:lid/:upload/:index",
+               move |request: &mut Request| http_upload_chunk(request, &context),
+               "upload_chunk");
+    router.post("/upload/:lid/:upload/commit",
+                move |request: &mut Request| http_commit_upload(request, &context),
+                "commit_upload");
+    router.delete("/upload/:lid/:upload",
+                  move |request: &mut Request| http_abort_upload(request, &context),
+                  "abort_upload");
     router.put("/document/:lid/transaction/:session/document/:id",
                move |request: &mut Request| http_trans_put(request, &context),
                "put_trans_document");
+    router.post("/counter/:lid/:id",
+                move |request: &mut Request| http_increment(request, &context),
+                "increment_counter");
+    router.post("/sequence/:lid/:name",
+                move |request: &mut Request| http_next_sequence(request, &context),
+                "next_sequence");
     router.post("/transaction/begin/:lid",
                 move |request: &mut Request| http_begin_transaction(request, &context),
                 "begin_transaction");
@@ -107,7 +293,16 @@ pub fn init(binding_addr: SocketAddr,
                 move |request: &mut Request| http_rollback_transaction(request, &context),
                 "rollback_transaction");
 
+    router.post("/transaction/savepoint/:lid/:session/:name",
+                move |request: &mut Request| http_create_savepoint(request, &context),
+                "create_savepoint");
+
+    router.post("/transaction/rollback/:lid/:session/:name",
+                move |request: &mut Request| http_rollback_to_savepoint(request, &context),
+                "rollback_to_savepoint");
+
     {
+        let state_machines = state_machines.clone();
         router.get("/meta/log/:lid/documents",
                    move |request: &mut Request| {
                        http_get_documents(request, &context, state_machines.clone())
@@ -115,6 +310,58 @@ pub fn init(binding_addr: SocketAddr,
                    "get_document_keys");
 
     }
+    {
+        let state_machines = state_machines.clone();
+        router.get("/meta/:lid/transactions",
+                   move |request: &mut Request| {
+                       http_meta_transactions(request, &context, state_machines.clone())
+                   },
+                   "meta_transactions");
+    }
+    {
+        let state_machines = state_machines.clone();
+        router.get("/meta/:lid/schema",
+                   move |request: &mut Request| {
+                       http_meta_schema(request, &context, state_machines.clone())
+                   },
+                   "meta_schema");
+    }
+    router.put("/meta/:lid/schema",
+               move |request: &mut Request| http_set_schema(request, &context, true),
+               "set_schema");
+    router.delete("/meta/:lid/schema",
+                  move |request: &mut Request| http_set_schema(request, &context, false),
+                  "drop_schema");
+    router.put("/meta/:lid/softdelete/:enabled",
+               move |request: &mut Request| http_set_soft_delete(request, &context),
+               "set_soft_delete");
+    router.put("/meta/:lid/quota",
+               move |request: &mut Request| http_set_quota(request, &context),
+               "set_quota");
+    {
+        let state_machines = state_machines.clone();
+        router.get("/meta/:lid/usage",
+                   move |request: &mut Request| {
+                       http_meta_usage(request, &context, state_machines.clone())
+                   },
+                   "meta_usage");
+    }
+    {
+        let state_machines = state_machines.clone();
+        router.get("/meta/:lid/blobs",
+                   move |request: &mut Request| {
+                       http_meta_blobs(request, &context, state_machines.clone())
+                   },
+                   "meta_blobs");
+    }
+    {
+        let state_machines = state_machines.clone();
+        router.get("/meta/:lid/transactions/:session",
+                   move |request: &mut Request| {
+                       http_meta_transaction(request, &context, state_machines.clone())
+                   },
+                   "meta_transaction");
+    }
     {
         let states = states.clone();
         router.get("/meta/logs",
@@ -137,6 +384,14 @@ pub fn init(binding_addr: SocketAddr,
                    },
                    "meta_state_candidate");
     }
+    {
+        let state_machines = state_machines.clone();
+        router.get("/meta/:lid/state/storage",
+                   move |request: &mut Request| {
+                       http_meta_state_storage(request, &context, state_machines.clone())
+                   },
+                   "meta_state_storage");
+    }
     {
         router.get("/meta/:lid/state/follower",
                    move |request: &mut Request| {
@@ -167,7 +422,18 @@ pub fn init(binding_addr: SocketAddr,
             .read()
             .unwrap();
 
-        let documents = state_machine.get_documents();
+        let deleted = match req.get_ref::<Params>()
+            .ok()
+            .and_then(|params| params.find(&["deleted"])) {
+            Some(&Value::String(ref deleted)) => deleted == "true",
+            _ => false,
+        };
+
+        let documents = if deleted {
+            state_machine.get_tombstones().into_iter().map(|(id, _)| id).collect()
+        } else {
+            state_machine.get_documents()
+        };
 
         Ok(Response::with((status::Ok,
                            format!("{:?}",
@@ -176,6 +442,212 @@ pub fn init(binding_addr: SocketAddr,
                                        .collect::<Vec<_>>()))))
     }
 
+    // TODO implement user & password
+    fn http_meta_schema(req: &mut Request,
+                        _: &Context,
+                        state_machines: Arc<HashMap<LogId, Arc<RwLock<DocumentStateMachine>>>>)
+                        -> IronResult<Response> {
+        let raw_lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "No lid found"));
+        let lid = itry!(LogId::from(raw_lid),
+                        (status::BadRequest, "LogId is invalid"));
+
+        let state_machine = iexpect!(state_machines.get(&lid),
+                                     (status::BadRequest, "No log found"))
+            .read()
+            .unwrap();
+
+        match state_machine.get_schema() {
+            Some(schema) => Ok(Response::with((status::Ok, schema))),
+            None => Ok(Response::with((status::NotFound, "The log has no schema"))),
+        }
+    }
+
+    /// Replaces the schema of the log with the JSON body or removes it
+    fn http_set_schema(req: &mut Request,
+                       context: &Context,
+                       replace: bool)
+                       -> IronResult<Response> {
+        let schema = if replace {
+            let mut source = String::new();
+            itry!(req.body.read_to_string(&mut source),
+                  (status::BadRequest, "Cannot read schema"));
+
+            Some(source)
+        } else {
+            None
+        };
+
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).set_schema(schema) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when setting the schema"))
+            }
+        }
+    }
+
+    /// Enables or disables the soft delete of the log
+    fn http_set_soft_delete(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let enabled = itry!(iexpect!(req.extensions.get::<Router>().unwrap().find("enabled"),
+                                     (status::BadRequest, "Cannot find enabled"))
+                                .parse::<bool>(),
+                            (status::BadRequest, "enabled has to be true or false"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).set_soft_delete(enabled) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when setting the soft delete"))
+            }
+        }
+    }
+
+    /// Replaces the quota of the log with the JSON body, e.g.
+    /// `{"max_documents":1000,"max_payload_size":null,"max_total_bytes":null}`
+    fn http_set_quota(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let mut body = String::new();
+        itry!(req.body.read_to_string(&mut body),
+              (status::BadRequest, "Cannot read quota"));
+
+        let quota: Quota = itry!(serde_json::from_str(&body),
+                                 (status::BadRequest, "The quota is invalid"));
+
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).set_quota(quota) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when setting the quota"))
+            }
+        }
+    }
+
+    // TODO implement user & password
+    fn http_meta_usage(req: &mut Request,
+                       _: &Context,
+                       state_machines: Arc<HashMap<LogId, Arc<RwLock<DocumentStateMachine>>>>)
+                       -> IronResult<Response> {
+        let raw_lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "No lid found"));
+        let lid = itry!(LogId::from(raw_lid),
+                        (status::BadRequest, "LogId is invalid"));
+
+        let state_machine = iexpect!(state_machines.get(&lid),
+                                     (status::BadRequest, "No log found"))
+            .read()
+            .unwrap();
+
+        let json = itry!(to_json(&state_machine.get_usage()),
+                         "Cannot encode usage to json");
+
+        Ok(Response::with((status::Ok, json)))
+    }
+
+    // TODO implement user & password
+    fn http_meta_blobs(req: &mut Request,
+                       _: &Context,
+                       state_machines: Arc<HashMap<LogId, Arc<RwLock<DocumentStateMachine>>>>)
+                       -> IronResult<Response> {
+        let raw_lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "No lid found"));
+        let lid = itry!(LogId::from(raw_lid),
+                        (status::BadRequest, "LogId is invalid"));
+
+        let state_machine = iexpect!(state_machines.get(&lid),
+                                     (status::BadRequest, "No log found"))
+            .read()
+            .unwrap();
+
+        let json = itry!(to_json(&state_machine.get_blob_stats()),
+                         "Cannot encode blob stats to json");
+
+        Ok(Response::with((status::Ok, json)))
+    }
+
+    // TODO implement user & password
+    fn http_meta_transactions(req: &mut Request,
+                              _: &Context,
+                              state_machines: Arc<HashMap<LogId,
+                                                          Arc<RwLock<DocumentStateMachine>>>>)
+                              -> IronResult<Response> {
+        let raw_lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "No lid found"));
+        let lid = itry!(LogId::from(raw_lid),
+                        (status::BadRequest, "LogId is invalid"));
+
+        let state_machine = iexpect!(state_machines.get(&lid),
+                                     (status::BadRequest, "No log found"))
+            .read()
+            .unwrap();
+
+        let json = itry!(to_json(&state_machine.get_transactions()),
+                         "Cannot encode transactions to json");
+
+        Ok(Response::with((status::Ok, json)))
+    }
+
+    // TODO implement user & password
+    fn http_meta_transaction(req: &mut Request,
+                             _: &Context,
+                             state_machines: Arc<HashMap<LogId,
+                                                         Arc<RwLock<DocumentStateMachine>>>>)
+                             -> IronResult<Response> {
+        let raw_lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "No lid found"));
+        let lid = itry!(LogId::from(raw_lid),
+                        (status::BadRequest, "LogId is invalid"));
+
+        let raw_session = iexpect!(req.extensions.get::<Router>().unwrap().find("session"),
+                                   (status::BadRequest, "No session found"));
+        let session = itry!(TransactionId::from(raw_session),
+                            (status::BadRequest, "Session is invalid"));
+
+        let state_machine = iexpect!(state_machines.get(&lid),
+                                     (status::BadRequest, "No log found"))
+            .read()
+            .unwrap();
+
+        let transaction = iexpect!(state_machine.get_transaction(session),
+                                   (status::NotFound, "Transaction is not open"));
+
+        let json = itry!(to_json(&transaction), "Cannot encode transaction to json");
+
+        Ok(Response::with((status::Ok, json)))
+    }
+
     // TODO implement user & password
     fn http_logs(_: &mut Request,
                  _: &Context,
@@ -249,6 +721,26 @@ pub fn init(binding_addr: SocketAddr,
         Ok(Response::with((status::Ok, format!("{}", to_json(&*lock).unwrap()))))
     }
 
+    fn http_meta_state_storage(req: &mut Request,
+                               _: &Context,
+                               state_machines: Arc<HashMap<LogId,
+                                                           Arc<RwLock<DocumentStateMachine>>>>)
+                               -> IronResult<Response> {
+        let raw_lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+        let lid = itry!(LogId::from(raw_lid), (status::BadRequest, "Invalid logid"));
+
+        let state_machine = iexpect!(state_machines.get(&lid),
+                                     (status::BadRequest, "No log found"))
+            .read()
+            .unwrap();
+
+        let json = itry!(to_json(&state_machine.get_storage_state()),
+                         "Cannot encode storage state to json");
+
+        Ok(Response::with((status::Ok, json)))
+    }
+
     fn http_meta_peers(_: &mut Request,
                        _: &Context,
                        peers: Arc<RwLock<HashMap<ServerId, SocketAddr>>>)
@@ -314,12 +806,27 @@ pub fn init(binding_addr: SocketAddr,
     }
 
     // TODO implement user & password
-    fn http_get(req: &mut Request, context: &Context) -> IronResult<Response> {
+    fn http_get(req: &mut Request,
+                context: &Context,
+                state_machines: Arc<HashMap<LogId, Arc<RwLock<DocumentStateMachine>>>>)
+                -> IronResult<Response> {
         let session = iexpect!(try!(req.session().get::<Login>()));
 
         let ref username = session.username;
         let ref password = session.hashed_password;
 
+        let consistency: Consistency = match req.get_ref::<Params>()
+            .ok()
+            .and_then(|params| params.find(&["consistency"])) {
+            Some(&Value::String(ref level)) => {
+                match level.parse() {
+                    Ok(consistency) => consistency,
+                    Err(err) => return Ok(Response::with((status::BadRequest, err))),
+                }
+            }
+            _ => Consistency::default(),
+        };
+
         let ref id = iexpect!(req.extensions
             .get::<Router>()
             .unwrap()
@@ -328,15 +835,29 @@ pub fn init(binding_addr: SocketAddr,
         let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
                                (status::BadRequest, "Cannot find logid"));
 
-        match Handler::get(&SocketAddr::V4(context.node_addr),
-                           &username,
-                           &password,
-                           Uuid::parse_str(*id).unwrap(),
-                           LogId::from(*lid).unwrap()) {
+        let id = document_id_from_key(*id);
+        let lid = itry!(LogId::from(*lid), (status::BadRequest, "LogId is invalid"));
+
+        if consistency == Consistency::Stale {
+            let state_machine = iexpect!(state_machines.get(&lid),
+                                         (status::BadRequest, "No log found"))
+                .read()
+                .unwrap();
+
+            let http_doc = iexpect!(stale_get(&state_machine, id, timestamp()),
+                                    (status::NotFound, "Cannot find document"));
+
+            let encoded = itry!(to_json(&http_doc), "Cannot encode document to json");
+
+            return Ok(Response::with((status::Ok, encoded)));
+        }
+
+        match context.client(&username, &password, lid).get(id, consistency) {
             Ok(document) => {
                 let http_doc = http_Response {
                     version: document.version,
                     payload: document.payload.as_slice().to_base64(STANDARD),
+                    staleness: None,
                 };
 
                 let encoded = itry!(to_json(&http_doc), "Cannot encode document to json");
@@ -344,7 +865,86 @@ pub fn init(binding_addr: SocketAddr,
                 Ok(Response::with((status::Ok, encoded)))
             }
             Err(ref error) => {
-                Ok(Response::with((status::InternalServerError, format!("{}", error))))
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when getting document"))
+            }
+        }
+    }
+
+    /// Returns the documents whose ids are in the JSON array `ids` of the body
+    fn http_get_many(req: &mut Request,
+                     context: &Context,
+                     state_machines: Arc<HashMap<LogId, Arc<RwLock<DocumentStateMachine>>>>)
+                     -> IronResult<Response> {
+        let ids = {
+            let ref body = iexpect!(itry!(req.get::<bodyparser::Json>(),
+                                          (status::BadRequest, "Body is not json")),
+                                    (status::BadRequest, "No body was defined"));
+
+            let values = match body.find("ids") {
+                Some(&serde_json::Value::Array(ref values)) => values,
+                _ => return Ok(Response::with((status::BadRequest, "No ids were in the body"))),
+            };
+
+            let mut ids = Vec::with_capacity(values.len());
+            for value in values {
+                match *value {
+                    serde_json::Value::String(ref id) => {
+                        ids.push(document_id_from_key(id))
+                    }
+                    _ => return Ok(Response::with((status::BadRequest, "Id is not a string"))),
+                }
+            }
+
+            ids
+        };
+
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let consistency: Consistency = match req.get_ref::<Params>()
+            .ok()
+            .and_then(|params| params.find(&["consistency"])) {
+            Some(&Value::String(ref level)) => {
+                match level.parse() {
+                    Ok(consistency) => consistency,
+                    Err(err) => return Ok(Response::with((status::BadRequest, err))),
+                }
+            }
+            _ => Consistency::default(),
+        };
+
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+        let lid = itry!(LogId::from(*lid), (status::BadRequest, "LogId is invalid"));
+
+        if consistency == Consistency::Stale {
+            let state_machine = iexpect!(state_machines.get(&lid),
+                                         (status::BadRequest, "No log found"))
+                .read()
+                .unwrap();
+
+            let result = stale_get_many(&state_machine, &ids, timestamp());
+            let encoded = itry!(to_json(&result), "Cannot encode documents to json");
+
+            return Ok(Response::with((status::Ok, encoded)));
+        }
+
+        match context.client(&username, &password, lid).get_many(ids, consistency) {
+            Ok(result) => {
+                let encoded = itry!(to_json(&http_MultiGet::new(result, None)),
+                                    "Cannot encode documents to json");
+
+                Ok(Response::with((status::Ok, encoded)))
+            }
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when getting the documents"))
             }
         }
     }
@@ -382,44 +982,426 @@ pub fn init(binding_addr: SocketAddr,
 
         let session = TransactionId::new();
 
-        match Handler::post(&SocketAddr::V4(context.node_addr),
-                            &username,
-                            &password,
-                            document,
-                            session,
-                            LogId::from(lid).unwrap()) {
+        let lid = LogId::from(lid).unwrap();
+        match context.client(&username, &password, lid)
+                  .post(document, session, get_request_id(req)) {
             Ok(id) => Ok(Response::with((status::Ok, format!("{}", id)))),
-            Err(_) => {
-                Ok(Response::with((status::BadRequest,
-                                   "An error occured when posting new document")))
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::BadRequest,
+                                  "An error occured when posting new document"))
             }
         }
     }
 
-    fn http_trans_post(req: &mut Request, context: &Context) -> IronResult<Response> {
+    /// Inserts a document with the key of the client. If `upsert` is true, an existing
+    /// document will be updated instead of rejecting the request
+    fn http_post_key(req: &mut Request, context: &Context, upsert: bool) -> IronResult<Response> {
         let payload = {
-            let ref body = req.get::<bodyparser::Json>().unwrap().unwrap();
+            let ref body = iexpect!(itry!(req.get::<bodyparser::Json>(),
+                                          (status::BadRequest, "Body is not json")),
+                                    (status::BadRequest, "No body was defined"));
 
-            let p = iexpect!(body.find("payload"));
+            let p = iexpect!(body.find("payload"),
+                             (status::BadRequest, "No payload was in the body defined"));
 
             let str_payload = match *p {
                 serde_json::Value::String(ref load) => load,
-                _ => panic!("Unexpected payload type"),
+                _ => return Ok(Response::with((status::BadRequest, "Unexpected payload type"))),
             };
 
-            str_payload.from_base64().expect("Payload is not base64")
+            itry!(str_payload.from_base64(),
+                  (status::BadRequest, "Payload is not base64"))
         };
 
-        let ref session = iexpect!(try!(req.session().get::<Login>()));
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                                   (status::BadRequest, "No session! Please login"));
 
         let ref username = session.username;
         let ref password = session.hashed_password;
 
-        // TODO do not panic
-        let session: TransactionId = req.extensions
-            .get::<Router>()
-            .unwrap()
-            .find("session")
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+        let ref key = iexpect!(req.extensions.get::<Router>().unwrap().find("key"),
+                               (status::BadRequest, "Cannot find key"));
+
+        let document = Document {
+            id: document_id_from_key(key),
+            payload: payload,
+            version: 1,
+        };
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        let addr = SocketAddr::V4(context.node_addr);
+
+        let result = if upsert {
+            connect(&addr, &username, &password, lid)
+                .upsert(document, TransactionId::new(), get_request_id(req))
+        } else {
+            connect(&addr, &username, &password, lid)
+                .post(document, TransactionId::new(), get_request_id(req))
+        };
+
+        match result {
+            Ok(id) => Ok(Response::with((status::Ok, format!("{}", id)))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when posting new document"))
+            }
+        }
+    }
+
+    /// Streams the payload of a document in chunks, so it is never buffered completely
+    struct DocumentStream {
+        addr: SocketAddr,
+        username: String,
+        password: String,
+        id: Uuid,
+        lid: LogId,
+    }
+
+    impl WriteBody for DocumentStream {
+        fn write_body(&mut self, res: &mut Write) -> io::Result<()> {
+            match connect(&self.addr, &self.username, &self.password, self.lid)
+                      .download(self.id, res) {
+                Ok(_) => Ok(()),
+                Err(ClientError::Io(err)) => Err(err),
+                Err(err) => Err(io::Error::new(io::ErrorKind::Other, format!("{}", err))),
+            }
+        }
+    }
+
+    fn http_download(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                              (status::BadRequest, "Cannot find id"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let stream = DocumentStream {
+            addr: SocketAddr::V4(context.node_addr),
+            username: session.username.clone(),
+            password: session.hashed_password.clone(),
+            id: document_id_from_key(id),
+            lid: itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid")),
+        };
+
+        // Checks that the document exists before the status is sent
+        let mut client = connect(&stream.addr, &stream.username, &stream.password, stream.lid);
+        if let Err(ref error) = client.get_range(stream.id, 0, 0) {
+            return Ok(error_response(error,
+                                     status::InternalServerError,
+                                     "An error occured when downloading document"));
+        }
+
+        let body: Box<WriteBody> = Box::new(stream);
+
+        Ok(Response::with((status::Ok, body)))
+    }
+
+    /// Uploads the raw body of the request in chunks, so it is never buffered completely
+    fn http_upload(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let id = {
+            let ref id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                                  (status::BadRequest, "Cannot find id"));
+            document_id_from_key(id)
+        };
+        let lid = {
+            let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                                   (status::BadRequest, "Cannot find logid"));
+            itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"))
+        };
+
+        match context.client(&username, &password, lid)
+                  .upload(id, &mut req.body, TransactionId::new()) {
+            Ok(length) => Ok(Response::with((status::Ok, format!("{}", length)))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when uploading document"))
+            }
+        }
+    }
+
+    fn http_begin_upload(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                              (status::BadRequest, "Cannot find id"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).begin_upload(document_id_from_key(id)) {
+            Ok(upload) => Ok(Response::with((status::Ok, upload.simple().to_string()))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when beginning the upload"))
+            }
+        }
+    }
+
+    /// Uploads the raw body of the request as the chunk with the `index`
+    fn http_upload_chunk(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let mut chunk = Vec::new();
+        itry!(req.body.read_to_end(&mut chunk),
+              (status::BadRequest, "Cannot read chunk"));
+
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref upload = iexpect!(req.extensions.get::<Router>().unwrap().find("upload"),
+                                  (status::BadRequest, "Cannot find upload"));
+        let ref index = iexpect!(req.extensions.get::<Router>().unwrap().find("index"),
+                                 (status::BadRequest, "Cannot find index"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let upload = itry!(Uuid::parse_str(upload),
+                           (status::BadRequest, "Upload id is invalid"));
+        let index = itry!(index.parse::<u64>(), (status::BadRequest, "Index is invalid"));
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).upload_chunk(upload, index, chunk) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when uploading the chunk"))
+            }
+        }
+    }
+
+    fn http_commit_upload(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref upload = iexpect!(req.extensions.get::<Router>().unwrap().find("upload"),
+                                  (status::BadRequest, "Cannot find upload"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let upload = itry!(Uuid::parse_str(upload),
+                           (status::BadRequest, "Upload id is invalid"));
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid)
+                  .commit_upload(upload, TransactionId::new()) {
+            Ok(length) => Ok(Response::with((status::Ok, format!("{}", length)))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when committing the upload"))
+            }
+        }
+    }
+
+    fn http_abort_upload(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref upload = iexpect!(req.extensions.get::<Router>().unwrap().find("upload"),
+                                  (status::BadRequest, "Cannot find upload"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let upload = itry!(Uuid::parse_str(upload),
+                           (status::BadRequest, "Upload id is invalid"));
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).abort_upload(upload) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when aborting the upload"))
+            }
+        }
+    }
+
+    /// Appends, overwrites or truncates the payload. The body is
+    /// `{"op": "append" | "write" | "truncate", "offset": n, "length": n, "payload": base64}`
+    fn http_patch(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let delta = {
+            let ref body = iexpect!(itry!(req.get::<bodyparser::Json>(),
+                                          (status::BadRequest, "Body is not json")),
+                                    (status::BadRequest, "No body was defined"));
+
+            let payload = match body.find("payload") {
+                Some(&serde_json::Value::String(ref payload)) => {
+                    itry!(payload.from_base64(),
+                          (status::BadRequest, "Payload is not base64"))
+                }
+                _ => Vec::new(),
+            };
+
+            let number = |name: &str| match body.find(name) {
+                Some(&serde_json::Value::U64(n)) => Some(n),
+                Some(&serde_json::Value::I64(n)) if n >= 0 => Some(n as u64),
+                _ => None,
+            };
+
+            match body.find("op") {
+                Some(&serde_json::Value::String(ref op)) if op == "append" => {
+                    Delta::Append(payload)
+                }
+                Some(&serde_json::Value::String(ref op)) if op == "write" => {
+                    Delta::Write(iexpect!(number("offset"),
+                                          (status::BadRequest, "Offset is missing")),
+                                 payload)
+                }
+                Some(&serde_json::Value::String(ref op)) if op == "truncate" => {
+                    Delta::Truncate(iexpect!(number("length"),
+                                             (status::BadRequest, "Length is missing")))
+                }
+                _ => return Ok(Response::with((status::BadRequest, "Unknown operation"))),
+            }
+        };
+
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                              (status::BadRequest, "Cannot find id"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid)
+                  .patch(document_id_from_key(id),
+                         delta,
+                         TransactionId::new(),
+                         get_request_id(req)) {
+            Ok(length) => Ok(Response::with((status::Ok, format!("{}", length)))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when patching document"))
+            }
+        }
+    }
+
+    /// Adds the `delta` of the body to a numeric document or to its numeric `field`
+    fn http_increment(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let (delta, field) = {
+            let ref body = iexpect!(itry!(req.get::<bodyparser::Json>(),
+                                          (status::BadRequest, "Body is not json")),
+                                    (status::BadRequest, "No body was defined"));
+
+            let delta = match body.find("delta") {
+                Some(&serde_json::Value::I64(delta)) => delta,
+                Some(&serde_json::Value::U64(delta)) if delta <= i64::max_value() as u64 => {
+                    delta as i64
+                }
+                None => 1,
+                _ => return Ok(Response::with((status::BadRequest, "Delta is not an integer"))),
+            };
+
+            let field = match body.find("field") {
+                Some(&serde_json::Value::String(ref field)) => Some(field.clone()),
+                _ => None,
+            };
+
+            (delta, field)
+        };
+
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                              (status::BadRequest, "Cannot find id"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid)
+                  .increment(document_id_from_key(id),
+                             field.as_ref().map(|f| f.as_str()),
+                             delta,
+                             TransactionId::new(),
+                             get_request_id(req)) {
+            Ok(value) => Ok(Response::with((status::Ok, format!("{}", value)))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when incrementing the counter"))
+            }
+        }
+    }
+
+    fn http_next_sequence(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref name = iexpect!(req.extensions.get::<Router>().unwrap().find("name"),
+                                (status::BadRequest, "Cannot find sequence name"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).next_sequence(name, get_request_id(req)) {
+            Ok(value) => Ok(Response::with((status::Ok, format!("{}", value)))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when fetching the next value"))
+            }
+        }
+    }
+
+    fn http_trans_post(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let payload = {
+            let ref body = req.get::<bodyparser::Json>().unwrap().unwrap();
+
+            let p = iexpect!(body.find("payload"));
+
+            let str_payload = match *p {
+                serde_json::Value::String(ref load) => load,
+                _ => panic!("Unexpected payload type"),
+            };
+
+            str_payload.from_base64().expect("Payload is not base64")
+        };
+
+        let ref session = iexpect!(try!(req.session().get::<Login>()));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        // TODO do not panic
+        let session: TransactionId = req.extensions
+            .get::<Router>()
+            .unwrap()
+            .find("session")
             .expect("Cannot find session")
             .parse()
             .expect("Failed to parse session id");
@@ -435,16 +1417,14 @@ pub fn init(binding_addr: SocketAddr,
             version: 1,
         };
 
-        match Handler::post(&SocketAddr::V4(context.node_addr),
-                            &username,
-                            &password,
-                            document,
-                            session,
-                            LogId::from(lid).unwrap()) {
+        let lid = LogId::from(lid).unwrap();
+        match context.client(&username, &password, lid)
+                  .post(document, session, get_request_id(req)) {
             Ok(id) => Ok(Response::with((status::Ok, format!("{}", id)))),
-            Err(_) => {
-                Ok(Response::with((status::InternalServerError,
-                                   "An error occured when posting new document")))
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when posting new document"))
             }
         }
 
@@ -466,22 +1446,156 @@ pub fn init(binding_addr: SocketAddr,
         let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"));
 
 
-        let res = match Handler::remove(&SocketAddr::V4(context.node_addr),
-                                        &username,
-                                        &password,
-                                        Uuid::parse_str(*doc_id).unwrap(),
-                                        session,
-                                        LogId::from(lid).unwrap()) {
+        let lid = LogId::from(lid).unwrap();
+        let res = match context.client(&username, &password, lid)
+                            .remove(document_id_from_key(*doc_id), session, get_request_id(req)) {
             Ok(()) => Response::with((status::Ok, "Ok")),
-            Err(_) => {
-                Response::with((status::InternalServerError,
-                                "An error occured when removing document"))
+            Err(ref error) => {
+                error_response(error,
+                               status::InternalServerError,
+                               "An error occured when removing document")
             }
         };
 
         Ok(res)
     }
 
+    fn http_undelete(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref doc_id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                                  (status::BadRequest, "Cannot find document id"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid)
+                  .undelete(document_id_from_key(*doc_id),
+                            TransactionId::new(),
+                            get_request_id(req)) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when restoring document"))
+            }
+        }
+    }
+
+    /// Unlocks a document whose move was interrupted
+    fn http_abort_move(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref doc_id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                                  (status::BadRequest, "Cannot find document id"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).abort_move(document_id_from_key(*doc_id)) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when aborting the move"))
+            }
+        }
+    }
+
+    /// Copies or moves a document to the log `target`. The query parameters `keep_id`
+    /// and `keep_version` keep the id and the version of the document
+    fn http_transfer(req: &mut Request,
+                     context: &Context,
+                     remove_source: bool)
+                     -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let (keep_id, keep_version) = match req.get_ref::<Params>() {
+            Ok(params) => {
+                let flag = |name: &str| match params.find(&[name]) {
+                    Some(&Value::String(ref value)) => value == "true",
+                    _ => false,
+                };
+
+                (flag("keep_id"), flag("keep_version"))
+            }
+            Err(_) => (false, false),
+        };
+
+        let ref doc_id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"),
+                                  (status::BadRequest, "Cannot find document id"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+        let ref target = iexpect!(req.extensions.get::<Router>().unwrap().find("target"),
+                                  (status::BadRequest, "Cannot find target logid"));
+
+        let addr = SocketAddr::V4(context.node_addr);
+        let id = document_id_from_key(*doc_id);
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        let target = itry!(LogId::from(target),
+                           (status::BadRequest, "Target LogId is invalid"));
+
+        let result = if remove_source {
+            connect(&addr, &username, &password, lid)
+                .move_document(id, target, keep_id, keep_version)
+        } else {
+            connect(&addr, &username, &password, lid)
+                .copy_document(id, target, keep_id, keep_version, get_request_id(req))
+        };
+
+        match result {
+            Ok(new_id) => Ok(Response::with((status::Ok, new_id.simple().to_string()))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when transferring document"))
+            }
+        }
+    }
+
+    fn http_purge(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()),
+                               (status::BadRequest, "No session! Please login"));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let ref retention = iexpect!(req.extensions.get::<Router>().unwrap().find("retention"),
+                                     (status::BadRequest, "Cannot find retention"));
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+
+        let retention = itry!(retention.parse::<u64>(),
+                              (status::BadRequest, "Retention is invalid"));
+        let lid = itry!(LogId::from(lid), (status::BadRequest, "LogId is invalid"));
+        match context.client(&username, &password, lid).purge(retention) {
+            Ok(purged) => {
+                Ok(Response::with((status::Ok,
+                                   format!("{:?}",
+                                           purged.iter()
+                                               .map(|d| d.simple().to_string())
+                                               .collect::<Vec<_>>()))))
+            }
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when purging tombstones"))
+            }
+        }
+    }
+
     fn http_trans_delete(req: &mut Request, context: &Context) -> IronResult<Response> {
         let ref session = iexpect!(try!(req.session().get::<Login>()));
 
@@ -505,16 +1619,14 @@ pub fn init(binding_addr: SocketAddr,
 
         let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"));
 
-        let res = match Handler::remove(&SocketAddr::V4(context.node_addr),
-                                        &username,
-                                        &password,
-                                        Uuid::parse_str(*doc_id).unwrap(),
-                                        *session,
-                                        LogId::from(lid).unwrap()) {
+        let lid = LogId::from(lid).unwrap();
+        let res = match context.client(&username, &password, lid)
+                            .remove(document_id_from_key(*doc_id), *session, get_request_id(req)) {
             Ok(()) => Response::with((status::Ok, "Ok")),
-            Err(_) => {
-                Response::with((status::InternalServerError,
-                                "An error occured when removing document"))
+            Err(ref error) => {
+                error_response(error,
+                               status::InternalServerError,
+                               "An error occured when removing document")
             }
         };
 
@@ -553,17 +1665,14 @@ pub fn init(binding_addr: SocketAddr,
                           (status::BadRequest, "Payload is not base64"));
 
 
-        let res = match Handler::put(&SocketAddr::V4(context.node_addr),
-                                     &username,
-                                     &password,
-                                     Uuid::parse_str(&id).unwrap(),
-                                     bytes,
-                                     session,
-                                     LogId::from(lid).unwrap()) {
+        let lid = LogId::from(lid).unwrap();
+        let res = match context.client(&username, &password, lid)
+                            .put(document_id_from_key(&id), bytes, session, get_request_id(req)) {
             Ok(()) => Response::with((status::Ok, "Ok")),
-            Err(_) => {
-                Response::with((status::InternalServerError,
-                                "An error occured when updating document"))
+            Err(ref error) => {
+                error_response(error,
+                               status::InternalServerError,
+                               "An error occured when updating document")
             }
         };
         Ok(res)
@@ -602,17 +1711,14 @@ pub fn init(binding_addr: SocketAddr,
         let ref id = iexpect!(req.extensions.get::<Router>().unwrap().find("id"));
         let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"));
 
-        let res = match Handler::put(&SocketAddr::V4(context.node_addr),
-                                     &username,
-                                     &password,
-                                     Uuid::parse_str(&id).unwrap(),
-                                     payload,
-                                     session,
-                                     LogId::from(lid).unwrap()) {
+        let lid = LogId::from(lid).unwrap();
+        let res = match context.client(&username, &password, lid)
+                            .put(document_id_from_key(&id), payload, session, get_request_id(req)) {
             Ok(()) => Response::with((status::Ok, "Ok")),
-            Err(_) => {
-                Response::with((status::InternalServerError,
-                                "An error occured when updating document"))
+            Err(ref error) => {
+                error_response(error,
+                               status::InternalServerError,
+                               "An error occured when updating document")
             }
         };
         Ok(res)
@@ -627,11 +1733,8 @@ pub fn init(binding_addr: SocketAddr,
 
         let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"));
 
-        match Handler::begin_transaction(&SocketAddr::V4(context.node_addr),
-                                         &username,
-                                         &password,
-                                         TransactionId::new(),
-                                         LogId::from(lid).unwrap()) {
+        let lid = LogId::from(lid).unwrap();
+        match context.client(&username, &password, lid).begin_transaction(TransactionId::new()) {
             Ok(session) => Ok(Response::with((status::Ok, session))),
             Err(_) => Ok(Response::with((status::InternalServerError, "Something went wrong :("))),
         }
@@ -655,13 +1758,12 @@ pub fn init(binding_addr: SocketAddr,
         let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
                                (status::BadRequest, "Cannot find logid"));
 
-        match Handler::commit_transaction(&SocketAddr::V4(context.node_addr),
-                                          &username,
-                                          &password,
-                                          LogId::from(lid).unwrap(),
-                                          *session) {
+        let lid = LogId::from(lid).unwrap();
+        match context.client(&username, &password, lid).commit_transaction(*session) {
             Ok(res) => Ok(Response::with((status::Ok, res))),
-            Err(_) => Ok(Response::with((status::InternalServerError, "Something went wrong :("))),
+            Err(ref error) => {
+                Ok(error_response(error, status::InternalServerError, "Something went wrong :("))
+            }
         }
     }
 
@@ -683,13 +1785,146 @@ pub fn init(binding_addr: SocketAddr,
         let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
                                (status::BadRequest, "Cannot find logid"));
 
-        match Handler::rollback_transaction(&SocketAddr::V4(context.node_addr),
-                                            &username,
-                                            &password,
-                                            LogId::from(lid).unwrap(),
-                                            *session) {
+        let lid = LogId::from(lid).unwrap();
+        match context.client(&username, &password, lid).rollback_transaction(*session) {
             Ok(res) => Ok(Response::with((status::Ok, res))),
-            Err(_) => Ok(Response::with((status::InternalServerError, "Something went wrong :("))),
+            Err(ref error) => {
+                Ok(error_response(error, status::InternalServerError, "Something went wrong :("))
+            }
+        }
+    }
+
+    fn http_create_savepoint(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let session: TransactionId = itry!(iexpect!(req.extensions
+                                                        .get::<Router>()
+                                                        .unwrap()
+                                                        .find("session"),
+                                                    (status::BadRequest, "Cannot find session"))
+                                               .parse(),
+                                           (status::BadRequest, "Failed to parse session id"));
+
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+        let ref name = iexpect!(req.extensions.get::<Router>().unwrap().find("name"),
+                                (status::BadRequest, "Cannot find savepoint name"));
+
+        let lid = LogId::from(lid).unwrap();
+        match context.client(&username, &password, lid).create_savepoint(session, name) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when creating the savepoint"))
+            }
+        }
+    }
+
+    fn http_rollback_to_savepoint(req: &mut Request, context: &Context) -> IronResult<Response> {
+        let session = iexpect!(try!(req.session().get::<Login>()));
+
+        let ref username = session.username;
+        let ref password = session.hashed_password;
+
+        let session: TransactionId = itry!(iexpect!(req.extensions
+                                                        .get::<Router>()
+                                                        .unwrap()
+                                                        .find("session"),
+                                                    (status::BadRequest, "Cannot find session"))
+                                               .parse(),
+                                           (status::BadRequest, "Failed to parse session id"));
+
+        let ref lid = iexpect!(req.extensions.get::<Router>().unwrap().find("lid"),
+                               (status::BadRequest, "Cannot find logid"));
+        let ref name = iexpect!(req.extensions.get::<Router>().unwrap().find("name"),
+                                (status::BadRequest, "Cannot find savepoint name"));
+
+        let lid = LogId::from(lid).unwrap();
+        match context.client(&username, &password, lid).rollback_to_savepoint(session, name) {
+            Ok(()) => Ok(Response::with((status::Ok, "Ok"))),
+            Err(ref error) => {
+                Ok(error_response(error,
+                                  status::InternalServerError,
+                                  "An error occured when rolling back to the savepoint"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::{stale_get, stale_get_many};
+    use document::{Document, document_id_from_key};
+    use handler::Message;
+    use raft::TransactionId;
+    use raft::state_machine::StateMachine;
+    use serde_json;
+    use serde_json::to_string as to_json;
+    use statemachine::DocumentStateMachine;
+    use uuid::Uuid;
+    use wire;
+    use self::tempdir::TempDir;
+
+    fn apply(state_machine: &mut DocumentStateMachine, message: &Message) {
+        state_machine.apply(&wire::encode_command(message).unwrap());
+    }
+
+    #[test]
+    fn test_stale_get() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let document = Document {
+                id: Uuid::new_v4(),
+                payload: b"test".to_vec(),
+                version: 1,
+            };
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), document.clone()));
+            apply(&mut state_machine, &Message::Heartbeat(100));
+
+            let response = stale_get(&state_machine, document.id, 130).unwrap();
+            assert_eq!(Some(30), response.staleness);
+
+            let json: serde_json::Value = serde_json::from_str(&to_json(&response).unwrap())
+                .unwrap();
+            assert_eq!(Some(30), json.find("staleness").and_then(|value| value.as_u64()));
+
+            assert!(stale_get(&state_machine, Uuid::new_v4(), 130).is_none());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_stale_get_many() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let document = Document {
+                id: document_id_from_key("first"),
+                payload: b"test".to_vec(),
+                version: 1,
+            };
+            let missing = document_id_from_key("second");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), document.clone()));
+            apply(&mut state_machine, &Message::Heartbeat(100));
+
+            let response = stale_get_many(&state_machine, &[document.id, missing], 105);
+            assert_eq!(Some(5), response.staleness);
+            assert_eq!(1, response.documents.len());
+            assert_eq!(vec![missing.to_string()], response.missing);
+
+            // the replica has applied no command since, so the bound grows with time
+            let response = stale_get_many(&state_machine, &[document.id], 160);
+            assert_eq!(Some(60), response.staleness);
+
+            dir.close().unwrap();
         }
     }
 }
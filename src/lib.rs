@@ -0,0 +1,73 @@
+#![feature(plugin)]
+#![feature(custom_derive)]
+#![feature(drop_types_in_const)]
+
+extern crate raft;
+
+extern crate log;
+
+#[macro_use]
+extern crate iron;
+extern crate router;
+extern crate params;
+extern crate bodyparser;
+extern crate iron_sessionstorage;
+
+extern crate bincode;
+extern crate rustc_serialize;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+extern crate uuid;
+extern crate toml;
+extern crate base64;
+extern crate crypto;
+extern crate fs2;
+extern crate rand;
+extern crate futures;
+extern crate capnp;
+extern crate capnp_nonblock;
+extern crate mio;
+
+#[macro_use]
+extern crate lazy_static;
+
+pub mod paenko_capnp {
+    include!(concat!(env!("OUT_DIR"), "/paenko_capnp.rs"));
+}
+
+pub mod document;
+pub mod http_handler;
+pub mod capnp_handler;
+pub mod handler;
+pub mod client;
+pub mod async_client;
+pub mod capnp_client;
+pub mod protocol;
+pub mod error;
+pub mod retry;
+pub mod cache;
+pub mod wire;
+pub mod config;
+pub mod doclog;
+pub mod transaction;
+pub mod delta;
+pub mod upload;
+pub mod blobs;
+pub mod schema;
+pub mod quota;
+pub mod storage;
+pub mod heartbeat;
+pub mod statemachine;
+mod requests;
+mod snapshot;
+mod counter;
+mod parser;
+mod login;
+
+pub use client::{PaenkoClient, Transaction};
+pub use cache::CacheConfig;
+pub use async_client::{AsyncClient, AsyncTransaction};
+pub use capnp_client::CapnpClient;
+pub use error::ClientError;
@@ -1,41 +1,10 @@
-#![feature(plugin)]
-#![feature(custom_derive)]
-#![feature(drop_types_in_const)]
-
+extern crate paenkodb;
 extern crate raft;
 
-extern crate log;
 extern crate env_logger;
-
-#[macro_use]
-extern crate iron;
-extern crate router;
-extern crate params;
-extern crate bodyparser;
-extern crate iron_sessionstorage;
-
 extern crate docopt;
-extern crate bincode;
 extern crate rustc_serialize;
-extern crate serde;
-extern crate serde_json;
-#[macro_use]
-extern crate serde_derive;
 extern crate uuid;
-extern crate toml;
-extern crate base64;
-
-#[macro_use]
-extern crate lazy_static;
-
-pub mod document;
-pub mod http_handler;
-pub mod handler;
-pub mod config;
-pub mod doclog;
-mod statemachine;
-mod parser;
-mod login;
 
 use std::net::SocketAddr;
 use docopt::Docopt;
@@ -53,16 +22,19 @@ use raft::LogId;
 use raft::TransactionId;
 use raft::state_machine::StateMachine;
 
-use statemachine::DocumentStateMachine;
-use document::*;
-use config::*;
-use handler::Handler;
-use doclog::DocLog;
+use paenkodb::statemachine::DocumentStateMachine;
+use paenkodb::document::*;
+use paenkodb::config::*;
+use paenkodb::handler::{Handler, Consistency, RequestId};
+use paenkodb::delta::Delta;
+use paenkodb::quota::Quota;
+use paenkodb::storage::WatchedLog;
+use paenkodb::doclog::DocLog;
 
 use raft::auth::sha256::Sha256Auth;
 use raft::auth::credentials::SingleCredentials;
 
-use http_handler::*;
+use paenkodb::http_handler::*;
 
 static USAGE: &'static str = "
 A replicated document database.
@@ -71,29 +43,93 @@ Commands:
 
     get     Return document
 
+    mget    Return many documents
+
     put     Set document
 
     server  Start server
 
 Usage:
-    document get <doc-id> <lid> <node-address> <username> <password>
-    document put <doc-id> <lid> <node-address> <filepath> <username> <password>
-    document post <lid> <node-address> <filepath> <username> <password> 
-    document remove <doc-id> <lid> <node-address> <username> <password>
+    document get <doc-id> <lid> <node-address> <username> <password> [--consistency=<level>]
+    document mget <lid> <node-address> <username> <password> <doc-ids>... [--consistency=<level>]
+    document put <doc-id> <lid> <node-address> <filepath> <username> <password> [--request=<id>]
+    document post <lid> <node-address> <filepath> <username> <password> [--request=<id>]
+    document postkey <lid> <node-address> <key> <filepath> <username> <password> [--request=<id>]
+    document upsert <lid> <node-address> <key> <filepath> <username> <password> [--request=<id>]
+    document append <doc-id> <lid> <node-address> <filepath> <username> <password> [--request=<id>]
+    document writeat <doc-id> <lid> <node-address> <offset> <filepath> <username> <password> [--request=<id>]
+    document truncate <doc-id> <lid> <node-address> <length> <username> <password> [--request=<id>]
+    document upload <doc-id> <lid> <node-address> <filepath> <username> <password>
+    document download <doc-id> <lid> <node-address> <filepath> <username> <password>
+    document remove <doc-id> <lid> <node-address> <username> <password> [--request=<id>]
+    document increment <doc-id> <lid> <node-address> <amount> <username> <password> [--field=<field>] [--request=<id>]
+    document decrement <doc-id> <lid> <node-address> <amount> <username> <password> [--field=<field>] [--request=<id>]
+    document nextval <lid> <node-address> <name> <username> <password> [--request=<id>]
+    document undelete <doc-id> <lid> <node-address> <username> <password> [--request=<id>]
+    document tombstones <lid> <node-address> <username> <password>
+    document purge <lid> <node-address> <retention> <username> <password>
+    document copy <doc-id> <lid> <target-lid> <node-address> <username> <password> [--keep-id] [--keep-version] [--request=<id>]
+    document move <doc-id> <lid> <target-lid> <node-address> <username> <password> [--keep-id] [--keep-version]
+    document abortmove <doc-id> <lid> <node-address> <username> <password>
+    document setschema <lid> <node-address> <filepath> <username> <password>
+    document dropschema <lid> <node-address> <username> <password>
+    document schema <lid> <node-address> <username> <password>
+    document softdelete <lid> <node-address> <username> <password> [--disable]
+    document setquota <lid> <node-address> <username> <password> [--max-documents=<n>] [--max-payload-size=<n>] [--max-total-bytes=<n>]
+    document usage <lid> <node-address> <username> <password>
     document server  <config-path>
     document begintrans <lid> <node-address> <username> <password>
     document commit <lid> <node-address> <username> <password> <transid>
     document rollback <lid> <node-address> <username> <password> <transid>
-    document transpost <lid> <node-address> <filepath> <username> <password> <transid>
-    document transremove <lid> <node-address> <doc-id> <username> <password> <transid>
-    document transput <lid> <node-address> <doc-id> <filepath> <username> <password> <transid>
+    document transpost <lid> <node-address> <filepath> <username> <password> <transid> [--request=<id>]
+    document transremove <lid> <node-address> <doc-id> <username> <password> <transid> [--request=<id>]
+    document transput <lid> <node-address> <doc-id> <filepath> <username> <password> <transid> [--request=<id>]
+    document savepoint <lid> <node-address> <username> <password> <transid> <name>
+    document rollbackto <lid> <node-address> <username> <password> <transid> <name>
+    document transactions <lid> <node-address> <username> <password>
+
+<node-address> is the Cap'n Proto endpoint (capnp_addr) of a node.
+
+Options:
+    --consistency=<level>  linearizable, leader or stale [default: leader]
+    --request=<id>         Request id of the write. Retries with the same id are applied once
+    --field=<field>        Path of the numeric field in a JSON document, e.g. stats.orders
+    --keep-id              Keep the id of the document in the target log
+    --keep-version         Keep the version of the document in the target log
+    --disable              Remove documents permanently
+    --max-documents=<n>    The maximum number of documents in the log, unlimited if omitted
+    --max-payload-size=<n>  The maximum size of a single payload in bytes, unlimited if omitted
+    --max-total-bytes=<n>  The maximum size of all payloads in bytes, unlimited if omitted
 ";
 
 #[derive(Debug,RustcDecodable,Clone)]
 struct Args {
     cmd_server: bool,
     cmd_get: bool,
+    cmd_mget: bool,
     cmd_post: bool,
+    cmd_postkey: bool,
+    cmd_upsert: bool,
+    cmd_increment: bool,
+    cmd_decrement: bool,
+    cmd_nextval: bool,
+    cmd_undelete: bool,
+    cmd_tombstones: bool,
+    cmd_purge: bool,
+    cmd_copy: bool,
+    cmd_setschema: bool,
+    cmd_dropschema: bool,
+    cmd_schema: bool,
+    cmd_softdelete: bool,
+    cmd_setquota: bool,
+    cmd_usage: bool,
+    cmd_append: bool,
+    cmd_upload: bool,
+    cmd_download: bool,
+    cmd_writeat: bool,
+    cmd_truncate: bool,
+    cmd_move: bool,
+    cmd_abortmove: bool,
     cmd_remove: bool,
     cmd_put: bool,
     cmd_begintrans: bool,
@@ -102,8 +138,13 @@ struct Args {
     cmd_transpost: bool,
     cmd_transremove: bool,
     cmd_transput: bool,
+    cmd_savepoint: bool,
+    cmd_rollbackto: bool,
+    cmd_transactions: bool,
     arg_id: Option<u64>,
     arg_doc_id: Option<String>,
+    arg_doc_ids: Vec<String>,
+    arg_key: Option<String>,
     arg_node_id: Vec<u64>,
     arg_node_address: Option<String>,
     arg_filepath: String,
@@ -113,12 +154,37 @@ struct Args {
     arg_username: Option<String>,
     arg_transid: Option<String>,
     arg_lid: Option<String>,
+    arg_target_lid: Option<String>,
+    arg_name: Option<String>,
+    arg_amount: Option<i64>,
+    arg_retention: Option<u64>,
+    arg_offset: Option<u64>,
+    arg_length: Option<u64>,
+    flag_consistency: Option<String>,
+    flag_request: Option<String>,
+    flag_field: Option<String>,
+    flag_keep_id: bool,
+    flag_keep_version: bool,
+    flag_disable: bool,
+    flag_max_documents: Option<u64>,
+    flag_max_payload_size: Option<u64>,
+    flag_max_total_bytes: Option<u64>,
 }
 
 impl Args {
-    pub fn get_doc_id(&self) -> Uuid {
-        let doc_id = self.arg_doc_id.clone().unwrap();
-        Uuid::parse_str(&doc_id).expect(&format!("{} is not a valid id", doc_id))
+    pub fn get_doc_id(&self) -> DocumentId {
+        document_id_from_key(&self.arg_doc_id.clone().unwrap())
+    }
+
+    pub fn get_doc_ids(&self) -> Vec<DocumentId> {
+        self.arg_doc_ids
+            .iter()
+            .map(|id| document_id_from_key(id))
+            .collect()
+    }
+
+    pub fn get_key(&self) -> DocumentId {
+        document_id_from_key(&self.arg_key.clone().unwrap())
     }
 
     pub fn get_node_addr(&self) -> SocketAddr {
@@ -129,6 +195,23 @@ impl Args {
         LogId::from(&self.arg_lid.clone().unwrap()).expect("Given LogId is not valid")
     }
 
+    pub fn get_target_lid(&self) -> LogId {
+        LogId::from(&self.arg_target_lid.clone().unwrap()).expect("Given target LogId is not valid")
+    }
+
+    pub fn get_consistency(&self) -> Consistency {
+        match self.flag_consistency {
+            Some(ref level) => level.parse().unwrap_or_else(|err: String| panic!(err)),
+            None => Consistency::default(),
+        }
+    }
+
+    pub fn get_request_id(&self) -> Option<RequestId> {
+        self.flag_request.as_ref().map(|request| {
+            Uuid::parse_str(request).expect(&format!("{} is not a valid request id", request))
+        })
+    }
+
     pub fn get_trans_id(&self) -> TransactionId{
         let tid = self.arg_transid.clone().unwrap();
         TransactionId::from(&tid).expect(&format!("{} is not a valid transaction id", tid))
@@ -154,20 +237,269 @@ fn main() {
         if args.cmd_get {
             let id = args.get_doc_id();
 
-            get(&node_addr, id, &username, &password, lid);
+            get(&node_addr, id, &username, &password, lid, args.get_consistency());
+
+        } else if args.cmd_mget {
+            let result = match Handler::get_many(&node_addr,
+                                                 &username,
+                                                 &password,
+                                                 args.get_doc_ids(),
+                                                 lid,
+                                                 args.get_consistency()) {
+                Ok(result) => result,
+                Err(err) => panic!(err),
+            };
+
+            for document in result.documents {
+                println!("{:?}", document);
+            }
 
+            for id in result.missing {
+                println!("{} is missing", id);
+            }
         } else if args.cmd_post {
 
             post(&node_addr,
+                 Uuid::new_v4(),
                  &args.arg_filepath,
                  &username,
                  &password,
                  TransactionId::new(),
-                 lid);
+                 lid,
+                 args.get_request_id());
+        } else if args.cmd_postkey {
+            post(&node_addr,
+                 args.get_key(),
+                 &args.arg_filepath,
+                 &username,
+                 &password,
+                 TransactionId::new(),
+                 lid,
+                 args.get_request_id());
+        } else if args.cmd_upsert {
+            upsert(&node_addr,
+                   args.get_key(),
+                   &args.arg_filepath,
+                   &username,
+                   &password,
+                   TransactionId::new(),
+                   lid,
+                   args.get_request_id());
+        } else if args.cmd_increment || args.cmd_decrement {
+            let id = args.get_doc_id();
+            let amount = args.arg_amount.unwrap();
+            let delta = if args.cmd_decrement { -amount } else { amount };
+
+            match Handler::increment(&node_addr,
+                                     &username,
+                                     &password,
+                                     id,
+                                     args.flag_field.as_ref().map(|f| f.as_str()),
+                                     delta,
+                                     TransactionId::new(),
+                                     lid,
+                                     args.get_request_id()) {
+                Ok(value) => println!("{}", value),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_nextval {
+            let name = args.arg_name.clone().unwrap();
+
+            match Handler::next_sequence(&node_addr,
+                                         &username,
+                                         &password,
+                                         &name,
+                                         lid,
+                                         args.get_request_id()) {
+                Ok(value) => println!("{}", value),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_undelete {
+            let id = args.get_doc_id();
+
+            match Handler::undelete(&node_addr,
+                                    &username,
+                                    &password,
+                                    id,
+                                    TransactionId::new(),
+                                    lid,
+                                    args.get_request_id()) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_tombstones {
+            let tombstones = match Handler::get_tombstones(&node_addr, &username, &password, lid) {
+                Ok(tombstones) => tombstones,
+                Err(err) => panic!(err),
+            };
+
+            for (id, deletion) in tombstones {
+                println!("{} author={} deleted_at={}",
+                         id,
+                         deletion.author,
+                         deletion.deleted_at);
+            }
+        } else if args.cmd_purge {
+            let retention = args.arg_retention.unwrap();
+
+            match Handler::purge(&node_addr, &username, &password, retention, lid) {
+                Ok(purged) => println!("Purged {} documents", purged.len()),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_upload {
+            let id = args.get_doc_id();
+            let mut file = File::open(&args.arg_filepath)
+                .expect(&format!("Unable to open file from path {}", args.arg_filepath));
+
+            match Handler::upload(&node_addr,
+                                  &username,
+                                  &password,
+                                  id,
+                                  &mut file,
+                                  TransactionId::new(),
+                                  lid) {
+                Ok(length) => println!("Uploaded {} bytes", length),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_download {
+            let id = args.get_doc_id();
+            let mut file = File::create(&args.arg_filepath)
+                .expect(&format!("Unable to create file at path {}", args.arg_filepath));
+
+            match Handler::download(&node_addr, &username, &password, id, lid, &mut file) {
+                Ok(length) => println!("Downloaded {} bytes", length),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_append || args.cmd_writeat || args.cmd_truncate {
+            let id = args.get_doc_id();
+
+            let delta = if args.cmd_truncate {
+                Delta::Truncate(args.arg_length.unwrap())
+            } else {
+                let mut handle = File::open(&args.arg_filepath)
+                    .expect(&format!("Unable to open file from path {}", args.arg_filepath));
+                let mut bytes = Vec::new();
+                handle.read_to_end(&mut bytes).expect("Unable to read file");
+
+                match args.arg_offset {
+                    Some(offset) if args.cmd_writeat => Delta::Write(offset, bytes),
+                    _ => Delta::Append(bytes),
+                }
+            };
+
+            match Handler::patch(&node_addr,
+                                 &username,
+                                 &password,
+                                 id,
+                                 delta,
+                                 TransactionId::new(),
+                                 lid,
+                                 args.get_request_id()) {
+                Ok(length) => println!("{}", length),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_setschema || args.cmd_dropschema {
+            let schema = if args.cmd_setschema {
+                let mut source = String::new();
+                File::open(&args.arg_filepath)
+                    .and_then(|mut file| file.read_to_string(&mut source))
+                    .expect(&format!("Unable to read the schema {}", args.arg_filepath));
+
+                Some(source)
+            } else {
+                None
+            };
+
+            match Handler::set_schema(&node_addr, &username, &password, schema, lid) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_schema {
+            match Handler::get_schema(&node_addr, &username, &password, lid) {
+                Ok(Some(schema)) => println!("{}", schema),
+                Ok(None) => println!("The log has no schema"),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_softdelete {
+            let enabled = !args.flag_disable;
+
+            match Handler::set_soft_delete(&node_addr, &username, &password, enabled, lid) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_setquota {
+            let quota = Quota {
+                max_documents: args.flag_max_documents,
+                max_payload_size: args.flag_max_payload_size,
+                max_total_bytes: args.flag_max_total_bytes,
+            };
+
+            match Handler::set_quota(&node_addr, &username, &password, quota, lid) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_usage {
+            let usage = match Handler::get_usage(&node_addr, &username, &password, lid) {
+                Ok(usage) => usage,
+                Err(err) => panic!(err),
+            };
+
+            let limit = |max: Option<u64>| match max {
+                Some(max) => max.to_string(),
+                None => "unlimited".to_string(),
+            };
+
+            println!("documents={}/{} bytes={}/{} staged_bytes={} max_payload_size={}",
+                     usage.documents,
+                     limit(usage.quota.max_documents),
+                     usage.total_bytes,
+                     limit(usage.quota.max_total_bytes),
+                     usage.staged_bytes,
+                     limit(usage.quota.max_payload_size));
+        } else if args.cmd_copy {
+            let id = args.get_doc_id();
+
+            match Handler::copy_document(&node_addr,
+                                         &username,
+                                         &password,
+                                         id,
+                                         lid,
+                                         args.get_target_lid(),
+                                         args.flag_keep_id,
+                                         args.flag_keep_version,
+                                         args.get_request_id()) {
+                Ok(new_id) => println!("{}", new_id),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_move {
+            let id = args.get_doc_id();
+
+            match Handler::move_document(&node_addr,
+                                         &username,
+                                         &password,
+                                         id,
+                                         lid,
+                                         args.get_target_lid(),
+                                         args.flag_keep_id,
+                                         args.flag_keep_version) {
+                Ok(new_id) => println!("{}", new_id),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_abortmove {
+            match Handler::abort_move(&node_addr, &username, &password, args.get_doc_id(), lid) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
         } else if args.cmd_remove {
             let id = args.get_doc_id();
 
-            remove(&node_addr, id, &username, &password, TransactionId::new(), lid);
+            remove(&node_addr,
+                   id,
+                   &username,
+                   &password,
+                   TransactionId::new(),
+                   lid,
+                   args.get_request_id());
         } else if args.cmd_put {
             let id = args.get_doc_id();
 
@@ -177,7 +509,8 @@ fn main() {
                 &username,
                 &password,
                 TransactionId::new(),
-                lid);
+                lid,
+                args.get_request_id());
         } else if args.cmd_begintrans {
             let res =
                 Handler::begin_transaction(&node_addr, &username, &password, TransactionId::new(), lid);
@@ -185,28 +518,33 @@ fn main() {
             println!("{}", res.unwrap());
         } else if args.cmd_commit{
             let tid = args.get_trans_id();
-            let res = Handler::commit_transaction(&node_addr, &username, &password, lid, tid);
-            println!("{}", res.unwrap());
+            match Handler::commit_transaction(&node_addr, &username, &password, lid, tid) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
         } else if args.cmd_rollback {
             let tid = args.get_trans_id();
-            let res = Handler::rollback_transaction(&node_addr, &username, &password, lid,tid);
-
-            println!("{}", res.unwrap());
+            match Handler::rollback_transaction(&node_addr, &username, &password, lid, tid) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
         } else if args.cmd_transpost {
             let tid = args.get_trans_id();
 
             post(&node_addr,
+                 Uuid::new_v4(),
                  &args.arg_filepath,
                  &username,
                  &password,
                  tid,
-                 lid);
+                 lid,
+                 args.get_request_id());
 
         } else if args.cmd_transremove {
             let id = args.get_doc_id();
             let tid = args.get_trans_id();
 
-            remove(&node_addr, id, &username, &password, tid, lid);
+            remove(&node_addr, id, &username, &password, tid, lid, args.get_request_id());
         } else if args.cmd_transput {
             let id = args.get_doc_id();
             let tid = args.get_trans_id();
@@ -217,7 +555,46 @@ fn main() {
                 &username,
                 &password,
                 tid,
-                lid);
+                lid,
+                args.get_request_id());
+        } else if args.cmd_savepoint {
+            let tid = args.get_trans_id();
+            let name = args.arg_name.clone().unwrap();
+
+            match Handler::create_savepoint(&node_addr, &username, &password, lid, tid, &name) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_rollbackto {
+            let tid = args.get_trans_id();
+            let name = args.arg_name.clone().unwrap();
+
+            match Handler::rollback_to_savepoint(&node_addr,
+                                                 &username,
+                                                 &password,
+                                                 lid,
+                                                 tid,
+                                                 &name) {
+                Ok(()) => println!("Ok"),
+                Err(err) => panic!(err),
+            }
+        } else if args.cmd_transactions {
+            let transactions = match Handler::get_transactions(&node_addr,
+                                                               &username,
+                                                               &password,
+                                                               lid) {
+                Ok(transactions) => transactions,
+                Err(err) => panic!(err),
+            };
+
+            for t in transactions {
+                println!("{} author={} age={}s operations={} documents={}",
+                         t.id,
+                         t.author,
+                         t.age,
+                         t.operations,
+                         t.documents.len());
+            }
         }
     }
 }
@@ -246,8 +623,10 @@ fn server(args: &Args) {
 
         let mut state_machine = DocumentStateMachine::new(&l.path);
         {
-            let snap_map = state_machine.get_snapshot_map().unwrap_or_default();
-            let snap_log = state_machine.get_snapshot_log().unwrap_or_default();
+            state_machine.set_min_free_space(l.min_free_space);
+
+            let (snap_map, snap_log) = state_machine.get_snapshot()
+                .expect(&format!("Unable to read the snapshot of {}", l.path));
 
             state_machine.restore_snapshot(snap_map, snap_log);
         }
@@ -283,7 +662,43 @@ fn server(args: &Args) {
         let state_machines = server.log_manager.get_state_machines();
         let peers = server.log_manager.get_peers();
 
+        for state_machine in state_machines.values() {
+            state_machine.write().unwrap().set_cluster(server_addr, peers.clone());
+        }
+
+        let watched = config.logs
+            .iter()
+            .filter_map(|l| {
+                l.min_free_space.map(|min_free_space| {
+                    WatchedLog {
+                        lid: LogId::from(&l.lid).unwrap(),
+                        path: l.path.clone(),
+                        min_free_space: min_free_space,
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if !watched.is_empty() {
+            paenkodb::storage::watch(server_addr.to_string(),
+                           server_addr,
+                           config.security.username.clone(),
+                           config.security.password.clone(),
+                           watched,
+                           state_machines.clone());
+        }
+
+        paenkodb::heartbeat::run(server_addr,
+                                 config.security.username.clone(),
+                                 config.security.password.clone(),
+                                 state_machines.clone());
+
         init(config.get_binding_addr(), node_addr, states, state_machines,peers, auth);
+
+        if let Some(capnp_addr) = config.get_capnp_addr() {
+            paenkodb::capnp_handler::init(capnp_addr, server_addr)
+                .expect(&format!("Unable to bind the Cap'n Proto endpoint {}", capnp_addr));
+        }
     }
 
     server.init(&mut event_loop);
@@ -291,17 +706,52 @@ fn server(args: &Args) {
     event_loop.run(&mut server).unwrap();
 }
 
-fn get(addr: &SocketAddr, doc_id: Uuid, username: &str, password: &str, lid: LogId) {
-    let document = Handler::get(addr, &username, &password, doc_id, lid);
+fn get(addr: &SocketAddr,
+       doc_id: Uuid,
+       username: &str,
+       password: &str,
+       lid: LogId,
+       consistency: Consistency) {
+    let document = Handler::get(addr, &username, &password, doc_id, lid, consistency);
     println!("{:?}", document);
 }
 
 fn post(addr: &SocketAddr,
+        doc_id: DocumentId,
         filepath: &str,
         username: &str,
         password: &str,
         session: TransactionId,
-        lid: LogId) {
+        lid: LogId,
+        request: Option<RequestId>) {
+
+    let mut handler = File::open(&filepath).expect(&format!("Unable to open the file{}", filepath));
+    let mut buffer: Vec<u8> = Vec::new();
+
+    handler.read_to_end(&mut buffer).expect(&format!("Unable read the file to end {}", filepath));
+
+    let document = Document {
+        id: doc_id,
+        payload: buffer,
+        version: 1,
+    };
+
+    let id = match Handler::post(addr, &username, &password, document, session, lid, request) {
+        Ok(id) => id,
+        Err(err) => panic!(err),
+    };
+
+    println!("{}", id);
+}
+
+fn upsert(addr: &SocketAddr,
+          doc_id: DocumentId,
+          filepath: &str,
+          username: &str,
+          password: &str,
+          session: TransactionId,
+          lid: LogId,
+          request: Option<RequestId>) {
 
     let mut handler = File::open(&filepath).expect(&format!("Unable to open the file{}", filepath));
     let mut buffer: Vec<u8> = Vec::new();
@@ -309,12 +759,12 @@ fn post(addr: &SocketAddr,
     handler.read_to_end(&mut buffer).expect(&format!("Unable read the file to end {}", filepath));
 
     let document = Document {
-        id: Uuid::new_v4(),
+        id: doc_id,
         payload: buffer,
         version: 1,
     };
 
-    let id = match Handler::post(addr, &username, &password, document, session, lid) {
+    let id = match Handler::upsert(addr, &username, &password, document, session, lid, request) {
         Ok(id) => id,
         Err(err) => panic!(err),
     };
@@ -328,14 +778,15 @@ fn put(addr: &SocketAddr,
        username: &str,
        password: &str,
        session: TransactionId,
-       lid: LogId) {
+       lid: LogId,
+       request: Option<RequestId>) {
 
     let mut handler = File::open(filepath).expect(&format!("Unable to open the file{}", filepath));
     let mut buffer: Vec<u8> = Vec::new();
 
     handler.read_to_end(&mut buffer).expect(&format!("Unable read the file to end {}", filepath));
 
-    match Handler::put(addr, &username, &password, doc_id, buffer, session, lid) {
+    match Handler::put(addr, &username, &password, doc_id, buffer, session, lid, request) {
         Ok(()) => {
 
         }
@@ -348,8 +799,9 @@ fn remove(addr: &SocketAddr,
           username: &str,
           password: &str,
           session: TransactionId,
-          lid: LogId) {
-    match Handler::remove(addr, &username, &password, doc_id, session, lid) {
+          lid: LogId,
+          request: Option<RequestId>) {
+    match Handler::remove(addr, &username, &password, doc_id, session, lid, request) {
         Ok(()) => println!("Ok"),
         Err(err) => panic!(err),
     }
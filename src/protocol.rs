@@ -0,0 +1,536 @@
+use std::io::{Error as IoError, ErrorKind};
+
+use capnp::text_list;
+use uuid::Uuid;
+use raft::LogId;
+use raft::TransactionId;
+
+use delta::Delta;
+use document::{Deletion, Document, DocumentError, DocumentId, MultiGet, document_id_from_key};
+use error::ClientError;
+use handler::{Consistency, Result};
+use quota::{Quota, QuotaLimit, Usage};
+use schema::SchemaViolation;
+use transaction::TransactionInfo;
+
+use paenko_capnp::{delta, document, error, quota, quota_limit, response, schema_violation,
+                   tombstone, transaction_info, usage};
+use paenko_capnp::Consistency as CapnpConsistency;
+
+/// Parses an id of the protocol
+pub fn parse_id(text: &str) -> Result<Uuid> {
+    Uuid::parse_str(text).map_err(|_| ClientError::Protocol(format!("Invalid id {}", text)))
+}
+
+/// Parses an optional id of the protocol. An empty text is no id
+pub fn parse_optional_id(text: &str) -> Result<Option<Uuid>> {
+    if text.is_empty() {
+        Ok(None)
+    } else {
+        parse_id(text).map(Some)
+    }
+}
+
+/// Parses the id of a log
+pub fn parse_lid(text: &str) -> Result<LogId> {
+    LogId::from(text).map_err(|_| ClientError::Protocol(format!("Invalid log {}", text)))
+}
+
+/// Parses the id of a transaction
+pub fn parse_session(text: &str) -> Result<TransactionId> {
+    TransactionId::from(text)
+        .map_err(|_| ClientError::Protocol(format!("Invalid transaction {}", text)))
+}
+
+pub fn unexpected_response() -> ClientError {
+    ClientError::Protocol("Unexpected response".to_string())
+}
+
+/// Returns the answer of a response or the error which was sent by the node
+pub fn get_response<'a>(reader: response::Reader<'a>) -> Result<response::WhichReader<'a>> {
+    match try!(reader.which()) {
+        response::Which::Error(err) => Err(try!(get_error(try!(err)))),
+        which => Ok(which),
+    }
+}
+
+pub fn to_consistency(consistency: CapnpConsistency) -> Consistency {
+    match consistency {
+        CapnpConsistency::Linearizable => Consistency::Linearizable,
+        CapnpConsistency::Leader => Consistency::Leader,
+        CapnpConsistency::Stale => Consistency::Stale,
+    }
+}
+
+pub fn from_consistency(consistency: Consistency) -> CapnpConsistency {
+    match consistency {
+        Consistency::Linearizable => CapnpConsistency::Linearizable,
+        Consistency::Leader => CapnpConsistency::Leader,
+        Consistency::Stale => CapnpConsistency::Stale,
+    }
+}
+
+pub fn set_document(mut builder: document::Builder, document: &Document) {
+    builder.set_id(&document.id.to_string());
+    builder.set_payload(&document.payload);
+    builder.set_version(document.version as u64);
+}
+
+pub fn get_document(reader: document::Reader) -> Result<Document> {
+    Ok(Document {
+        id: try!(parse_id(try!(reader.get_id()))),
+        payload: try!(reader.get_payload()).to_vec(),
+        version: reader.get_version() as usize,
+    })
+}
+
+/// Reads a document of a request. Its id is a key of the client like the keys of the HTTP
+/// API, so keys which are no uuid are mapped with `document_id_from_key`
+pub fn get_keyed_document(reader: document::Reader) -> Result<Document> {
+    Ok(Document {
+        id: document_id_from_key(try!(reader.get_id())),
+        payload: try!(reader.get_payload()).to_vec(),
+        version: reader.get_version() as usize,
+    })
+}
+
+pub fn set_ids(mut builder: text_list::Builder, ids: &[Uuid]) {
+    for (i, id) in ids.iter().enumerate() {
+        builder.set(i as u32, &id.to_string());
+    }
+}
+
+pub fn get_ids(reader: text_list::Reader) -> Result<Vec<Uuid>> {
+    let mut ids = Vec::with_capacity(reader.len() as usize);
+
+    for i in 0..reader.len() {
+        ids.push(try!(parse_id(try!(reader.get(i)))));
+    }
+
+    Ok(ids)
+}
+
+/// Maps the ids or keys of a request to the ids of the documents like `get_key`
+pub fn get_keys(reader: text_list::Reader) -> Result<Vec<DocumentId>> {
+    let mut ids = Vec::with_capacity(reader.len() as usize);
+
+    for i in 0..reader.len() {
+        ids.push(document_id_from_key(try!(reader.get(i))));
+    }
+
+    Ok(ids)
+}
+
+pub fn set_multi_get(mut builder: response::many::Builder, result: &MultiGet) {
+    {
+        let mut documents = builder.borrow().init_documents(result.documents.len() as u32);
+        for (i, document) in result.documents.iter().enumerate() {
+            set_document(documents.borrow().get(i as u32), document);
+        }
+    }
+
+    set_ids(builder.init_missing(result.missing.len() as u32), &result.missing);
+}
+
+pub fn get_multi_get(reader: response::many::Reader) -> Result<MultiGet> {
+    let list = try!(reader.get_documents());
+    let mut documents = Vec::with_capacity(list.len() as usize);
+
+    for document in list.iter() {
+        documents.push(try!(get_document(document)));
+    }
+
+    Ok(MultiGet {
+        documents: documents,
+        missing: try!(get_ids(try!(reader.get_missing()))),
+    })
+}
+
+pub fn set_delta(mut builder: delta::Builder, value: &Delta) {
+    match *value {
+        Delta::Append(ref bytes) => builder.set_append(bytes),
+        Delta::Write(offset, ref bytes) => {
+            let mut write = builder.init_write();
+            write.set_offset(offset);
+            write.set_bytes(bytes);
+        }
+        Delta::Truncate(length) => builder.set_truncate(length),
+    }
+}
+
+pub fn get_delta(reader: delta::Reader) -> Result<Delta> {
+    Ok(match try!(reader.which()) {
+        delta::Which::Append(bytes) => Delta::Append(try!(bytes).to_vec()),
+        delta::Which::Write(write) => {
+            Delta::Write(write.get_offset(), try!(write.get_bytes()).to_vec())
+        }
+        delta::Which::Truncate(length) => Delta::Truncate(length),
+    })
+}
+
+pub fn set_quota(mut builder: quota::Builder, value: &Quota) {
+    builder.set_max_documents(value.max_documents.unwrap_or(0));
+    builder.set_max_payload_size(value.max_payload_size.unwrap_or(0));
+    builder.set_max_total_bytes(value.max_total_bytes.unwrap_or(0));
+}
+
+/// Returns the limit of the protocol, which is 0 if it is not set
+fn limit(value: u64) -> Option<u64> {
+    if value == 0 {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+pub fn get_quota(reader: quota::Reader) -> Quota {
+    Quota {
+        max_documents: limit(reader.get_max_documents()),
+        max_payload_size: limit(reader.get_max_payload_size()),
+        max_total_bytes: limit(reader.get_max_total_bytes()),
+    }
+}
+
+pub fn set_tombstone(mut builder: tombstone::Builder, id: &Uuid, deletion: &Deletion) {
+    builder.set_id(&id.to_string());
+    builder.set_author(&deletion.author);
+    builder.set_deleted_at(deletion.deleted_at);
+}
+
+pub fn get_tombstone(reader: tombstone::Reader) -> Result<(Uuid, Deletion)> {
+    let deletion = Deletion {
+        author: try!(reader.get_author()).to_string(),
+        deleted_at: reader.get_deleted_at(),
+    };
+
+    Ok((try!(parse_id(try!(reader.get_id()))), deletion))
+}
+
+pub fn set_transaction_info(mut builder: transaction_info::Builder, info: &TransactionInfo) {
+    builder.set_id(&info.id.to_string());
+    builder.set_author(&info.author);
+    builder.set_started(info.started);
+    builder.set_age(info.age);
+    builder.set_operations(info.operations as u64);
+
+    let mut documents = builder.init_documents(info.documents.len() as u32);
+    for (i, id) in info.documents.iter().enumerate() {
+        documents.set(i as u32, &id.to_string());
+    }
+}
+
+pub fn get_transaction_info(reader: transaction_info::Reader) -> Result<TransactionInfo> {
+    let ids = try!(reader.get_documents());
+    let mut documents = Vec::with_capacity(ids.len() as usize);
+
+    for i in 0..ids.len() {
+        documents.push(try!(parse_id(try!(ids.get(i)))));
+    }
+
+    Ok(TransactionInfo {
+        id: try!(parse_session(try!(reader.get_id()))),
+        author: try!(reader.get_author()).to_string(),
+        started: reader.get_started(),
+        age: reader.get_age(),
+        operations: reader.get_operations() as usize,
+        documents: documents,
+    })
+}
+
+pub fn set_usage(mut builder: usage::Builder, usage: &Usage) {
+    builder.set_documents(usage.documents);
+    builder.set_total_bytes(usage.total_bytes);
+    builder.set_staged_bytes(usage.staged_bytes);
+    builder.set_max_documents(usage.quota.max_documents.unwrap_or(0));
+    builder.set_max_payload_size(usage.quota.max_payload_size.unwrap_or(0));
+    builder.set_max_total_bytes(usage.quota.max_total_bytes.unwrap_or(0));
+}
+
+pub fn get_usage(reader: usage::Reader) -> Usage {
+    Usage {
+        documents: reader.get_documents(),
+        total_bytes: reader.get_total_bytes(),
+        staged_bytes: reader.get_staged_bytes(),
+        quota: Quota {
+            max_documents: limit(reader.get_max_documents()),
+            max_payload_size: limit(reader.get_max_payload_size()),
+            max_total_bytes: limit(reader.get_max_total_bytes()),
+        },
+    }
+}
+
+fn set_quota_limit(mut builder: quota_limit::Builder, limit: &QuotaLimit) {
+    let (kind, value) = match *limit {
+        QuotaLimit::Documents(value) => (quota_limit::Kind::Documents, value),
+        QuotaLimit::PayloadSize(value) => (quota_limit::Kind::PayloadSize, value),
+        QuotaLimit::TotalBytes(value) => (quota_limit::Kind::TotalBytes, value),
+    };
+
+    builder.set_kind(kind);
+    builder.set_limit(value);
+}
+
+fn get_quota_limit(reader: quota_limit::Reader) -> Result<QuotaLimit> {
+    let value = reader.get_limit();
+
+    Ok(match try!(reader.get_kind()) {
+        quota_limit::Kind::Documents => QuotaLimit::Documents(value),
+        quota_limit::Kind::PayloadSize => QuotaLimit::PayloadSize(value),
+        quota_limit::Kind::TotalBytes => QuotaLimit::TotalBytes(value),
+    })
+}
+
+fn set_violation(mut builder: schema_violation::Builder, violation: &SchemaViolation) {
+    builder.set_path(&violation.path);
+    builder.set_message(&violation.message);
+}
+
+/// Writes the rejection of the state machine
+fn set_document_error(mut builder: error::Builder, err: &DocumentError) {
+    let (kind, subject) = match *err {
+        DocumentError::NotFound(ref id) => (error::Kind::NotFound, id.to_string()),
+        DocumentError::Conflict(ref id, ref session) => {
+            builder.set_session(&session.to_string());
+            (error::Kind::Conflict, id.to_string())
+        }
+        DocumentError::AlreadyExists(ref id) => (error::Kind::AlreadyExists, id.to_string()),
+        DocumentError::NotNumeric(ref id) => (error::Kind::NotNumeric, id.to_string()),
+        DocumentError::Overflow(ref id) => (error::Kind::Overflow, id.to_string()),
+        DocumentError::TransactionNotFound(ref session) => {
+            (error::Kind::TransactionNotFound, session.to_string())
+        }
+        DocumentError::SavepointNotFound(ref name) => {
+            (error::Kind::SavepointNotFound, name.clone())
+        }
+        DocumentError::Moving(ref id) => (error::Kind::Moving, id.to_string()),
+        DocumentError::InvalidRange(ref id) => (error::Kind::InvalidRange, id.to_string()),
+        DocumentError::UploadNotFound(ref id) => (error::Kind::UploadNotFound, id.to_string()),
+        DocumentError::IncompleteUpload(ref id, index) => {
+            builder.set_index(index);
+            (error::Kind::IncompleteUpload, id.to_string())
+        }
+        DocumentError::InvalidDocument(ref id, ref violations) => {
+            {
+                let mut list = builder.borrow().init_violations(violations.len() as u32);
+                for (i, violation) in violations.iter().enumerate() {
+                    set_violation(list.borrow().get(i as u32), violation);
+                }
+            }
+            (error::Kind::InvalidDocument, id.to_string())
+        }
+        DocumentError::InvalidSchema(ref reason) => (error::Kind::InvalidSchema, reason.clone()),
+        DocumentError::QuotaExceeded(ref id, ref limit) => {
+            set_quota_limit(builder.borrow().init_limit(), limit);
+            (error::Kind::QuotaExceeded, id.to_string())
+        }
+        DocumentError::ReadOnly => (error::Kind::ReadOnly, String::new()),
+        DocumentError::InvalidCommand(ref reason) => {
+            (error::Kind::InvalidCommand, reason.clone())
+        }
+        DocumentError::VersionChanged(ref id) => (error::Kind::VersionChanged, id.to_string()),
+    };
+
+    builder.set_kind(kind);
+    builder.set_subject(&subject);
+}
+
+pub fn set_error(mut builder: error::Builder, err: &ClientError) {
+    builder.set_message(&err.to_string());
+
+    match *err {
+        ClientError::NotFound(ref err) |
+        ClientError::Conflict(ref err) |
+        ClientError::Rejected(ref err) => set_document_error(builder, err),
+        ClientError::Unauthorized => builder.set_kind(error::Kind::Unauthorized),
+        ClientError::NoLeader(ref reason) => {
+            builder.set_kind(error::Kind::NoLeader);
+            builder.set_subject(reason);
+        }
+        ClientError::Timeout => builder.set_kind(error::Kind::Timeout),
+        ClientError::Protocol(ref reason) => {
+            builder.set_kind(error::Kind::Protocol);
+            builder.set_subject(reason);
+        }
+        ClientError::Io(_) => builder.set_kind(error::Kind::Io),
+    }
+}
+
+/// Returns the error which was sent by the node. Fails if the error itself is invalid
+pub fn get_error(reader: error::Reader) -> Result<ClientError> {
+    let subject = try!(reader.get_subject());
+
+    let err = match try!(reader.get_kind()) {
+        error::Kind::NotFound => DocumentError::NotFound(try!(parse_id(subject))),
+        error::Kind::Conflict => {
+            DocumentError::Conflict(try!(parse_id(subject)),
+                                    try!(parse_session(try!(reader.get_session()))))
+        }
+        error::Kind::AlreadyExists => DocumentError::AlreadyExists(try!(parse_id(subject))),
+        error::Kind::NotNumeric => DocumentError::NotNumeric(try!(parse_id(subject))),
+        error::Kind::Overflow => DocumentError::Overflow(try!(parse_id(subject))),
+        error::Kind::TransactionNotFound => {
+            DocumentError::TransactionNotFound(try!(parse_session(subject)))
+        }
+        error::Kind::SavepointNotFound => DocumentError::SavepointNotFound(subject.to_string()),
+        error::Kind::Moving => DocumentError::Moving(try!(parse_id(subject))),
+        error::Kind::InvalidRange => DocumentError::InvalidRange(try!(parse_id(subject))),
+        error::Kind::UploadNotFound => DocumentError::UploadNotFound(try!(parse_id(subject))),
+        error::Kind::IncompleteUpload => {
+            DocumentError::IncompleteUpload(try!(parse_id(subject)), reader.get_index())
+        }
+        error::Kind::InvalidDocument => {
+            let list = try!(reader.get_violations());
+            let mut violations = Vec::with_capacity(list.len() as usize);
+
+            for violation in list.iter() {
+                violations.push(SchemaViolation {
+                    path: try!(violation.get_path()).to_string(),
+                    message: try!(violation.get_message()).to_string(),
+                });
+            }
+
+            DocumentError::InvalidDocument(try!(parse_id(subject)), violations)
+        }
+        error::Kind::InvalidSchema => DocumentError::InvalidSchema(subject.to_string()),
+        error::Kind::QuotaExceeded => {
+            DocumentError::QuotaExceeded(try!(parse_id(subject)),
+                                         try!(get_quota_limit(try!(reader.get_limit()))))
+        }
+        error::Kind::ReadOnly => DocumentError::ReadOnly,
+        error::Kind::InvalidCommand => DocumentError::InvalidCommand(subject.to_string()),
+        error::Kind::VersionChanged => DocumentError::VersionChanged(try!(parse_id(subject))),
+        error::Kind::Unauthorized => return Ok(ClientError::Unauthorized),
+        error::Kind::NoLeader => return Ok(ClientError::NoLeader(subject.to_string())),
+        error::Kind::Timeout => return Ok(ClientError::Timeout),
+        error::Kind::Protocol => return Ok(ClientError::Protocol(subject.to_string())),
+        error::Kind::Io => {
+            let message = try!(reader.get_message());
+            return Ok(ClientError::Io(IoError::new(ErrorKind::Other, message)));
+        }
+    };
+
+    Ok(ClientError::from(err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use capnp::message::{Builder, HeapAllocator};
+    use document::{Document, DocumentError};
+    use error::ClientError;
+    use quota::QuotaLimit;
+    use schema::SchemaViolation;
+    use paenko_capnp::{delta, document, error, quota};
+    use uuid::Uuid;
+
+    fn round_trip(err: ClientError) -> ClientError {
+        let mut message = Builder::new(HeapAllocator::new());
+        set_error(message.init_root::<error::Builder>(), &err);
+
+        get_error(message.get_root::<error::Builder>().unwrap().as_reader()).unwrap()
+    }
+
+    #[test]
+    fn test_document() {
+        let document = Document {
+            id: Uuid::new_v4(),
+            payload: b"test".to_vec(),
+            version: 3,
+        };
+
+        let mut message = Builder::new(HeapAllocator::new());
+        set_document(message.init_root::<document::Builder>(), &document);
+
+        let decoded = get_document(message.get_root::<document::Builder>()
+                .unwrap()
+                .as_reader())
+            .unwrap();
+        assert_eq!(document, decoded);
+    }
+
+    #[test]
+    fn test_keyed_document() {
+        let mut message = Builder::new(HeapAllocator::new());
+        {
+            let mut builder = message.init_root::<document::Builder>();
+            builder.set_id("user-42");
+            builder.set_payload(b"test");
+            builder.set_version(1);
+        }
+
+        {
+            let reader = message.get_root::<document::Builder>().unwrap().as_reader();
+            assert!(get_document(reader).is_err());
+            assert_eq!(document_id_from_key("user-42"),
+                       get_keyed_document(reader).unwrap().id);
+        }
+
+        let id = Uuid::new_v4();
+        message.get_root::<document::Builder>().unwrap().set_id(&id.to_string());
+
+        let reader = message.get_root::<document::Builder>().unwrap().as_reader();
+        assert_eq!(id, get_keyed_document(reader).unwrap().id);
+    }
+
+    #[test]
+    fn test_delta() {
+        for value in vec![Delta::Append(b"tail".to_vec()),
+                          Delta::Write(4, b"test".to_vec()),
+                          Delta::Truncate(2)] {
+            let mut message = Builder::new(HeapAllocator::new());
+            set_delta(message.init_root::<delta::Builder>(), &value);
+
+            let decoded = get_delta(message.get_root::<delta::Builder>().unwrap().as_reader());
+            assert_eq!(value, decoded.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_quota() {
+        let value = Quota {
+            max_documents: Some(10),
+            max_payload_size: None,
+            max_total_bytes: Some(1024),
+        };
+
+        let mut message = Builder::new(HeapAllocator::new());
+        set_quota(message.init_root::<quota::Builder>(), &value);
+
+        assert_eq!(value,
+                   get_quota(message.get_root::<quota::Builder>().unwrap().as_reader()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let id = Uuid::new_v4();
+
+        match round_trip(ClientError::from(DocumentError::NotFound(id))) {
+            ClientError::NotFound(DocumentError::NotFound(not_found)) => assert_eq!(id, not_found),
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        let violations = vec![SchemaViolation {
+                                  path: "/zip".to_string(),
+                                  message: "is not a string".to_string(),
+                              }];
+        match round_trip(ClientError::from(DocumentError::InvalidDocument(id,
+                                                                          violations.clone()))) {
+            ClientError::Rejected(DocumentError::InvalidDocument(invalid, decoded)) => {
+                assert_eq!(id, invalid);
+                assert_eq!(violations, decoded);
+            }
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        match round_trip(ClientError::from(DocumentError::QuotaExceeded(id,
+                                                                        QuotaLimit::Documents(10)))) {
+            ClientError::Rejected(DocumentError::QuotaExceeded(_, QuotaLimit::Documents(10))) => {}
+            err => panic!("Unexpected error {:?}", err),
+        }
+
+        match round_trip(ClientError::NoLeader("no nodes".to_string())) {
+            ClientError::NoLeader(reason) => assert_eq!("no nodes", reason),
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+}
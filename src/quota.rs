@@ -0,0 +1,103 @@
+use std::fmt;
+
+/// The limits of a log. `None` means unlimited
+#[derive(Debug,Clone,Default,Serialize,Deserialize,PartialEq)]
+pub struct Quota {
+    pub max_documents: Option<u64>,
+    /// The maximum size of a single payload in bytes
+    pub max_payload_size: Option<u64>,
+    /// The maximum size of all payloads in bytes
+    pub max_total_bytes: Option<u64>,
+}
+
+/// The limit which a write would exceed
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub enum QuotaLimit {
+    Documents(u64),
+    PayloadSize(u64),
+    TotalBytes(u64),
+}
+
+impl fmt::Display for QuotaLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            QuotaLimit::Documents(max) => write!(f, "the log is limited to {} documents", max),
+            QuotaLimit::PayloadSize(max) => write!(f, "a payload is limited to {} bytes", max),
+            QuotaLimit::TotalBytes(max) => write!(f, "the log is limited to {} bytes", max),
+        }
+    }
+}
+
+/// The current usage of a log together with its quota, which is returned to clients
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct Usage {
+    pub documents: u64,
+    /// The size of all payloads in bytes
+    pub total_bytes: u64,
+    /// The size of the chunks of pending uploads in bytes. They count towards
+    /// `max_total_bytes`
+    pub staged_bytes: u64,
+    pub quota: Quota,
+}
+
+impl Quota {
+    /// Checks a write which changes a payload of `old_size` bytes to `new_size` bytes.
+    /// `old_size` is `None` if the write creates a new document.
+    pub fn check(&self,
+                 usage: &Usage,
+                 old_size: Option<u64>,
+                 new_size: u64)
+                 -> Result<(), QuotaLimit> {
+        if let Some(max) = self.max_payload_size {
+            if new_size > max {
+                return Err(QuotaLimit::PayloadSize(max));
+            }
+        }
+
+        if let Some(max) = self.max_documents {
+            if old_size.is_none() && usage.documents >= max {
+                return Err(QuotaLimit::Documents(max));
+            }
+        }
+
+        if let Some(max) = self.max_total_bytes {
+            let old_size = old_size.unwrap_or(0);
+
+            let used = usage.total_bytes + usage.staged_bytes;
+
+            if new_size > old_size && used + (new_size - old_size) > max {
+                return Err(QuotaLimit::TotalBytes(max));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let quota = Quota {
+            max_documents: Some(2),
+            max_payload_size: Some(10),
+            max_total_bytes: Some(15),
+        };
+        let usage = Usage {
+            documents: 2,
+            total_bytes: 10,
+            staged_bytes: 2,
+            quota: quota.clone(),
+        };
+
+        assert_eq!(Err(QuotaLimit::PayloadSize(10)), quota.check(&usage, Some(5), 11));
+        assert_eq!(Err(QuotaLimit::Documents(2)), quota.check(&usage, None, 1));
+        assert_eq!(Err(QuotaLimit::TotalBytes(15)), quota.check(&usage, Some(5), 9));
+        assert_eq!(Ok(()), quota.check(&usage, Some(5), 8));
+
+        // shrinking is always allowed
+        assert_eq!(Ok(()), quota.check(&usage, Some(10), 2));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::{HashMap, VecDeque};
+
+use raft::TransactionId;
+
+use handler::RequestId;
+
+/// The number of requests whose responses are remembered per log
+pub const MAX_REQUESTS: usize = 10000;
+
+/// The total size in bytes of the responses which are remembered per log
+pub const MAX_REQUEST_BYTES: usize = 16 * 1024 * 1024;
+
+/// Remembers the responses of the latest client requests, so a request which is
+/// proposed again is not applied twice
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct RequestTable {
+    capacity: usize,
+    /// The maximum total size of the responses. The latest response is kept even if it
+    /// is larger on its own, so its request is never applied twice.
+    max_bytes: usize,
+    /// The total size of the responses
+    bytes: usize,
+    /// The response of each request together with the transaction which applied it
+    responses: HashMap<RequestId, (Option<TransactionId>, Vec<u8>)>,
+    /// The requests in the order they were applied. The oldest will be dropped first.
+    order: VecDeque<RequestId>,
+}
+
+impl RequestTable {
+    pub fn new(capacity: usize, max_bytes: usize) -> Self {
+        RequestTable {
+            capacity: capacity,
+            max_bytes: max_bytes,
+            bytes: 0,
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns the response of a request which was already applied
+    pub fn get(&self, id: &RequestId) -> Option<&Vec<u8>> {
+        self.responses.get(id).map(|&(_, ref response)| response)
+    }
+
+    /// Returns the total size of the remembered responses
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Saves the response of a request and drops the oldest requests while the table is
+    /// full
+    pub fn insert(&mut self, id: RequestId, session: Option<TransactionId>, response: Vec<u8>) {
+        self.bytes += response.len();
+
+        match self.responses.insert(id, (session, response)) {
+            Some((_, old)) => self.bytes -= old.len(),
+            None => self.order.push_back(id),
+        }
+
+        while self.order.len() > self.capacity ||
+              (self.bytes > self.max_bytes && self.order.len() > 1) {
+            if let Some(oldest) = self.order.pop_front() {
+                if let Some((_, response)) = self.responses.remove(&oldest) {
+                    self.bytes -= response.len();
+                }
+            }
+        }
+    }
+
+    /// Forgets a request whose command was reverted by the transaction `session`
+    pub fn remove(&mut self, id: &RequestId, session: Option<TransactionId>) {
+        let applied_by_session = match self.responses.get(id) {
+            Some(&(s, _)) => s == session,
+            None => false,
+        };
+
+        if applied_by_session {
+            if let Some((_, response)) = self.responses.remove(id) {
+                self.bytes -= response.len();
+            }
+            self.order.retain(|r| r != id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_byte_limit() {
+        let mut table = RequestTable::new(10, 10);
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let third = Uuid::new_v4();
+
+        table.insert(first, None, vec![0; 4]);
+        table.insert(second, None, vec![0; 4]);
+        assert_eq!(8, table.bytes());
+
+        // the oldest response is dropped to make room
+        table.insert(third, None, vec![0; 4]);
+        assert!(table.get(&first).is_none());
+        assert!(table.get(&second).is_some());
+        assert_eq!(8, table.bytes());
+
+        // a response above the limit is kept alone
+        let large = Uuid::new_v4();
+        table.insert(large, None, vec![0; 20]);
+        assert!(table.get(&second).is_none());
+        assert!(table.get(&third).is_none());
+        assert_eq!(Some(&vec![0; 20]), table.get(&large));
+        assert_eq!(20, table.bytes());
+
+        table.remove(&large, None);
+        assert_eq!(0, table.bytes());
+    }
+
+    #[test]
+    fn test_count_limit() {
+        let mut table = RequestTable::new(2, MAX_REQUEST_BYTES);
+        let ids = vec![Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+
+        for id in &ids {
+            table.insert(*id, None, b"ok".to_vec());
+        }
+
+        assert!(table.get(&ids[0]).is_none());
+        assert!(table.get(&ids[1]).is_some());
+        assert!(table.get(&ids[2]).is_some());
+        assert_eq!(4, table.bytes());
+    }
+}
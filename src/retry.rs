@@ -0,0 +1,76 @@
+use std::cmp::min;
+use std::time::Duration;
+
+use rand;
+
+/// Limits how often a client follows redirects and retries a request
+#[derive(Debug,Clone,PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of redirects to another leader per request
+    pub max_redirects: u32,
+    /// The maximum number of retries after a node could not be reached or knew no leader.
+    /// Every retry is sent to the next seed node
+    pub max_retries: u32,
+    /// The backoff before the first retry. It doubles with every further retry
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// The maximum duration of a request including all retries. `None` retries until
+    /// `max_retries` is reached
+    pub deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_redirects: 5,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            deadline: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+fn to_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+impl RetryPolicy {
+    /// Returns the backoff before the `retry`, starting with 1. The exponential backoff
+    /// is capped by `max_backoff` and the second half of it is random, so clients which
+    /// failed at the same time do not retry at the same time.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let initial = to_millis(self.initial_backoff);
+        let exponent = min(retry.saturating_sub(1), 32);
+        let backoff = min(initial.saturating_mul(1u64 << exponent), to_millis(self.max_backoff));
+
+        let half = backoff / 2;
+        let jitter = (rand::random::<f64>() * (backoff - half) as f64) as u64;
+
+        Duration::from_millis(half + jitter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_redirects: 5,
+            max_retries: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            deadline: None,
+        };
+
+        for &(retry, max) in &[(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (40, 1000)] {
+            let backoff = policy.backoff(retry);
+
+            assert!(backoff >= Duration::from_millis(max / 2));
+            assert!(backoff <= Duration::from_millis(max));
+        }
+    }
+}
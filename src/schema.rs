@@ -0,0 +1,336 @@
+use std::fmt;
+
+use serde_json;
+use serde_json::Value;
+
+/// A part of a document which does not match the schema
+#[derive(Serialize,Deserialize,Debug,Clone,PartialEq)]
+pub struct SchemaViolation {
+    /// The JSON pointer of the failing value, e.g. `/address/zip`. The whole document is `""`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// The keywords which are checked by `Schema::validate`
+const KEYWORDS: &'static [&'static str] = &["type",
+                                            "enum",
+                                            "minimum",
+                                            "maximum",
+                                            "minLength",
+                                            "maxLength",
+                                            "properties",
+                                            "required",
+                                            "additionalProperties",
+                                            "items",
+                                            "minItems",
+                                            "maxItems"];
+
+/// The keywords which only describe the schema
+const ANNOTATIONS: &'static [&'static str] = &["$schema", "title", "description", "default"];
+
+/// A JSON Schema which the payloads of a log have to match. The keywords of `KEYWORDS`
+/// are supported, a schema with any other keyword than an annotation is rejected, so no
+/// constraint is silently ignored.
+#[derive(Debug,Clone)]
+pub struct Schema {
+    source: String,
+    schema: Value,
+}
+
+impl Schema {
+    /// Parses the JSON text of a schema
+    pub fn parse(source: String) -> Result<Schema, String> {
+        let schema: Value = try!(serde_json::from_str(&source).map_err(|err| format!("{}", err)));
+
+        if !schema.is_object() {
+            return Err("The schema is not a JSON object".to_string());
+        }
+
+        try!(check_keywords(&schema, ""));
+
+        Ok(Schema {
+            source: source,
+            schema: schema,
+        })
+    }
+
+    /// Returns the JSON text of the schema
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns all parts of the payload which do not match the schema. The violations are
+    /// ordered by their path, so every replica returns the same result.
+    pub fn validate(&self, payload: &[u8]) -> Vec<SchemaViolation> {
+        let mut violations = Vec::new();
+
+        match serde_json::from_slice::<Value>(payload) {
+            Ok(value) => validate_value(&self.schema, &value, "", &mut violations),
+            Err(_) => violation(&mut violations, "", "The payload is not JSON".to_string()),
+        }
+
+        // the sort is stable, so the violations of a path keep the order of the checks
+        violations.sort_by(|a, b| a.path.cmp(&b.path));
+
+        violations
+    }
+}
+
+/// Fails if the schema or one of its subschemas has a keyword which is not supported.
+/// `path` is the JSON pointer of the subschema.
+fn check_keywords(schema: &Value, path: &str) -> Result<(), String> {
+    let location = if path.is_empty() { "/" } else { path };
+
+    let object = match schema.as_object() {
+        Some(object) => object,
+        None => return Err(format!("The schema {} is not a JSON object", location)),
+    };
+
+    for keyword in object.keys() {
+        if !KEYWORDS.contains(&keyword.as_str()) && !ANNOTATIONS.contains(&keyword.as_str()) {
+            return Err(format!("The keyword {} of the schema {} is not supported",
+                               keyword,
+                               location));
+        }
+    }
+
+    if let Some(properties) = schema.find("properties") {
+        let properties = try!(properties.as_object()
+            .ok_or(format!("The properties of the schema {} are not a JSON object", location)));
+
+        for (name, property) in properties.iter() {
+            try!(check_keywords(property, &format!("{}/properties/{}", path, escape(name))));
+        }
+    }
+
+    if let Some(items) = schema.find("items") {
+        try!(check_keywords(items, &format!("{}/items", path)));
+    }
+
+    match schema.find("additionalProperties") {
+        None | Some(&Value::Bool(_)) => {}
+        Some(additional) => {
+            try!(check_keywords(additional, &format!("{}/additionalProperties", path)))
+        }
+    }
+
+    Ok(())
+}
+
+fn violation(violations: &mut Vec<SchemaViolation>, path: &str, message: String) {
+    violations.push(SchemaViolation {
+        path: path.to_string(),
+        message: message,
+    });
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match *value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::I64(_) | Value::U64(_) => "integer",
+        Value::F64(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    let actual = type_name(value);
+    actual == name || (name == "number" && actual == "integer")
+}
+
+fn validate_value(schema: &Value,
+                  value: &Value,
+                  path: &str,
+                  violations: &mut Vec<SchemaViolation>) {
+    match schema.find("type") {
+        Some(&Value::String(ref name)) if !has_type(value, name) => {
+            violation(violations,
+                      path,
+                      format!("Expected {} but found {}", name, type_name(value)));
+            return;
+        }
+        Some(&Value::Array(ref names)) if !names.iter()
+            .filter_map(|name| name.as_str())
+            .any(|name| has_type(value, name)) => {
+            violation(violations,
+                      path,
+                      format!("The type {} is not allowed", type_name(value)));
+            return;
+        }
+        _ => {}
+    }
+
+    if let Some(&Value::Array(ref allowed)) = schema.find("enum") {
+        if !allowed.contains(value) {
+            violation(violations, path, "The value is not allowed".to_string());
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema.find("minimum").and_then(|m| m.as_f64()) {
+            if number < minimum {
+                violation(violations, path, format!("The value is less than {}", minimum));
+            }
+        }
+
+        if let Some(maximum) = schema.find("maximum").and_then(|m| m.as_f64()) {
+            if number > maximum {
+                violation(violations,
+                          path,
+                          format!("The value is greater than {}", maximum));
+            }
+        }
+    }
+
+    match *value {
+        Value::String(ref string) => {
+            let length = string.chars().count() as u64;
+
+            if let Some(min) = schema.find("minLength").and_then(|m| m.as_u64()) {
+                if length < min {
+                    violation(violations, path, format!("The string is shorter than {}", min));
+                }
+            }
+
+            if let Some(max) = schema.find("maxLength").and_then(|m| m.as_u64()) {
+                if length > max {
+                    violation(violations, path, format!("The string is longer than {}", max));
+                }
+            }
+        }
+        Value::Array(ref items) => {
+            let length = items.len() as u64;
+
+            if let Some(min) = schema.find("minItems").and_then(|m| m.as_u64()) {
+                if length < min {
+                    violation(violations, path, format!("The array has less than {} items", min));
+                }
+            }
+
+            if let Some(max) = schema.find("maxItems").and_then(|m| m.as_u64()) {
+                if length > max {
+                    violation(violations, path, format!("The array has more than {} items", max));
+                }
+            }
+
+            if let Some(item_schema) = schema.find("items") {
+                for (index, item) in items.iter().enumerate() {
+                    validate_value(item_schema, item, &format!("{}/{}", path, index), violations);
+                }
+            }
+        }
+        Value::Object(ref object) => {
+            if let Some(&Value::Array(ref required)) = schema.find("required") {
+                for name in required.iter().filter_map(|name| name.as_str()) {
+                    if !object.contains_key(name) {
+                        violation(violations,
+                                  &format!("{}/{}", path, escape(name)),
+                                  "The property is required".to_string());
+                    }
+                }
+            }
+
+            let properties = schema.find("properties").and_then(|p| p.as_object());
+
+            for (name, property) in object.iter() {
+                let property_path = format!("{}/{}", path, escape(name));
+
+                match properties.and_then(|p| p.get(name)) {
+                    Some(property_schema) => {
+                        validate_value(property_schema, property, &property_path, violations)
+                    }
+                    None => {
+                        match schema.find("additionalProperties") {
+                            Some(&Value::Bool(false)) => {
+                                violation(violations,
+                                          &property_path,
+                                          "The property is not allowed".to_string())
+                            }
+                            Some(additional) if additional.is_object() => {
+                                validate_value(additional, property, &property_path, violations)
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Escapes a property name for a JSON pointer
+fn escape(name: &str) -> String {
+    name.replace("~", "~0").replace("/", "~1")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::parse(r#"{
+            "type": "object",
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "age": { "type": "integer", "minimum": 0 },
+                "tags": { "type": "array", "items": { "enum": ["a", "b"] } }
+            }
+        }"#
+                .to_string())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_valid_document() {
+        let payload = br#"{"name": "paenko", "age": 3, "tags": ["a"]}"#;
+
+        assert!(schema().validate(payload).is_empty());
+    }
+
+    #[test]
+    fn test_violations() {
+        let payload = br#"{"age": -1, "tags": ["a", "c"], "other": true}"#;
+
+        let paths: Vec<String> = schema()
+            .validate(payload)
+            .into_iter()
+            .map(|violation| violation.path)
+            .collect();
+
+        assert_eq!(vec!["/age", "/name", "/other", "/tags/1"], paths);
+    }
+
+    #[test]
+    fn test_unsupported_keywords() {
+        assert!(Schema::parse(r#"{"title": "test", "type": "object"}"#.to_string()).is_ok());
+
+        for schema in &[r#"{"type": "string", "pattern": "^a"}"#,
+                        r#"{"$ref": "other.json"}"#,
+                        r#"{"properties": {"name": {"format": "email"}}}"#,
+                        r#"{"items": {"oneOf": []}}"#,
+                        r#"{"items": [{"type": "string"}]}"#] {
+            assert!(Schema::parse(schema.to_string()).is_err(), "{}", schema);
+        }
+    }
+
+    #[test]
+    fn test_not_json() {
+        assert_eq!(vec![SchemaViolation {
+                            path: "".to_string(),
+                            message: "The payload is not JSON".to_string(),
+                        }],
+                   schema().validate(b"no json"));
+    }
+}
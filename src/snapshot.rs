@@ -0,0 +1,459 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::error;
+use std::fmt;
+
+use bincode::serde::serialize as encode;
+use bincode::serde::deserialize as decode;
+use bincode::SizeLimit;
+
+use raft::TransactionId;
+
+use handler::RequestId;
+
+use blobs::BlobStore;
+use document::{ActionType, Document, DocumentId, DocumentRecord, PendingMove, StoredDocument,
+               Tombstone};
+use quota::Quota;
+use requests::{RequestTable, MAX_REQUESTS, MAX_REQUEST_BYTES};
+use transaction::Transaction;
+use upload::{Upload, UploadId};
+
+/// The version of the layout of `Snapshot`. It has to be increased whenever the layout
+/// changes, together with a conversion from the older layout.
+pub const SNAPSHOT_VERSION: u16 = 3;
+
+/// Marks a snapshot which starts with its version. Snapshots without the header were
+/// written as two buffers, the documents and the undo records.
+const MAGIC: &'static [u8; 4] = b"PKSN";
+
+const HEADER_LEN: usize = 6;
+
+/// The replicated state of a log. It is sent to followers which catch up by snapshot and
+/// saved on the volume, so a restarted node continues with the same state as the other
+/// replicas. The documents, tombstones and undo records reference their payloads in the
+/// `BlobStore`.
+#[derive(Debug,Serialize,Deserialize)]
+pub struct Snapshot {
+    pub documents: HashMap<DocumentId, StoredDocument>,
+    /// The undo records which older nodes kept for every write. The undo records are part
+    /// of their transactions now, so it is written empty.
+    pub log: Vec<DocumentRecord>,
+    pub blobs: BlobStore,
+    pub transactions: HashMap<TransactionId, Transaction>,
+    pub requests: RequestTable,
+    pub sequences: HashMap<String, u64>,
+    pub tombstones: HashMap<DocumentId, Tombstone>,
+    pub moves: HashMap<DocumentId, PendingMove>,
+    pub uploads: HashMap<UploadId, Upload>,
+    pub schema: Option<String>,
+    pub soft_delete: bool,
+    pub quota: Quota,
+    pub full_nodes: BTreeSet<String>,
+}
+
+/// A pending upload of version 1. The bytes of its chunks were part of the snapshot.
+#[derive(Debug,Serialize,Deserialize)]
+struct UploadV1 {
+    document: DocumentId,
+    started: u64,
+    chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+/// The request table up to version 2. It was bounded by the number of requests only.
+#[derive(Debug,Serialize,Deserialize)]
+struct RequestTableV2 {
+    capacity: usize,
+    responses: HashMap<RequestId, (Option<TransactionId>, Vec<u8>)>,
+    order: VecDeque<RequestId>,
+}
+
+impl RequestTableV2 {
+    /// Converts the table. The oldest responses are dropped if they exceed the size limit.
+    fn upgrade(self) -> RequestTable {
+        let mut responses = self.responses;
+        let mut table = RequestTable::new(MAX_REQUESTS, MAX_REQUEST_BYTES);
+
+        for id in self.order {
+            if let Some((session, response)) = responses.remove(&id) {
+                table.insert(id, session, response);
+            }
+        }
+
+        table
+    }
+}
+
+/// The layout of version 1. It differs from `SnapshotV2` only in the pending uploads.
+#[derive(Debug,Serialize,Deserialize)]
+struct SnapshotV1 {
+    documents: HashMap<DocumentId, StoredDocument>,
+    log: Vec<DocumentRecord>,
+    blobs: BlobStore,
+    transactions: HashMap<TransactionId, Transaction>,
+    requests: RequestTableV2,
+    sequences: HashMap<String, u64>,
+    tombstones: HashMap<DocumentId, Tombstone>,
+    moves: HashMap<DocumentId, PendingMove>,
+    uploads: HashMap<UploadId, UploadV1>,
+    schema: Option<String>,
+    soft_delete: bool,
+    quota: Quota,
+    full_nodes: BTreeSet<String>,
+}
+
+impl SnapshotV1 {
+    /// Converts the snapshot. The pending uploads are dropped, because the chunks are
+    /// staged in the volume since version 2. Their clients have to upload them again.
+    fn upgrade(self) -> SnapshotV2 {
+        SnapshotV2 {
+            documents: self.documents,
+            log: self.log,
+            blobs: self.blobs,
+            transactions: self.transactions,
+            requests: self.requests,
+            sequences: self.sequences,
+            tombstones: self.tombstones,
+            moves: self.moves,
+            uploads: HashMap::new(),
+            schema: self.schema,
+            soft_delete: self.soft_delete,
+            quota: self.quota,
+            full_nodes: self.full_nodes,
+        }
+    }
+}
+
+/// The layout of version 2. It differs from `Snapshot` only in the request table.
+#[derive(Debug,Serialize,Deserialize)]
+struct SnapshotV2 {
+    documents: HashMap<DocumentId, StoredDocument>,
+    log: Vec<DocumentRecord>,
+    blobs: BlobStore,
+    transactions: HashMap<TransactionId, Transaction>,
+    requests: RequestTableV2,
+    sequences: HashMap<String, u64>,
+    tombstones: HashMap<DocumentId, Tombstone>,
+    moves: HashMap<DocumentId, PendingMove>,
+    uploads: HashMap<UploadId, Upload>,
+    schema: Option<String>,
+    soft_delete: bool,
+    quota: Quota,
+    full_nodes: BTreeSet<String>,
+}
+
+impl SnapshotV2 {
+    /// Converts the snapshot. The request table is bounded by the size of its responses
+    /// since version 3.
+    fn upgrade(self) -> Snapshot {
+        Snapshot {
+            documents: self.documents,
+            log: self.log,
+            blobs: self.blobs,
+            transactions: self.transactions,
+            requests: self.requests.upgrade(),
+            sequences: self.sequences,
+            tombstones: self.tombstones,
+            moves: self.moves,
+            uploads: self.uploads,
+            schema: self.schema,
+            soft_delete: self.soft_delete,
+            quota: self.quota,
+            full_nodes: self.full_nodes,
+        }
+    }
+}
+
+/// The undo record of the layout without a header. Tombstones and deltas did not exist.
+#[derive(Debug,Serialize,Deserialize)]
+struct LegacyRecord {
+    id: DocumentId,
+    path: String,
+    method: ActionType,
+    old: Option<Vec<u8>>,
+}
+
+impl LegacyRecord {
+    /// Converts the record and moves its old payload to the `BlobStore`
+    fn into_record(self, blobs: &mut BlobStore) -> DocumentRecord {
+        let mut record = DocumentRecord::new(self.id, self.path, self.method);
+
+        if let Some(old) = self.old {
+            record.set_old_hash(blobs.insert(old));
+        }
+
+        record
+    }
+}
+
+/// Errors of restoring a snapshot
+#[derive(Debug,Clone,PartialEq)]
+pub enum SnapshotError {
+    /// The snapshot was written by a newer node with an unknown version
+    UnsupportedVersion(u16),
+    /// The bytes match no known layout
+    Malformed(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f,
+                       "The snapshot version {} is not supported, the latest is {}",
+                       version,
+                       SNAPSHOT_VERSION)
+            }
+            SnapshotError::Malformed(ref reason) => write!(f, "Malformed snapshot: {}", reason),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn description(&self) -> &str {
+        match *self {
+            SnapshotError::UnsupportedVersion(_) => "unsupported snapshot version",
+            SnapshotError::Malformed(_) => "malformed snapshot",
+        }
+    }
+}
+
+/// Encodes a snapshot with the current version
+pub fn encode_snapshot(snapshot: &Snapshot) -> Vec<u8> {
+    let body = encode(snapshot, SizeLimit::Infinite).unwrap();
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+
+    bytes.extend_from_slice(MAGIC);
+    bytes.push((SNAPSHOT_VERSION >> 8) as u8);
+    bytes.push(SNAPSHOT_VERSION as u8);
+    bytes.extend(body);
+
+    bytes
+}
+
+/// Decodes a snapshot. `bytes` and `log` are the two buffers of the state machine
+/// snapshot. A snapshot of the current version is completely in `bytes`, the older
+/// layout without a header keeps the documents in `bytes` and the undo records in `log`.
+/// Empty buffers are the state of a new log. The older layout was written without
+/// truncating the files, so trailing bytes are ignored there.
+pub fn decode_snapshot(bytes: &[u8], log: &[u8]) -> Result<Snapshot, SnapshotError> {
+    if bytes.len() >= HEADER_LEN && &bytes[..MAGIC.len()] == &MAGIC[..] {
+        let version = ((bytes[4] as u16) << 8) | bytes[5] as u16;
+
+        return match version {
+            1 => {
+                decode(&bytes[HEADER_LEN..])
+                    .map(|snapshot: SnapshotV1| snapshot.upgrade().upgrade())
+                    .map_err(|err| SnapshotError::Malformed(format!("{}", err)))
+            }
+            2 => {
+                decode(&bytes[HEADER_LEN..])
+                    .map(SnapshotV2::upgrade)
+                    .map_err(|err| SnapshotError::Malformed(format!("{}", err)))
+            }
+            3 => {
+                decode(&bytes[HEADER_LEN..])
+                    .map_err(|err| SnapshotError::Malformed(format!("{}", err)))
+            }
+            version => Err(SnapshotError::UnsupportedVersion(version)),
+        };
+    }
+
+    let map: HashMap<DocumentId, Document> = if bytes.is_empty() {
+        HashMap::new()
+    } else {
+        try!(decode(bytes).map_err(|err| {
+            SnapshotError::Malformed(format!("Cannot decode the documents: {}", err))
+        }))
+    };
+
+    let records: Vec<LegacyRecord> = if log.is_empty() {
+        Vec::new()
+    } else {
+        try!(decode(log).map_err(|err| {
+            SnapshotError::Malformed(format!("Cannot decode the undo records: {}", err))
+        }))
+    };
+
+    Ok(Snapshot::from_legacy(map, records))
+}
+
+impl Snapshot {
+    /// Converts the documents and the undo records of a snapshot without a header. The
+    /// other state did not exist in this layout, soft delete, the schema and the quota
+    /// were set by the config of each node and have to be set again with their commands.
+    fn from_legacy(map: HashMap<DocumentId, Document>, records: Vec<LegacyRecord>) -> Snapshot {
+        let mut blobs = BlobStore::new();
+
+        let documents = map.into_iter()
+            .map(|(id, document)| {
+                (id,
+                 StoredDocument {
+                     version: document.version,
+                     hash: blobs.insert(document.payload),
+                 })
+            })
+            .collect();
+
+        let log = records.into_iter()
+            .map(|record| record.into_record(&mut blobs))
+            .collect();
+
+        Snapshot {
+            documents: documents,
+            log: log,
+            blobs: blobs,
+            transactions: HashMap::new(),
+            requests: RequestTable::new(MAX_REQUESTS, MAX_REQUEST_BYTES),
+            sequences: HashMap::new(),
+            tombstones: HashMap::new(),
+            moves: HashMap::new(),
+            uploads: HashMap::new(),
+            schema: None,
+            soft_delete: false,
+            quota: Quota::default(),
+            full_nodes: BTreeSet::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::{LegacyRecord, RequestTableV2, SnapshotV1, SnapshotV2, UploadV1};
+    use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+    use blobs::BlobStore;
+    use quota::Quota;
+    use requests::MAX_REQUEST_BYTES;
+    use bincode::serde::serialize as encode;
+    use bincode::SizeLimit;
+    use document::{Document, ActionType};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_decode_legacy_snapshot() {
+        let id = Uuid::new_v4();
+        let mut map = HashMap::new();
+        map.insert(id,
+                   Document {
+                       id: id,
+                       payload: b"test".to_vec(),
+                       version: 2,
+                   });
+
+        let record = LegacyRecord {
+            id: id,
+            path: "volume".to_string(),
+            method: ActionType::Put,
+            old: Some(b"test".to_vec()),
+        };
+
+        let bytes = encode(&map, SizeLimit::Infinite).unwrap();
+        let mut log = encode(&vec![record], SizeLimit::Infinite).unwrap();
+        // the legacy files were overwritten without truncating them
+        log.extend_from_slice(b"stale");
+
+        let snapshot = decode_snapshot(&bytes, &log).unwrap();
+        let document = &snapshot.documents[&id];
+
+        assert_eq!(2, document.version);
+        assert_eq!(Some(&b"test"[..]), snapshot.blobs.get(&document.hash));
+        assert_eq!(Some(document.hash.clone()), snapshot.log[0].get_old_hash());
+        assert_eq!(id, snapshot.log[0].get_id());
+        assert_eq!(ActionType::Put, snapshot.log[0].method);
+        assert_eq!(2, snapshot.blobs.stats().references);
+        assert_eq!(1, snapshot.blobs.stats().blobs);
+
+        assert!(decode_snapshot(&[], &[]).unwrap().documents.is_empty());
+        assert!(decode_snapshot(b"garbage", &[]).is_err());
+
+        let mut future = b"PKSN\x00\x04".to_vec();
+        future.extend_from_slice(&bytes);
+        assert_eq!(Err(SnapshotError::UnsupportedVersion(4)),
+                   decode_snapshot(&future, &[]).map(|_| ()));
+    }
+
+    #[test]
+    fn test_upgrade_version_1() {
+        let mut chunks = BTreeMap::new();
+        chunks.insert(0, b"chunk".to_vec());
+
+        let mut uploads = HashMap::new();
+        uploads.insert(Uuid::new_v4(),
+                       UploadV1 {
+                           document: Uuid::new_v4(),
+                           started: 0,
+                           chunks: chunks,
+                       });
+
+        let mut sequences = HashMap::new();
+        sequences.insert("orders".to_string(), 7);
+
+        let snapshot = SnapshotV1 {
+            documents: HashMap::new(),
+            log: Vec::new(),
+            blobs: BlobStore::new(),
+            transactions: HashMap::new(),
+            requests: RequestTableV2 {
+                capacity: 10000,
+                responses: HashMap::new(),
+                order: VecDeque::new(),
+            },
+            sequences: sequences,
+            tombstones: HashMap::new(),
+            moves: HashMap::new(),
+            uploads: uploads,
+            schema: None,
+            soft_delete: true,
+            quota: Quota::default(),
+            full_nodes: BTreeSet::new(),
+        };
+
+        let mut bytes = b"PKSN\x00\x01".to_vec();
+        bytes.extend(encode(&snapshot, SizeLimit::Infinite).unwrap());
+
+        // the pending uploads are dropped, the other state is kept
+        let snapshot = decode_snapshot(&bytes, &[]).unwrap();
+        assert!(snapshot.uploads.is_empty());
+        assert_eq!(Some(&7), snapshot.sequences.get("orders"));
+        assert!(snapshot.soft_delete);
+    }
+
+    #[test]
+    fn test_upgrade_version_2() {
+        let old = Uuid::new_v4();
+        let new = Uuid::new_v4();
+
+        let mut responses = HashMap::new();
+        responses.insert(old, (None, b"old".to_vec()));
+        responses.insert(new, (None, vec![0; MAX_REQUEST_BYTES]));
+
+        let snapshot = SnapshotV2 {
+            documents: HashMap::new(),
+            log: Vec::new(),
+            blobs: BlobStore::new(),
+            transactions: HashMap::new(),
+            requests: RequestTableV2 {
+                capacity: 10000,
+                responses: responses,
+                order: vec![old, new].into_iter().collect(),
+            },
+            sequences: HashMap::new(),
+            tombstones: HashMap::new(),
+            moves: HashMap::new(),
+            uploads: HashMap::new(),
+            schema: None,
+            soft_delete: false,
+            quota: Quota::default(),
+            full_nodes: BTreeSet::new(),
+        };
+
+        let mut bytes = b"PKSN\x00\x02".to_vec();
+        bytes.extend(encode(&snapshot, SizeLimit::Infinite).unwrap());
+
+        // the oldest responses which exceed the size limit are dropped
+        let snapshot = decode_snapshot(&bytes, &[]).unwrap();
+        assert!(snapshot.requests.get(&old).is_none());
+        assert!(snapshot.requests.get(&new).is_some());
+        assert_eq!(MAX_REQUEST_BYTES, snapshot.requests.bytes());
+    }
+}
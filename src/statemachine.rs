@@ -2,26 +2,75 @@ use document::*;
 use raft::state_machine;
 
 use bincode::serde::serialize as encode;
-use bincode::serde::deserialize as decode;
 use bincode::SizeLimit;
 
+use std::cmp;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::io::Write;
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind};
 use std::path::Path;
 
 use handler::Message;
+use wire::{self, WireError};
 use document::DocumentId;
-use std::collections::HashMap;
+use transaction::{Transaction, TransactionInfo};
+use requests::{RequestTable, MAX_REQUESTS, MAX_REQUEST_BYTES};
+use snapshot::{Snapshot, encode_snapshot, decode_snapshot};
+use counter;
+use delta::Delta;
+use upload::{self, Upload, UploadId};
+use blobs::{BlobStore, BlobStats, Hash};
+use schema::Schema;
+use quota::{Quota, Usage};
+use storage::{self, StorageState};
+use std::collections::{HashMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+use raft::ServerId;
+use raft::TransactionId;
+
+/// The session of a move. No transaction has the nil id
+const MOVE_SESSION: &'static str = "00000000-0000-0000-0000-000000000000";
 
 #[derive(Debug,Clone)]
 pub struct DocumentStateMachine {
-    log: Vec<DocumentRecord>,
-    map: HashMap<DocumentId, Document>,
+    map: HashMap<DocumentId, StoredDocument>,
+    /// The payloads of the documents, tombstones and undo records. Each of them holds a
+    /// reference to its payload. The undo records are kept by their open transaction and
+    /// release their reference when it is undone or committed.
+    blobs: BlobStore,
+    transactions: HashMap<TransactionId, Transaction>,
+    requests: RequestTable,
+    /// The last value of each sequence
+    sequences: HashMap<String, u64>,
+    /// The removed documents if soft delete is enabled
+    tombstones: HashMap<DocumentId, Tombstone>,
+    /// Keeps removed documents as tombstones. It is set by `Message::SetSoftDelete`
+    soft_delete: bool,
+    /// The schema which every payload has to match
+    schema: Option<Schema>,
+    /// The limits of the log
+    quota: Quota,
+    /// The nodes whose volume ran out of space. Writes are rejected until it is empty
+    full_nodes: BTreeSet<String>,
+    /// The watermark of free bytes of the volume of this node
+    min_free_space: Option<u64>,
+    /// The documents which are being moved to another log
+    moves: HashMap<DocumentId, PendingMove>,
+    /// The chunked uploads which were not committed yet
+    uploads: HashMap<UploadId, Upload>,
     volume: String,
-    transaction_offset: usize,
+    /// The address of this node and its peers. They are returned to clients for the
+    /// discovery of the cluster
+    cluster: Option<(SocketAddr, Arc<RwLock<HashMap<ServerId, SocketAddr>>>)>,
+    /// Unix timestamp in seconds of the newest heartbeat which this replica applied. Every
+    /// command which was committed before it was applied too. It is not part of the
+    /// snapshot, so a restarted replica is unsynced until the next heartbeat.
+    synced: u64,
 }
 
 impl DocumentStateMachine {
@@ -30,8 +79,20 @@ impl DocumentStateMachine {
         let s = DocumentStateMachine {
             volume: volume.to_string(),
             map: HashMap::new(),
-            log: Vec::new(),
-            transaction_offset: 0,
+            blobs: BlobStore::new(),
+            transactions: HashMap::new(),
+            requests: RequestTable::new(MAX_REQUESTS, MAX_REQUEST_BYTES),
+            sequences: HashMap::new(),
+            tombstones: HashMap::new(),
+            soft_delete: false,
+            schema: None,
+            quota: Quota::default(),
+            full_nodes: BTreeSet::new(),
+            min_free_space: None,
+            moves: HashMap::new(),
+            uploads: HashMap::new(),
+            cluster: None,
+            synced: 0,
         };
 
         if !Self::check_if_volume_exists(volume) {
@@ -50,207 +111,2218 @@ impl DocumentStateMachine {
         ::std::fs::create_dir_all(&self.volume)
     }
 
-    pub fn get_documents(&self) -> Vec<DocumentId> {
-        self.map.keys().into_iter().cloned().collect()
+    /// Keeps removed documents as tombstones, so they can be restored with `undelete`.
+    /// Disabling it keeps the existing tombstones until they are purged.
+    fn replicate_soft_delete(&mut self, soft_delete: bool) -> Vec<u8> {
+        self.soft_delete = soft_delete;
+
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
+    }
+
+    fn set_schema(&mut self, source: Option<String>) -> Result<(), String> {
+        self.schema = match source {
+            Some(source) => Some(try!(Schema::parse(source))),
+            None => None,
+        };
+
+        Ok(())
+    }
+
+    /// Returns the JSON text of the schema of the log
+    pub fn get_schema(&self) -> Option<String> {
+        self.schema.as_ref().map(|schema| schema.source().to_string())
+    }
+
+    /// Rejects the payload if it does not match the schema of the log
+    fn validate(&self, id: DocumentId, payload: &[u8]) -> Result<(), DocumentError> {
+        let violations = match self.schema {
+            Some(ref schema) => schema.validate(payload),
+            None => return Ok(()),
+        };
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(DocumentError::InvalidDocument(id, violations))
+        }
+    }
+
+    /// Sets the watermark of free bytes of the volume. It is only reported in the
+    /// `StorageState`, the volume is watched by `storage::watch`.
+    pub fn set_min_free_space(&mut self, min_free_space: Option<u64>) {
+        self.min_free_space = min_free_space;
+    }
+
+    /// Returns true if writes are rejected because a node ran out of space
+    pub fn is_read_only(&self) -> bool {
+        !self.full_nodes.is_empty()
     }
 
-    fn post(&mut self, document: Document) -> Vec<u8> {
-        let record = DocumentRecord::new(document.id,
-                                         format!("{}/{}", &self.volume, &document.id.to_string()),
-                                         ActionType::Post);
+    /// Returns true if the node reported that its volume is below the watermark
+    pub fn is_node_full(&self, node: &str) -> bool {
+        self.full_nodes.contains(node)
+    }
 
-        self.log.push(record);
-        self.map.insert(document.id, document.clone());
+    pub fn get_storage_state(&self) -> StorageState {
+        StorageState {
+            read_only: self.is_read_only(),
+            full_nodes: self.full_nodes.iter().cloned().collect(),
+            min_free_space: self.min_free_space,
+            free_space: storage::available_space(&self.volume).ok(),
+        }
+    }
 
-        encode(&document, SizeLimit::Infinite).unwrap()
+    /// Sets the address of this node and its peers
+    pub fn set_cluster(&mut self,
+                       node: SocketAddr,
+                       peers: Arc<RwLock<HashMap<ServerId, SocketAddr>>>) {
+        self.cluster = Some((node, peers));
     }
 
-    fn remove(&mut self, id: DocumentId) -> Vec<u8> {
-        let mut record =
-            DocumentRecord::new(id, format!("{}/{}", &self.volume, &id), ActionType::Remove);
+    /// Returns the addresses of all nodes of the cluster, ordered by address. It is empty
+    /// if the cluster was not set
+    pub fn get_peers(&self) -> Vec<SocketAddr> {
+        let mut nodes = match self.cluster {
+            Some((node, ref peers)) => {
+                let mut nodes = vec![node];
 
-        {
-            let old_document = &self.map[&id];
+                if let Ok(peers) = peers.read() {
+                    nodes.extend(peers.values().cloned());
+                }
 
-            record.set_old_payload(old_document.payload.clone());
-            self.log.push(record);
+                nodes
+            }
+            None => Vec::new(),
+        };
+
+        nodes.sort_by_key(|addr| addr.to_string());
+        nodes.dedup();
+        nodes
+    }
+
+    fn set_storage_full(&mut self, node: String, full: bool) -> Vec<u8> {
+        if full {
+            self.full_nodes.insert(node);
+        } else {
+            self.full_nodes.remove(&node);
         }
-        self.map.remove(&id);
 
-        Vec::new()
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
     }
 
-    fn put(&mut self, id: DocumentId, new_payload: Vec<u8>) -> Vec<u8> {
-        let mut record =
-            DocumentRecord::new(id, format!("{}/{}", &self.volume, &id), ActionType::Put);
+    /// Replaces the limits of the log. Existing documents which exceed them are kept,
+    /// only new writes are rejected
+    fn replicate_quota(&mut self, quota: Quota) -> Vec<u8> {
+        self.quota = quota;
+
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
+    }
 
-        {
-            let old_document = &self.map[&id];
+    /// Returns the current usage of the log together with its limits
+    pub fn get_usage(&self) -> Usage {
+        Usage {
+            documents: self.map.len() as u64,
+            total_bytes: self.map
+                .values()
+                .map(|document| self.payload(document).len() as u64)
+                .sum(),
+            staged_bytes: self.uploads.values().map(|pending| pending.size()).sum(),
+            quota: self.quota.clone(),
+        }
+    }
 
-            record.set_old_payload(old_document.payload.clone());
+    /// Rejects a write which changes a payload of `old_size` bytes to `new_size` bytes if it
+    /// would exceed the quota. `old_size` is `None` if the write creates a new document.
+    fn check_quota(&self,
+                   id: DocumentId,
+                   old_size: Option<usize>,
+                   new_size: usize)
+                   -> Result<(), DocumentError> {
+        if self.quota == Quota::default() {
+            return Ok(());
+        }
 
-            assert!(record.get_old_payload().is_some());
+        self.quota
+            .check(&self.get_usage(),
+                   old_size.map(|size| size as u64),
+                   new_size as u64)
+            .map_err(|limit| DocumentError::QuotaExceeded(id, limit))
+    }
 
-            self.log.push(record);
+    /// Like `check_quota` for a write with the chunks of the `pending` upload. They are not
+    /// counted as staged bytes, because the write replaces them.
+    fn check_upload_quota(&self,
+                          pending: &Upload,
+                          old_size: Option<u64>,
+                          new_size: u64)
+                          -> Result<(), DocumentError> {
+        if self.quota == Quota::default() {
+            return Ok(());
         }
 
-        let mut document = self.map.get_mut(&id).unwrap().clone();
-        document.payload = new_payload;
+        let mut usage = self.get_usage();
+        usage.staged_bytes -= pending.size();
 
+        self.quota
+            .check(&usage, old_size, new_size)
+            .map_err(|limit| DocumentError::QuotaExceeded(pending.document, limit))
+    }
+
+    /// Replaces the schema of the log. `None` removes it
+    fn replicate_schema(&mut self, source: Option<String>) -> Vec<u8> {
+        let result = self.set_schema(source).map_err(DocumentError::InvalidSchema);
 
-        self.map.remove(&id);
-        self.map.insert(id, document.clone());
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
 
-        encode(&document, SizeLimit::Infinite).unwrap()
+    /// Returns the replicated state of the log
+    fn to_snapshot(&self) -> Snapshot {
+        Snapshot {
+            documents: self.map.clone(),
+            log: Vec::new(),
+            blobs: self.blobs.clone(),
+            transactions: self.transactions.clone(),
+            requests: self.requests.clone(),
+            sequences: self.sequences.clone(),
+            tombstones: self.tombstones.clone(),
+            moves: self.moves.clone(),
+            uploads: self.uploads.clone(),
+            schema: self.get_schema(),
+            soft_delete: self.soft_delete,
+            quota: self.quota.clone(),
+            full_nodes: self.full_nodes.clone(),
+        }
     }
 
+    /// Replaces the replicated state of the log with the snapshot
+    fn restore(&mut self, snapshot: Snapshot) {
+        self.map = snapshot.documents;
+        self.blobs = snapshot.blobs;
+        self.transactions = snapshot.transactions;
 
-    fn find_by_id(&self, id: DocumentId) -> DocumentRecord {
-        for s in self.log.iter().rev().skip(self.transaction_offset) {
-            if s.get_id() == id {
-                return s.clone();
+        // Older snapshots kept every undo record in the log, the records of the open
+        // transactions shared their reference with their copy in the log
+        if !snapshot.log.is_empty() {
+            for record in &snapshot.log {
+                if let Some(hash) = record.get_old_hash() {
+                    self.blobs.release(&hash);
+                }
+            }
+            for transaction in self.transactions.values() {
+                for hash in transaction.get_old_hashes() {
+                    self.blobs.retain(&hash);
+                }
             }
         }
+        self.requests = snapshot.requests;
+        self.sequences = snapshot.sequences;
+        self.tombstones = snapshot.tombstones;
+        self.moves = snapshot.moves;
+        self.uploads = snapshot.uploads;
+        self.full_nodes = snapshot.full_nodes;
+        self.soft_delete = snapshot.soft_delete;
+        self.quota = snapshot.quota;
 
-        panic!("Reverting failed")
+        // the schema was validated when it was set
+        if let Err(reason) = self.set_schema(snapshot.schema) {
+            panic!("The schema of the snapshot of {} is invalid: {}", self.volume, reason);
+        }
     }
 
-    pub fn get_snapshot_map(&self) -> Result<Vec<u8>, IoError> {
-        let mut fs = try!(File::open(&format!("./{}/snapshot_map", self.volume)));
-        let mut buffer = Vec::new();
+    /// Returns how the payloads of the documents and of the undo records are stored
+    pub fn get_blob_stats(&self) -> BlobStats {
+        self.blobs.stats()
+    }
 
-        try!(fs.read_to_end(&mut buffer));
+    /// Returns the payload of a document from the `BlobStore`
+    fn payload(&self, document: &StoredDocument) -> &[u8] {
+        match self.blobs.get(&document.hash) {
+            Some(payload) => payload,
+            None => panic!("The payload {} is missing in {}", document.hash, self.volume),
+        }
+    }
 
-        Ok(buffer)
+    fn load(&self, id: DocumentId, document: &StoredDocument) -> Document {
+        Document {
+            id: id,
+            payload: self.payload(document).to_vec(),
+            version: document.version,
+        }
     }
 
-    pub fn get_snapshot_log(&self) -> Result<Vec<u8>, IoError> {
-        let mut fs = try!(File::open(&format!("./{}/snapshot_log", self.volume)));
-        let mut buffer = Vec::new();
+    /// Points an existing document to another stored payload and increases its version.
+    /// Returns the hash of the old payload together with the reference of the document.
+    fn set_hash(&mut self, id: DocumentId, hash: Hash) -> Hash {
+        let document = self.map.get_mut(&id).expect("The document of the new payload is missing");
+        document.version += 1;
 
-        try!(fs.read_to_end(&mut buffer));
+        ::std::mem::replace(&mut document.hash, hash)
+    }
 
-        Ok(buffer)
+    /// Stores the new payload of an existing document, see `set_hash`
+    fn replace_payload(&mut self, id: DocumentId, payload: Vec<u8>) -> Hash {
+        let hash = self.blobs.insert(payload);
+
+        self.set_hash(id, hash)
     }
-}
 
-impl state_machine::StateMachine for DocumentStateMachine {
-    fn apply(&mut self, new_value: &[u8]) -> Vec<u8> {
-        let message = decode(&new_value).unwrap();
+    pub fn get_documents(&self) -> Vec<DocumentId> {
+        self.map.keys().into_iter().cloned().collect()
+    }
+
+    /// Returns the removed documents which were not purged yet
+    pub fn get_tombstones(&self) -> Vec<(DocumentId, Deletion)> {
+        self.tombstones
+            .iter()
+            .map(|(id, tombstone)| (*id, tombstone.deletion.clone()))
+            .collect()
+    }
+
+    /// Returns the local copy of a document. It might miss the latest writes when this
+    /// replica is not the leader.
+    pub fn get_document(&self, id: DocumentId) -> Option<Document> {
+        self.map.get(&id).map(|document| self.load(id, document))
+    }
+
+    /// Returns the version of a document. Every write of the payload increases it
+    pub fn get_version(&self, id: DocumentId) -> Result<usize, DocumentError> {
+        self.map.get(&id).map(|document| document.version).ok_or(DocumentError::NotFound(id))
+    }
 
-        let response = match message {
-            Message::Get(_) => self.query(new_value), // delegate to query when propose
-            Message::Post(document) => self.post(document),
-            Message::Remove(id) => self.remove(id),
-            Message::Put(id, new_payload) => self.put(id, new_payload),
+    /// Returns the documents with the given ids and the ids which do not exist
+    pub fn get_many(&self, ids: &[DocumentId]) -> MultiGet {
+        let mut result = MultiGet {
+            documents: Vec::new(),
+            missing: Vec::new(),
         };
 
-        self.snapshot();
+        for id in ids {
+            match self.map.get(id) {
+                Some(document) => result.documents.push(self.load(*id, document)),
+                None => result.missing.push(*id),
+            }
+        }
 
-        response
+        result
     }
 
-    fn query(&self, query: &[u8]) -> Vec<u8> {
-        let message = decode(&query).unwrap();
+    /// Returns the maximum staleness in seconds of the local copies at the unix timestamp
+    /// `now`. It is the age of the newest heartbeat this replica applied, so the copies
+    /// miss at most the writes which were committed in this time.
+    pub fn get_staleness(&self, now: u64) -> u64 {
+        now.saturating_sub(self.synced)
+    }
 
-        let response = match message {
-            Message::Get(id) => {
-                let doc = match self.map.get(&id) {
-                    Some(doc) => doc,
-                    None => {
-                        println!("Cannot find document");
-                        return Vec::new();
-                    }
+    /// Marks the replica as synced up to the time `sent` of a heartbeat
+    fn heartbeat(&mut self, sent: u64) -> Vec<u8> {
+        self.synced = cmp::max(self.synced, sent);
+
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
+    }
+
+    /// Fails if the document was written by another open transaction than `session`
+    fn check_conflict(&self, session: TransactionId, id: DocumentId) -> Result<(), DocumentError> {
+        if self.moves.contains_key(&id) {
+            return Err(DocumentError::Moving(id));
+        }
+
+        match self.transactions
+            .iter()
+            .find(|&(tid, transaction)| *tid != session && transaction.has_written(&id)) {
+            Some((tid, _)) => Err(DocumentError::Conflict(id, *tid)),
+            None => Ok(()),
+        }
+    }
+
+    /// Saves the undo record in the open transaction of `session`. A write outside of a
+    /// transaction is never undone, so its record releases the old payload right away.
+    fn record(&mut self, session: TransactionId, record: DocumentRecord) {
+        if let Some(transaction) = self.transactions.get_mut(&session) {
+            transaction.push_record(record);
+            return;
+        }
+
+        if let Some(hash) = record.get_old_hash() {
+            self.blobs.release(&hash);
+        }
+    }
+
+    fn post(&mut self, session: TransactionId, document: Document) -> Vec<u8> {
+        let result = self.check_conflict(session, document.id).and_then(|_| {
+            if self.map.contains_key(&document.id) {
+                return Err(DocumentError::AlreadyExists(document.id));
+            }
+
+            try!(self.validate(document.id, &document.payload));
+            try!(self.check_quota(document.id, None, document.payload.len()));
+
+            let record = DocumentRecord::new(document.id,
+                                             format!("{}/{}", &self.volume, &document.id),
+                                             ActionType::Post);
+
+            self.record(session, record);
+
+            let stored = StoredDocument {
+                version: document.version,
+                hash: self.blobs.insert(document.payload.clone()),
+            };
+            self.map.insert(document.id, stored);
+
+            Ok(document)
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    fn remove(&mut self,
+              session: TransactionId,
+              id: DocumentId,
+              author: String,
+              deleted_at: u64)
+              -> Vec<u8> {
+        let result = self.check_conflict(session, id).and_then(|_| {
+            let old_document = match self.map.remove(&id) {
+                Some(document) => document,
+                None => return Err(DocumentError::NotFound(id)),
+            };
+
+            // the undo record takes over the reference of the document
+            let mut record =
+                DocumentRecord::new(id, format!("{}/{}", &self.volume, &id), ActionType::Remove);
+            record.set_old_hash(old_document.hash.clone());
+
+            self.record(session, record);
+
+            if self.soft_delete {
+                self.blobs.retain(&old_document.hash);
+
+                let tombstone = Tombstone {
+                    document: old_document,
+                    deletion: Deletion {
+                        author: author,
+                        deleted_at: deleted_at,
+                    },
                 };
 
-                encode(&doc, SizeLimit::Infinite).unwrap()
+                self.tombstones.insert(id, tombstone);
             }
-            _ => {
-                let response = encode(&"Wrong usage of .query()", SizeLimit::Infinite);
 
-                response.unwrap()
+            Ok(())
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    fn put(&mut self, session: TransactionId, id: DocumentId, new_payload: Vec<u8>) -> Vec<u8> {
+        let result = self.check_conflict(session, id).and_then(|_| {
+            let old_size = match self.map.get(&id) {
+                Some(document) => self.payload(document).len(),
+                None => return Err(DocumentError::NotFound(id)),
+            };
+
+            try!(self.validate(id, &new_payload));
+            try!(self.check_quota(id, Some(old_size), new_payload.len()));
+
+            let mut record =
+                DocumentRecord::new(id, format!("{}/{}", &self.volume, &id), ActionType::Put);
+            record.set_old_hash(self.replace_payload(id, new_payload));
+
+            self.record(session, record);
+
+            Ok(self.get_document(id).unwrap())
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    /// Changes a byte range of the payload. Only the overwritten bytes are kept for undo.
+    /// Returns the new length of the payload.
+    fn patch(&mut self, session: TransactionId, id: DocumentId, delta: Delta) -> Vec<u8> {
+        let result = self.check_conflict(session, id).and_then(|_| {
+            let mut payload = match self.map.get(&id) {
+                Some(document) => self.payload(document).to_vec(),
+                None => return Err(DocumentError::NotFound(id)),
+            };
+            let old_size = payload.len();
+
+            let undo_deltas = try!(delta.apply(id, &mut payload));
+            try!(self.validate(id, &payload));
+            try!(self.check_quota(id, Some(old_size), payload.len()));
+
+            let length = payload.len() as u64;
+
+            // the undo record keeps the deltas instead of a reference to the old payload
+            let old_hash = self.replace_payload(id, payload);
+            self.blobs.release(&old_hash);
+
+            let mut record =
+                DocumentRecord::new(id, format!("{}/{}", &self.volume, &id), ActionType::Patch);
+            record.set_undo_deltas(undo_deltas);
+
+            self.record(session, record);
+
+            Ok(length)
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    /// Starts a chunked upload of the document at the time `started`. Beginning a pending
+    /// upload again has no effect. The uploads which expired at this time are dropped, an
+    /// upload without a timestamp neither expires nor drops the others.
+    fn begin_upload(&mut self, upload: UploadId, id: DocumentId, started: Option<u64>) -> Vec<u8> {
+        if let Some(now) = started {
+            let expired: Vec<UploadId> = self.uploads
+                .iter()
+                .filter(|&(_, pending)| pending.is_expired(now))
+                .map(|(upload, _)| *upload)
+                .collect();
+
+            for upload in expired {
+                self.drop_upload(upload);
+            }
+        }
+
+        self.uploads.entry(upload).or_insert_with(|| Upload::new(id, started));
+
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
+    }
+
+    /// Stages a chunk of a pending upload in the volume. The chunks count towards the quota
+    /// of the log until the upload is committed or aborted.
+    fn upload_chunk(&mut self, upload: UploadId, index: u64, bytes: Vec<u8>) -> Vec<u8> {
+        let result = match self.uploads.get(&upload) {
+            Some(pending) => {
+                // the staged chunks are checked like a new payload, the size of the
+                // document is only known when the upload is committed
+                let size = pending.size() - pending.chunk_size(index) + bytes.len() as u64;
+                self.check_upload_quota(pending, Some(0), size)
             }
+            None => Err(DocumentError::UploadNotFound(upload)),
         };
 
-        response
+        let result = result.map(|_| {
+            // the chunk is counted even if it cannot be written, so the replicas keep the
+            // same state. Committing the upload fails on this node in that case.
+            if let Err(err) = upload::stage_chunk(&self.volume, upload, index, &bytes) {
+                println!("Unable to stage the chunk {} of the upload {} in {}: {}",
+                         index,
+                         upload,
+                         self.volume,
+                         err);
+            }
+
+            self.uploads.get_mut(&upload).unwrap().insert(index, bytes.len() as u64)
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
     }
 
-    fn snapshot(&self) -> (Vec<u8>, Vec<u8>) {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&format!("{}/snapshot_map", self.volume))
-            .expect("Unable to create snapshot file");
+    /// Adds a reference to the payload of a complete upload and returns its hash. The
+    /// staged chunks are only read into the `BlobStore` if it has no equal payload yet.
+    fn store_upload(&mut self, upload: UploadId, pending: &Upload) -> Hash {
+        let hash = match pending.hash(&self.volume, upload) {
+            Ok(hash) => hash,
+            Err(err) => {
+                panic!("The chunks of the upload {} are missing in {}: {}",
+                       upload,
+                       self.volume,
+                       err)
+            }
+        };
 
-        let map = encode(&self.map, SizeLimit::Infinite).unwrap();
-        file.write_all(&map)
-            .expect("Unable to write to the snapshot file");
+        if self.blobs.contains(&hash) {
+            self.blobs.retain(&hash);
+            return hash;
+        }
 
-        let mut file = OpenOptions::new()
-            .read(false)
-            .write(true)
-            .create(true)
-            .open(&format!("{}/snapshot_log", self.volume))
-            .expect("Unable to create snapshot file");
+        match pending.read(&self.volume, upload) {
+            Ok(payload) => self.blobs.insert(payload),
+            Err(err) => {
+                panic!("The chunks of the upload {} are missing in {}: {}",
+                       upload,
+                       self.volume,
+                       err)
+            }
+        }
+    }
 
-        let log = encode(&self.log, SizeLimit::Infinite).unwrap();
+    /// Checks a pending upload before it is committed and stores its payload. Returns the
+    /// hash of the payload with a new reference.
+    fn prepare_upload(&mut self,
+                      session: TransactionId,
+                      upload: UploadId,
+                      pending: &Upload)
+                      -> Result<Hash, DocumentError> {
+        try!(self.check_conflict(session, pending.document));
+        try!(pending.check_complete(upload));
 
-        file.write_all(&log)
-            .expect("Unable to write to the snapshot file");
+        let old_size = self.map
+            .get(&pending.document)
+            .map(|document| self.payload(document).len() as u64);
+        try!(self.check_upload_quota(pending, old_size, pending.size()));
 
-        (map, log)
+        let hash = self.store_upload(upload, pending);
+        let result = self.validate(pending.document,
+                                   self.blobs.get(&hash).expect("The stored upload is missing"));
+
+        if let Err(err) = result {
+            self.blobs.release(&hash);
+            return Err(err);
+        }
+
+        Ok(hash)
     }
 
-    fn restore_snapshot(&mut self, snap_map: Vec<u8>, snap_log: Vec<u8>) {
-        let map: HashMap<DocumentId, Document> = match decode(&snap_map) {
-            Ok(m) => m,
-            Err(_) => HashMap::new(),
+    /// Creates or replaces the document with the staged chunks of an upload. Returns the
+    /// length of the payload. The chunks are dropped, so a reverted commit has to be
+    /// uploaded again.
+    fn commit_upload(&mut self, session: TransactionId, upload: UploadId) -> Vec<u8> {
+        let pending = match self.uploads.get(&upload) {
+            Some(pending) => pending.clone(),
+            None => {
+                let result = Err::<u64, DocumentError>(DocumentError::UploadNotFound(upload));
+                return encode(&result, SizeLimit::Infinite).unwrap();
+            }
         };
 
-        self.map = map;
+        let result = self.prepare_upload(session, upload, &pending).map(|hash| {
+            self.drop_upload(upload);
+
+            let id = pending.document;
+            let path = format!("{}/{}", &self.volume, &id);
+
+            let record = if self.map.contains_key(&id) {
+                let mut record = DocumentRecord::new(id, path, ActionType::Put);
+                record.set_old_hash(self.set_hash(id, hash));
+                record
+            } else {
+                let document = StoredDocument {
+                    version: 1,
+                    hash: hash,
+                };
+
+                self.map.insert(id, document);
+                DocumentRecord::new(id, path, ActionType::Post)
+            };
+
+            self.record(session, record);
+
+            pending.size()
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    fn abort_upload(&mut self, upload: UploadId) -> Vec<u8> {
+        self.drop_upload(upload);
+
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
+    }
+
+    /// Forgets a pending upload and deletes its staged chunks
+    fn drop_upload(&mut self, upload: UploadId) {
+        self.uploads.remove(&upload);
+
+        if let Err(err) = upload::remove_chunks(&self.volume, upload) {
+            println!("Unable to delete the chunks of the upload {} in {}: {}",
+                     upload,
+                     self.volume,
+                     err);
+        }
+    }
 
-        let log: Vec<DocumentRecord> = match decode(&snap_log) {
-            Ok(m) => m,
-            Err(_) => Vec::new(),
+    /// Returns at most `length` bytes of the payload from the `offset`
+    fn get_range(&self,
+                 id: DocumentId,
+                 offset: u64,
+                 length: u64)
+                 -> Result<Vec<u8>, DocumentError> {
+        let payload = match self.map.get(&id) {
+            Some(document) => self.payload(document),
+            None => return Err(DocumentError::NotFound(id)),
         };
 
-        self.log = log;
+        if offset > payload.len() as u64 {
+            return Err(DocumentError::InvalidRange(id));
+        }
+
+        let start = offset as usize;
+        let end = ::std::cmp::min(payload.len() as u64, offset.saturating_add(length)) as usize;
+
+        Ok(payload[start..end].to_vec())
     }
 
-    #[allow(unused_variables)]
-    fn revert(&mut self, command: &[u8]) {
-        let message = decode(&command).unwrap();
+    /// Restores a removed document from its tombstone
+    fn undelete(&mut self, session: TransactionId, id: DocumentId) -> Vec<u8> {
+        let result = self.check_conflict(session, id).and_then(|_| {
+            if self.map.contains_key(&id) {
+                return Err(DocumentError::AlreadyExists(id));
+            }
 
-        match message {
-            Message::Get(_) => return,
-            Message::Post(document) => {
-                self.remove(document.id);
-            }
-            Message::Remove(id) => {
-                let record = self.find_by_id(id);
-                let document = Document {
-                    id: record.get_id(),
-                    payload: record.get_old_payload().unwrap(),
-                    version: 0,
-                };
+            match self.tombstones.get(&id) {
+                Some(tombstone) => {
+                    try!(self.check_quota(id, None, self.payload(&tombstone.document).len()))
+                }
+                None => return Err(DocumentError::NotFound(id)),
+            }
+
+            let tombstone = self.tombstones.remove(&id).unwrap();
+
+            let mut record =
+                DocumentRecord::new(id, format!("{}/{}", &self.volume, &id), ActionType::Undelete);
+            record.set_deletion(tombstone.deletion);
+
+            self.record(session, record);
+            self.map.insert(id, tombstone.document);
+
+            Ok(())
+        });
 
-                self.post(document);
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    /// Removes the tombstones which are older than `retention` seconds at the time `now`.
+    /// Returns the ids of the purged documents. Purging cannot be reverted.
+    fn purge(&mut self, retention: u64, now: u64) -> Vec<u8> {
+        let mut expired: Vec<DocumentId> = self.tombstones
+            .iter()
+            .filter(|&(_, tombstone)| tombstone.deletion.deleted_at.saturating_add(retention) < now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort();
+
+        for id in &expired {
+            if let Some(tombstone) = self.tombstones.remove(id) {
+                self.blobs.release(&tombstone.document.hash);
+            }
+        }
+
+        let result: Result<Vec<DocumentId>, DocumentError> = Ok(expired);
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    /// Locks a document for a move to another log and returns it together with the move.
+    /// If the document is already being moved to the same log, the pending move is returned,
+    /// so an interrupted move can be resumed.
+    fn prepare_move(&mut self, id: DocumentId, pending: PendingMove) -> Vec<u8> {
+        let result = match self.moves.get(&id).cloned() {
+            Some(existing) if existing.target == pending.target => Ok(existing),
+            Some(_) => Err(DocumentError::Moving(id)),
+            None => {
+                // a move belongs to no transaction, so any open transaction which wrote
+                // the document conflicts with it
+                let session = TransactionId::from(MOVE_SESSION).unwrap();
+
+                self.check_conflict(session, id).and_then(|_| {
+                    if !self.map.contains_key(&id) {
+                        return Err(DocumentError::NotFound(id));
+                    }
+
+                    self.moves.insert(id, pending.clone());
+
+                    Ok(pending)
+                })
             }
-            Message::Put(id, new_payload) => {
-                let record = self.find_by_id(id);
+        };
+
+        let result = result.map(|pending| (pending, self.get_document(id).unwrap()));
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    /// Removes a moved document after it was inserted in the target log. Completing a move
+    /// which is not pending anymore has no effect.
+    fn complete_move(&mut self, id: DocumentId, move_id: Uuid) -> Vec<u8> {
+        match self.moves.get(&id) {
+            Some(pending) if pending.id == move_id => {}
+            _ => return encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap(),
+        }
+
+        self.moves.remove(&id);
+
+        // a move is never undone, so no undo record is kept
+        if let Some(old_document) = self.map.remove(&id) {
+            self.blobs.release(&old_document.hash);
+        }
+
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
+    }
+
+    /// Unlocks a document whose move failed before it was inserted in the target log
+    fn abort_move(&mut self, id: DocumentId, move_id: Uuid) -> Vec<u8> {
+        let pending = match self.moves.get(&id) {
+            Some(pending) => pending.id == move_id,
+            None => false,
+        };
+
+        if pending {
+            self.moves.remove(&id);
+        }
+
+        encode(&Ok::<(), DocumentError>(()), SizeLimit::Infinite).unwrap()
+    }
+
+    /// Returns the summaries of all open transactions
+    pub fn get_transactions(&self) -> Vec<TransactionInfo> {
+        let now = timestamp();
+
+        self.transactions
+            .iter()
+            .map(|(id, transaction)| transaction.info(*id, now))
+            .collect()
+    }
+
+    /// Returns the summary of an open transaction
+    pub fn get_transaction(&self, session: TransactionId) -> Option<TransactionInfo> {
+        self.transactions.get(&session).map(|transaction| transaction.info(session, timestamp()))
+    }
+
+    /// Adds `delta` to the numeric payload or the numeric JSON `field` of the document
+    fn increment(&mut self,
+                 session: TransactionId,
+                 id: DocumentId,
+                 field: Option<String>,
+                 delta: i64)
+                 -> Vec<u8> {
+        let result = self.check_conflict(session, id).and_then(|_| {
+            let (payload, value, old_size) = {
+                let old_payload = match self.map.get(&id) {
+                    Some(document) => self.payload(document),
+                    None => return Err(DocumentError::NotFound(id)),
+                };
+
+                let (payload, value) = try!(counter::increment(id,
+                                                               old_payload,
+                                                               field.as_ref().map(|f| f.as_str()),
+                                                               delta));
+
+                (payload, value, old_payload.len())
+            };
+
+            try!(self.validate(id, &payload));
+            try!(self.check_quota(id, Some(old_size), payload.len()));
+
+            let mut record =
+                DocumentRecord::new(id, format!("{}/{}", &self.volume, &id), ActionType::Put);
+            record.set_old_hash(self.replace_payload(id, payload));
+
+            self.record(session, record);
+
+            Ok(value)
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    /// Returns the next value of the sequence. Sequences start at 1. They are not reverted
+    /// by a rollback, so a value is never handed out twice.
+    fn next_sequence(&mut self, name: String) -> Vec<u8> {
+        let value = {
+            let sequence = self.sequences.entry(name).or_insert(0);
+            *sequence += 1;
+            *sequence
+        };
+
+        let result: Result<u64, DocumentError> = Ok(value);
 
-                let new_payload = record.get_old_payload().unwrap();
-                self.put(id, new_payload);
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
+
+    /// Inserts the document or replaces the payload if it already exists
+    fn upsert(&mut self, session: TransactionId, document: Document) -> Vec<u8> {
+        if self.map.contains_key(&document.id) {
+            self.put(session, document.id, document.payload)
+        } else {
+            self.post(session, document)
+        }
+    }
+
+    fn begin_transaction(&mut self,
+                         session: TransactionId,
+                         author: String,
+                         started: u64)
+                         -> Vec<u8> {
+        self.transactions.insert(session, Transaction::new(author, started));
+
+        Vec::new()
+    }
+
+    /// Drops the undo records of a transaction. It is applied after raft ended the
+    /// transaction, until then a rollback of raft reverts all of its writes.
+    fn commit_transaction(&mut self, session: TransactionId) -> Vec<u8> {
+        self.end_transaction(session);
+
+        Vec::new()
+    }
+
+    /// Removes a transaction and releases the old payloads of its undo records
+    fn end_transaction(&mut self, session: TransactionId) {
+        if let Some(transaction) = self.transactions.remove(&session) {
+            for hash in transaction.get_old_hashes() {
+                self.blobs.release(&hash);
             }
         }
+    }
+
+    fn create_savepoint(&mut self, session: TransactionId, name: String) -> Vec<u8> {
+        let result = match self.transactions.get_mut(&session) {
+            Some(transaction) => {
+                transaction.create_savepoint(name);
+                Ok(())
+            }
+            None => Err(DocumentError::TransactionNotFound(session)),
+        };
 
-        self.transaction_offset += 1;
+        encode(&result, SizeLimit::Infinite).unwrap()
+    }
 
-        self.snapshot();
+    fn rollback_to_savepoint(&mut self, session: TransactionId, name: String) -> Vec<u8> {
+        let records = match self.transactions.get_mut(&session) {
+            Some(transaction) => {
+                transaction.rollback_to_savepoint(&name)
+                    .ok_or(DocumentError::SavepointNotFound(name))
+            }
+            None => Err(DocumentError::TransactionNotFound(session)),
+        };
+
+        let result = records.map(|records| for record in records {
+            self.undo(record);
+        });
+
+        encode(&result, SizeLimit::Infinite).unwrap()
     }
 
-    fn rollback(&mut self) {
-        self.transaction_offset = 0;
+    /// Restores the state before the change of the `record`. The restored document takes
+    /// over the reference of the record to the old payload.
+    fn undo(&mut self, record: DocumentRecord) {
+        let id = record.get_id();
+
+        match record.method {
+            ActionType::Get => {}
+            ActionType::Post => {
+                if let Some(document) = self.map.remove(&id) {
+                    self.blobs.release(&document.hash);
+                }
+            }
+            ActionType::Remove => {
+                let hash = record.get_old_hash().unwrap();
+
+                let document = match self.tombstones.remove(&id) {
+                    // the tombstone holds its own reference
+                    Some(tombstone) => {
+                        self.blobs.release(&hash);
+                        tombstone.document
+                    }
+                    None => {
+                        StoredDocument {
+                            version: 0,
+                            hash: hash,
+                        }
+                    }
+                };
+
+                self.map.insert(id, document);
+            }
+            ActionType::Undelete => {
+                if let Some(document) = self.map.remove(&id) {
+                    match record.get_deletion() {
+                        Some(deletion) => {
+                            let tombstone = Tombstone {
+                                document: document,
+                                deletion: deletion,
+                            };
+
+                            self.tombstones.insert(id, tombstone);
+                        }
+                        None => {
+                            self.blobs.release(&document.hash);
+                        }
+                    }
+                }
+            }
+            // The version is increased by an undo as well, so a copy of the reverted
+            // payload in a client cache never matches the current version
+            ActionType::Put => {
+                let hash = record.get_old_hash().unwrap();
+
+                if self.map.contains_key(&id) {
+                    let current = self.set_hash(id, hash);
+                    self.blobs.release(&current);
+                } else {
+                    self.blobs.release(&hash);
+                }
+            }
+            ActionType::Patch => {
+                let payload = match self.map.get(&id) {
+                    Some(document) => {
+                        let mut payload = self.payload(document).to_vec();
+
+                        for delta in record.get_undo_deltas().unwrap() {
+                            delta.apply(id, &mut payload)
+                                .expect("The undo delta does not fit the payload");
+                        }
+
+                        Some(payload)
+                    }
+                    None => None,
+                };
+
+                if let Some(payload) = payload {
+                    let current = self.replace_payload(id, payload);
+                    self.blobs.release(&current);
+                }
+            }
+        }
+    }
+
+    /// Applies a command and returns the encoded response
+    fn execute(&mut self, message: Message) -> Vec<u8> {
+        if self.is_read_only() && message.is_write() {
+            return encode(&Err::<(), DocumentError>(DocumentError::ReadOnly),
+                          SizeLimit::Infinite)
+                .unwrap();
+        }
+
+        match message {
+            Message::Get(_) |
+            Message::GetMany(_) |
+            Message::GetVersion(_) |
+            Message::GetTransactions |
+            Message::GetTombstones |
+            Message::GetRange(_, _, _) |
+            Message::GetSchema |
+            Message::GetUsage |
+            Message::GetStorageState |
+            Message::GetPeers |
+            Message::GetVersionedRange(_, _, _) |
+            Message::GetMove(_) => self.read(message), // delegate to query when propose
+            Message::Post(session, document) => self.post(session, document),
+            Message::Remove(session, id, author, deleted_at) => {
+                self.remove(session, id, author, deleted_at)
+            }
+            Message::Undelete(session, id) => self.undelete(session, id),
+            Message::Patch(session, id, delta) => self.patch(session, id, delta),
+            Message::BeginUpload(upload, id) => self.begin_upload(upload, id, None),
+            Message::BeginTimedUpload(upload, id, started) => {
+                self.begin_upload(upload, id, Some(started))
+            }
+            Message::UploadChunk(upload, index, bytes) => self.upload_chunk(upload, index, bytes),
+            Message::CommitUpload(session, upload) => self.commit_upload(session, upload),
+            Message::AbortUpload(upload) => self.abort_upload(upload),
+            Message::SetSchema(source) => self.replicate_schema(source),
+            Message::SetSoftDelete(soft_delete) => self.replicate_soft_delete(soft_delete),
+            Message::SetQuota(quota) => self.replicate_quota(quota),
+            Message::SetStorageFull(node, full) => self.set_storage_full(node, full),
+            Message::Purge(retention, now) => self.purge(retention, now),
+            Message::PrepareMove(id, pending) => self.prepare_move(id, pending),
+            Message::CompleteMove(id, move_id) => self.complete_move(id, move_id),
+            Message::AbortMove(id, move_id) => self.abort_move(id, move_id),
+            Message::Put(session, id, new_payload) => self.put(session, id, new_payload),
+            Message::Upsert(session, document) => self.upsert(session, document),
+            Message::Increment(session, id, field, delta) => {
+                self.increment(session, id, field, delta)
+            }
+            Message::NextSequence(name) => self.next_sequence(name),
+            Message::Heartbeat(sent) => self.heartbeat(sent),
+            Message::BeginTransaction(session, author, started) => {
+                self.begin_transaction(session, author, started)
+            }
+            Message::CommitTransaction(session) => self.commit_transaction(session),
+            Message::CreateSavepoint(session, name) => self.create_savepoint(session, name),
+            Message::RollbackToSavepoint(session, name) => {
+                self.rollback_to_savepoint(session, name)
+            }
+            Message::Request(id, message) => {
+                if let Some(response) = self.requests.get(&id) {
+                    return response.clone();
+                }
+
+                let session = message.get_session();
+                let response = self.execute(*message);
+                self.requests.insert(id, session, response.clone());
+
+                response
+            }
+        }
+    }
+
+    /// Answers a query and returns the encoded response
+    fn read(&self, message: Message) -> Vec<u8> {
+        match message {
+            Message::Get(id) => {
+                let response = self.get_document(id).ok_or(DocumentError::NotFound(id));
+
+                encode(&response, SizeLimit::Infinite).unwrap()
+            }
+            Message::GetMany(ids) => encode(&self.get_many(&ids), SizeLimit::Infinite).unwrap(),
+            Message::GetVersion(id) => encode(&self.get_version(id), SizeLimit::Infinite).unwrap(),
+            Message::GetTransactions => {
+                encode(&self.get_transactions(), SizeLimit::Infinite).unwrap()
+            }
+            Message::GetTombstones => encode(&self.get_tombstones(), SizeLimit::Infinite).unwrap(),
+            Message::GetRange(id, offset, length) => {
+                encode(&self.get_range(id, offset, length), SizeLimit::Infinite).unwrap()
+            }
+            Message::GetSchema => encode(&self.get_schema(), SizeLimit::Infinite).unwrap(),
+            Message::GetUsage => encode(&self.get_usage(), SizeLimit::Infinite).unwrap(),
+            Message::GetStorageState => {
+                encode(&self.get_storage_state(), SizeLimit::Infinite).unwrap()
+            }
+            Message::GetPeers => encode(&self.get_peers(), SizeLimit::Infinite).unwrap(),
+            Message::GetMove(id) => encode(&self.moves.get(&id), SizeLimit::Infinite).unwrap(),
+            Message::GetVersionedRange(id, offset, length) => {
+                let response = self.get_range(id, offset, length)
+                    .map(|bytes| (self.map[&id].version, bytes));
+
+                encode(&response, SizeLimit::Infinite).unwrap()
+            }
+            Message::Request(_, message) => self.read(*message),
+            _ => {
+                let response = encode(&"Wrong usage of .query()", SizeLimit::Infinite);
+
+                response.unwrap()
+            }
+        }
+    }
+
+    /// Writes a snapshot file. A failed write, e.g. on a full volume, is reported and the
+    /// state is kept in memory until the next snapshot succeeds.
+    fn write_snapshot_file(&self, name: &str, bytes: &[u8]) {
+        let result = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&format!("{}/{}", self.volume, name))
+            .and_then(|mut file| file.write_all(bytes));
+
+        if let Err(err) = result {
+            println!("Unable to write the snapshot file {}/{}: {}", self.volume, name, err);
+        }
+    }
+
+    fn read_snapshot_file(&self, name: &str) -> Result<Vec<u8>, IoError> {
+        let mut fs = try!(File::open(&format!("{}/{}", self.volume, name)));
+        let mut buffer = Vec::new();
+
+        try!(fs.read_to_end(&mut buffer));
+
+        Ok(buffer)
+    }
+
+    /// Reads a snapshot file. A missing file is empty
+    fn read_optional_file(&self, name: &str) -> Result<Vec<u8>, IoError> {
+        match self.read_snapshot_file(name) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            result => result,
+        }
+    }
+
+    /// Reads the snapshot of the volume for `restore_snapshot`. Volumes of older nodes
+    /// have the documents and the undo records in two files. Both buffers are empty if
+    /// the log has no snapshot yet.
+    pub fn get_snapshot(&self) -> Result<(Vec<u8>, Vec<u8>), IoError> {
+        match self.read_snapshot_file("snapshot") {
+            Ok(snapshot) => Ok((snapshot, Vec::new())),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                Ok((try!(self.read_optional_file("snapshot_map")),
+                    try!(self.read_optional_file("snapshot_log"))))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Returns the response to a command which cannot be decoded
+fn invalid_command(err: WireError) -> Vec<u8> {
+    let response = Err::<(), DocumentError>(DocumentError::InvalidCommand(format!("{}", err)));
+
+    wire::encode_response(encode(&response, SizeLimit::Infinite).unwrap())
+}
+
+impl state_machine::StateMachine for DocumentStateMachine {
+    fn apply(&mut self, new_value: &[u8]) -> Vec<u8> {
+        // A command which cannot be decoded is rejected in the same way on every node
+        // of the same version, so the replicas stay identical
+        let message = match wire::decode_command(new_value) {
+            Ok(message) => message,
+            Err(err) => return invalid_command(err),
+        };
+
+        if let Some(session) = message.get_session() {
+            if let Some(transaction) = self.transactions.get_mut(&session) {
+                transaction.next_operation();
+            }
+        }
+
+        // heartbeats change no persisted state, so an idle log writes no snapshots
+        let heartbeat = match message {
+            Message::Heartbeat(_) => true,
+            _ => false,
+        };
+
+        let response = self.execute(message);
+
+        if !heartbeat {
+            self.snapshot();
+        }
+
+        wire::encode_response(response)
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        match wire::decode_command(query) {
+            Ok(message) => wire::encode_response(self.read(message)),
+            Err(err) => invalid_command(err),
+        }
+    }
+
+    fn snapshot(&self) -> (Vec<u8>, Vec<u8>) {
+        let snapshot = encode_snapshot(&self.to_snapshot());
+
+        self.write_snapshot_file("snapshot", &snapshot);
+
+        (snapshot, Vec::new())
+    }
+
+    fn restore_snapshot(&mut self, snap_map: Vec<u8>, snap_log: Vec<u8>) {
+        // Continuing with an empty state would silently lose the documents of the log
+        let snapshot = match decode_snapshot(&snap_map, &snap_log) {
+            Ok(snapshot) => snapshot,
+            Err(err) => panic!("Cannot restore the snapshot of {}: {}", self.volume, err),
+        };
+
+        self.restore(snapshot);
+    }
+
+    fn revert(&mut self, command: &[u8]) {
+        // a command which could not be decoded was not applied either
+        let message = match wire::decode_command(command) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+
+        let message = match message {
+            Message::Request(id, message) => {
+                self.requests.remove(&id, message.get_session());
+                *message
+            }
+            message => message,
+        };
+
+        if let Message::BeginTransaction(session, _, _) = message {
+            self.end_transaction(session);
+            self.snapshot();
+            return;
+        }
+
+        let session = match message.get_session() {
+            Some(session) => session,
+            None => return,
+        };
+
+        let record = match self.transactions.get_mut(&session) {
+            Some(transaction) => transaction.pop_operation(),
+            None => return,
+        };
+
+        if let Some(record) = record {
+            self.undo(record);
+        }
+
+        self.snapshot();
+    }
+
+    fn rollback(&mut self) {
+        // The undo records are kept per transaction, so there is nothing left to reset
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use document::*;
+    use delta::Delta;
+    use quota::{Quota, QuotaLimit};
+    use upload::UPLOAD_TTL;
+    use handler::Message;
+    use snapshot::encode_snapshot;
+    use transaction::TransactionInfo;
+    use wire;
+    use raft::TransactionId;
+    use raft::ServerId;
+    use raft::state_machine::StateMachine;
+    use bincode::serde::serialize as encode;
+    use bincode::serde::deserialize as decode;
+    use bincode::SizeLimit;
+    use uuid::Uuid;
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, RwLock};
+    use self::tempdir::TempDir;
+
+    fn apply(state_machine: &mut DocumentStateMachine, message: &Message) -> Vec<u8> {
+        let response = state_machine.apply(&wire::encode_command(message).unwrap());
+
+        wire::decode_response(&response).unwrap().to_vec()
+    }
+
+    fn query(state_machine: &DocumentStateMachine, message: &Message) -> Vec<u8> {
+        let response = state_machine.query(&wire::encode_command(message).unwrap());
+
+        wire::decode_response(&response).unwrap().to_vec()
+    }
+
+    fn revert(state_machine: &mut DocumentStateMachine, message: &Message) {
+        state_machine.revert(&wire::encode_command(message).unwrap())
+    }
+
+    fn document(payload: &[u8]) -> Document {
+        Document {
+            id: Uuid::new_v4(),
+            payload: payload.to_vec(),
+            version: 1,
+        }
+    }
+
+    #[test]
+    fn test_conflicting_transactions() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let first = TransactionId::new();
+            let second = TransactionId::new();
+            let doc = document(b"first");
+
+            apply(&mut state_machine,
+                  &Message::BeginTransaction(first, "first".to_string(), 0));
+            apply(&mut state_machine,
+                  &Message::BeginTransaction(second, "second".to_string(), 0));
+            apply(&mut state_machine, &Message::Post(first, doc.clone()));
+
+            let put = Message::Put(second, doc.id, b"second".to_vec());
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine, &put)).unwrap();
+
+            assert_eq!(Err(DocumentError::Conflict(doc.id, first)), response);
+
+            // the rejected put must not undo the post of the other transaction
+            revert(&mut state_machine, &put);
+            assert_eq!(vec![doc.id], state_machine.get_documents());
+
+            apply(&mut state_machine, &Message::CommitTransaction(first));
+
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine, &put)).unwrap();
+            assert_eq!(b"second".to_vec(), response.unwrap().payload);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_revert_transaction() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let session = TransactionId::new();
+            let doc = document(b"old");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+
+            let commands = vec![Message::BeginTransaction(session, "test".to_string(), 0),
+                                Message::Put(session, doc.id, b"new".to_vec()),
+                                Message::Remove(session, doc.id, "test".to_string(), 0)];
+
+            for command in &commands {
+                apply(&mut state_machine, command);
+            }
+
+            for command in commands.iter().rev() {
+                revert(&mut state_machine, command);
+            }
+
+            let response = query(&state_machine, &Message::Get(doc.id));
+            let restored: Result<Document, DocumentError> = decode(&response).unwrap();
+
+            assert_eq!(b"old".to_vec(), restored.unwrap().payload);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_failed_end_transaction() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let session = TransactionId::new();
+            let doc = document(b"old");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+
+            let commands = vec![Message::BeginTransaction(session, "test".to_string(), 0),
+                                Message::Put(session, doc.id, b"new".to_vec())];
+
+            for command in &commands {
+                apply(&mut state_machine, command);
+            }
+
+            // raft did not end the transaction, so the commit was not proposed and raft
+            // rolls the transaction back
+            for command in commands.iter().rev() {
+                revert(&mut state_machine, command);
+            }
+
+            assert_eq!(b"old".to_vec(), state_machine.get_document(doc.id).unwrap().payload);
+            assert!(state_machine.get_transactions().is_empty());
+
+            // the commit of an ended transaction drops its undo records
+            let session = TransactionId::new();
+            apply(&mut state_machine,
+                  &Message::BeginTransaction(session, "test".to_string(), 0));
+            apply(&mut state_machine, &Message::Put(session, doc.id, b"new".to_vec()));
+            apply(&mut state_machine, &Message::CommitTransaction(session));
+
+            assert_eq!(b"new".to_vec(), state_machine.get_document(doc.id).unwrap().payload);
+            assert!(state_machine.get_transactions().is_empty());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_rollback_to_savepoint() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let session = TransactionId::new();
+            let first = document(b"first");
+            let second = document(b"second");
+
+            let commands = vec![Message::BeginTransaction(session, "test".to_string(), 0),
+                                Message::Post(session, first.clone()),
+                                Message::CreateSavepoint(session, "import".to_string()),
+                                Message::Post(session, second.clone()),
+                                Message::Put(session, first.id, b"changed".to_vec()),
+                                Message::RollbackToSavepoint(session, "import".to_string())];
+
+            for command in &commands {
+                apply(&mut state_machine, command);
+            }
+
+            assert_eq!(vec![first.id], state_machine.get_documents());
+
+            let response = query(&state_machine, &Message::Get(first.id));
+            let restored: Result<Document, DocumentError> = decode(&response).unwrap();
+            assert_eq!(b"first".to_vec(), restored.unwrap().payload);
+
+            // a full rollback must not undo the operations twice
+            for command in commands.iter().rev() {
+                revert(&mut state_machine, command);
+            }
+
+            assert!(state_machine.get_documents().is_empty());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_post_existing_id() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let mut doc = document(b"first");
+            doc.id = document_id_from_key("order-1");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::Post(TransactionId::new(), doc.clone())))
+                    .unwrap();
+            assert_eq!(Err(DocumentError::AlreadyExists(doc.id)), response);
+
+            doc.payload = b"second".to_vec();
+
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine, &Message::Upsert(TransactionId::new(), doc)))
+                    .unwrap();
+            assert_eq!(b"second".to_vec(), response.unwrap().payload);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replayed_request() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let doc = document(b"payload");
+            let request = Message::Request(Uuid::new_v4(),
+                                           Box::new(Message::Post(TransactionId::new(),
+                                                                  doc.clone())));
+
+            let first = apply(&mut state_machine, &request);
+            let second = apply(&mut state_machine, &request);
+
+            assert_eq!(first, second);
+
+            let response: Result<Document, DocumentError> = decode(&second).unwrap();
+            assert_eq!(doc, response.unwrap());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replayed_request_after_snapshot() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut leader = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let request = Message::Request(Uuid::new_v4(),
+                                           Box::new(Message::NextSequence("orders"
+                                               .to_string())));
+
+            let first = apply(&mut leader, &request);
+
+            // a follower which caught up by the snapshot of the leader
+            let (map, log) = leader.snapshot();
+            let mut follower = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            follower.restore_snapshot(map, log);
+
+            assert_eq!(first, apply(&mut leader, &request));
+            assert_eq!(first, apply(&mut follower, &request));
+
+            let response: Result<u64, DocumentError> =
+                decode(&apply(&mut follower, &Message::NextSequence("orders".to_string())))
+                    .unwrap();
+            assert_eq!(Ok(2), response);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            apply(&mut state_machine, &Message::SetSoftDelete(true));
+
+            let doc = document(b"payload");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+            apply(&mut state_machine,
+                  &Message::Remove(TransactionId::new(), doc.id, "test".to_string(), 100));
+
+            assert!(state_machine.get_documents().is_empty());
+            assert_eq!(vec![doc.id],
+                       state_machine.get_tombstones()
+                           .into_iter()
+                           .map(|(id, _)| id)
+                           .collect::<Vec<_>>());
+
+            apply(&mut state_machine, &Message::Undelete(TransactionId::new(), doc.id));
+            assert_eq!(vec![doc.id], state_machine.get_documents());
+
+            apply(&mut state_machine,
+                  &Message::Remove(TransactionId::new(), doc.id, "test".to_string(), 100));
+
+            let response: Result<Vec<DocumentId>, DocumentError> =
+                decode(&apply(&mut state_machine, &Message::Purge(60, 130))).unwrap();
+            assert!(response.unwrap().is_empty());
+
+            let response: Result<Vec<DocumentId>, DocumentError> =
+                decode(&apply(&mut state_machine, &Message::Purge(60, 161))).unwrap();
+            assert_eq!(vec![doc.id], response.unwrap());
+            assert!(state_machine.get_tombstones().is_empty());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_move_document() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let doc = document(b"payload");
+            let pending = PendingMove {
+                id: Uuid::new_v4(),
+                target: "target".to_string(),
+                target_id: doc.id,
+                keep_version: true,
+            };
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+
+            let response: Result<(PendingMove, Document), DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::PrepareMove(doc.id, pending.clone())))
+                    .unwrap();
+            assert_eq!(Ok((pending.clone(), doc.clone())), response);
+
+            // the document is locked until the move is completed
+            let put = Message::Put(TransactionId::new(), doc.id, b"new".to_vec());
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine, &put)).unwrap();
+            assert_eq!(Err(DocumentError::Moving(doc.id)), response);
+
+            // an interrupted move is resumed with the pending move
+            let retry = PendingMove { id: Uuid::new_v4(), ..pending.clone() };
+            let response: Result<(PendingMove, Document), DocumentError> =
+                decode(&apply(&mut state_machine, &Message::PrepareMove(doc.id, retry)))
+                    .unwrap();
+            assert_eq!(pending.id, response.unwrap().0.id);
+
+            // an aborted move unlocks the document
+            let saved: Option<PendingMove> =
+                decode(&query(&state_machine, &Message::GetMove(doc.id))).unwrap();
+            assert_eq!(Some(pending.clone()), saved);
+
+            apply(&mut state_machine, &Message::AbortMove(doc.id, pending.id));
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine, &put)).unwrap();
+            assert!(response.is_ok());
+
+            let response: Result<(PendingMove, Document), DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::PrepareMove(doc.id, pending.clone())))
+                    .unwrap();
+            assert!(response.is_ok());
+
+            apply(&mut state_machine, &Message::CompleteMove(doc.id, pending.id));
+            assert!(state_machine.get_documents().is_empty());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_revert_patch() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let session = TransactionId::new();
+            let doc = document(b"hello world");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+            apply(&mut state_machine,
+                  &Message::BeginTransaction(session, "test".to_string(), 0));
+
+            let patches = vec![Message::Patch(session, doc.id, Delta::Append(b"!".to_vec())),
+                               Message::Patch(session, doc.id, Delta::Write(6, b"there".to_vec())),
+                               Message::Patch(session, doc.id, Delta::Truncate(5))];
+
+            for patch in &patches {
+                apply(&mut state_machine, patch);
+            }
+            assert_eq!(b"hello".to_vec(),
+                       state_machine.get_document(doc.id).unwrap().payload);
+
+            for patch in patches.iter().rev() {
+                revert(&mut state_machine, patch);
+            }
+            assert_eq!(b"hello world".to_vec(),
+                       state_machine.get_document(doc.id).unwrap().payload);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_chunked_upload() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let upload = Uuid::new_v4();
+            let id = Uuid::new_v4();
+
+            apply(&mut state_machine, &Message::BeginTimedUpload(upload, id, 0));
+            apply(&mut state_machine,
+                  &Message::UploadChunk(upload, 1, b"world".to_vec()));
+
+            let response: Result<u64, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::CommitUpload(TransactionId::new(), upload)))
+                    .unwrap();
+            assert_eq!(Err(DocumentError::IncompleteUpload(upload, 0)), response);
+
+            // the chunks are staged in the volume instead of the snapshot
+            let staged = dir.path().join("uploads").join(upload.to_string());
+            assert!(staged.join("1").exists());
+
+            apply(&mut state_machine,
+                  &Message::UploadChunk(upload, 0, b"hello ".to_vec()));
+
+            let response: Result<u64, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::CommitUpload(TransactionId::new(), upload)))
+                    .unwrap();
+            assert_eq!(Ok(11), response);
+            assert!(!staged.exists());
+
+            let range: Result<Vec<u8>, DocumentError> =
+                decode(&query(&state_machine, &Message::GetRange(id, 6, 100))).unwrap();
+            assert_eq!(Ok(b"world".to_vec()), range);
+
+            // an upload of an equal payload references the stored one
+            let again = Uuid::new_v4();
+            apply(&mut state_machine,
+                  &Message::BeginTimedUpload(again, Uuid::new_v4(), 0));
+            apply(&mut state_machine,
+                  &Message::UploadChunk(again, 0, b"hello world".to_vec()));
+            apply(&mut state_machine,
+                  &Message::CommitUpload(TransactionId::new(), again));
+
+            let stats = state_machine.get_blob_stats();
+            assert_eq!(1, stats.blobs);
+            assert_eq!(2, stats.references);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_staged_upload() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let upload = Uuid::new_v4();
+            let id = Uuid::new_v4();
+
+            apply(&mut state_machine,
+                  &Message::SetQuota(Quota {
+                      max_documents: None,
+                      max_payload_size: None,
+                      max_total_bytes: Some(8),
+                  }));
+
+            apply(&mut state_machine, &Message::BeginTimedUpload(upload, id, 0));
+            apply(&mut state_machine,
+                  &Message::UploadChunk(upload, 0, b"hello".to_vec()));
+            assert_eq!(5, state_machine.get_usage().staged_bytes);
+
+            // the staged chunks count towards the quota
+            let response: Result<(), DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::UploadChunk(upload, 1, b"world".to_vec())))
+                    .unwrap();
+            assert_eq!(Err(DocumentError::QuotaExceeded(id, QuotaLimit::TotalBytes(8))),
+                       response);
+
+            // but not twice when the upload is committed
+            let response: Result<u64, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::CommitUpload(TransactionId::new(), upload)))
+                    .unwrap();
+            assert_eq!(Ok(5), response);
+            assert_eq!(0, state_machine.get_usage().staged_bytes);
+
+            let response: Result<(usize, Vec<u8>), DocumentError> =
+                decode(&query(&state_machine, &Message::GetVersionedRange(id, 1, 2))).unwrap();
+            assert_eq!(Ok((1, b"el".to_vec())), response);
+
+            // an expired upload is dropped when the next upload begins
+            let expired = Uuid::new_v4();
+            apply(&mut state_machine, &Message::BeginTimedUpload(expired, id, 0));
+            apply(&mut state_machine,
+                  &Message::UploadChunk(expired, 0, b"!".to_vec()));
+            apply(&mut state_machine,
+                  &Message::BeginTimedUpload(Uuid::new_v4(), id, UPLOAD_TTL + 1));
+
+            let response: Result<(), DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::UploadChunk(expired, 1, b"!".to_vec())))
+                    .unwrap();
+            assert_eq!(Err(DocumentError::UploadNotFound(expired)), response);
+
+            // an upload which was begun without a timestamp does not expire
+            let legacy = Uuid::new_v4();
+            apply(&mut state_machine, &Message::BeginUpload(legacy, id));
+            apply(&mut state_machine,
+                  &Message::BeginTimedUpload(Uuid::new_v4(), id, 10 * UPLOAD_TTL));
+
+            let response: Result<(), DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::UploadChunk(legacy, 0, b"!".to_vec())))
+                    .unwrap();
+            assert_eq!(Ok(()), response);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_dedup_snapshot() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+
+            let first = document(b"attachment");
+            let second = document(b"attachment");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), first.clone()));
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), second.clone()));
+            apply(&mut state_machine,
+                  &Message::Put(TransactionId::new(), second.id, b"changed".to_vec()));
+
+            // the put outside of a transaction keeps no undo record, so the shared blob is
+            // referenced by the first document only
+            let stats = state_machine.get_blob_stats();
+            assert_eq!(2, stats.blobs);
+            assert_eq!(2, stats.references);
+
+            let (map, log) = state_machine.snapshot();
+
+            let mut restored = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            restored.restore_snapshot(map, log);
+
+            assert_eq!(Some(first.clone()), restored.get_document(first.id));
+            assert_eq!(b"changed".to_vec(),
+                       restored.get_document(second.id).unwrap().payload);
+            assert_eq!(stats, restored.get_blob_stats());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_release_undo_records() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let doc = document(b"first");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+            apply(&mut state_machine,
+                  &Message::Put(TransactionId::new(), doc.id, b"second".to_vec()));
+
+            // a write outside of a transaction cannot be undone, so the replaced payload
+            // is freed at once
+            assert_eq!(1, state_machine.get_blob_stats().blobs);
+
+            let session = TransactionId::new();
+            apply(&mut state_machine,
+                  &Message::BeginTransaction(session, "test".to_string(), 0));
+            apply(&mut state_machine, &Message::Put(session, doc.id, b"third".to_vec()));
+            apply(&mut state_machine, &Message::Remove(session, doc.id, "test".to_string(), 0));
+
+            // the open transaction keeps the old payloads for its undo records
+            assert_eq!(2, state_machine.get_blob_stats().blobs);
+
+            // the snapshot of the open transaction keeps them as well
+            let (map, log) = state_machine.snapshot();
+            let mut restored = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            restored.restore_snapshot(map, log);
+            assert_eq!(state_machine.get_blob_stats(), restored.get_blob_stats());
+
+            apply(&mut state_machine, &Message::CommitTransaction(session));
+            apply(&mut restored, &Message::CommitTransaction(session));
+
+            let stats = state_machine.get_blob_stats();
+            assert_eq!(0, stats.blobs);
+            assert_eq!(0, stats.references);
+            assert_eq!(stats, restored.get_blob_stats());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_restore_legacy_log() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let doc = document(b"first");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+
+            // older nodes kept the undo record of every write in the log
+            let mut snapshot = state_machine.to_snapshot();
+            let hash = snapshot.documents[&doc.id].hash.clone();
+            let mut record = DocumentRecord::new(doc.id, "legacy".to_string(), ActionType::Put);
+            record.set_old_hash(hash.clone());
+            snapshot.blobs.retain(&hash);
+            snapshot.log.push(record);
+
+            let mut restored = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            restored.restore_snapshot(encode_snapshot(&snapshot), Vec::new());
+
+            assert_eq!(state_machine.get_blob_stats(), restored.get_blob_stats());
+            assert_eq!(1, restored.get_blob_stats().references);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+
+            // a replica without a heartbeat cannot bound its staleness
+            assert_eq!(1000, state_machine.get_staleness(1000));
+
+            apply(&mut state_machine, &Message::Heartbeat(990));
+            assert_eq!(10, state_machine.get_staleness(1000));
+
+            // heartbeats which were delayed do not move the bound back
+            apply(&mut state_machine, &Message::Heartbeat(980));
+            assert_eq!(10, state_machine.get_staleness(1000));
+
+            // a read-only log still accepts heartbeats
+            apply(&mut state_machine,
+                  &Message::SetStorageFull("127.0.0.1:8000".to_string(), true));
+            let response = apply(&mut state_machine, &Message::Heartbeat(995));
+            let response: Result<(), DocumentError> = decode(response.as_slice()).unwrap();
+            assert!(response.is_ok());
+            assert_eq!(5, state_machine.get_staleness(1000));
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_blob_references() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let doc = document(b"hello");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+            apply(&mut state_machine,
+                  &Message::Patch(TransactionId::new(), doc.id, Delta::Append(b" world".to_vec())));
+
+            // the patch keeps the undo deltas, so the old payload is dropped
+            assert_eq!(1, state_machine.get_blob_stats().blobs);
+
+            let session = TransactionId::new();
+            let commands = vec![Message::BeginTransaction(session, "test".to_string(), 0),
+                                Message::Put(session, doc.id, b"new".to_vec())];
+
+            for command in &commands {
+                apply(&mut state_machine, command);
+            }
+
+            assert_eq!(2, state_machine.get_blob_stats().blobs);
+
+            for command in commands.iter().rev() {
+                revert(&mut state_machine, command);
+            }
+
+            // the reverted payload is dropped, the document took over the reference of the
+            // undo record to the old one
+            let stats = state_machine.get_blob_stats();
+            assert_eq!(1, stats.blobs);
+            assert_eq!(1, stats.references);
+            assert_eq!(b"hello world".to_vec(),
+                       state_machine.get_document(doc.id).unwrap().payload);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_list_transactions() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let first = TransactionId::new();
+            let second = TransactionId::new();
+            let posted = document(b"posted");
+            let updated = document(b"updated");
+            let started = timestamp() - 60;
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), updated.clone()));
+
+            let commands = vec![Message::BeginTransaction(first, "first".to_string(), started),
+                                Message::Post(first, posted.clone()),
+                                Message::Put(first, updated.id, b"new".to_vec()),
+                                Message::BeginTransaction(second, "second".to_string(), 0)];
+
+            for command in &commands {
+                apply(&mut state_machine, command);
+            }
+
+            let transactions: Vec<TransactionInfo> =
+                decode(&query(&state_machine, &Message::GetTransactions)).unwrap();
+            assert_eq!(2, transactions.len());
+
+            let info = transactions.iter().find(|info| info.id == first).unwrap();
+            assert_eq!("first", info.author);
+            assert_eq!(started, info.started);
+            assert_eq!(2, info.operations);
+            assert!(info.age >= 60 && info.age < 120);
+
+            let mut documents = info.documents.clone();
+            documents.sort();
+            let mut written = vec![posted.id, updated.id];
+            written.sort();
+            assert_eq!(written, documents);
+
+            // a transaction without writes has an empty write set
+            let info = state_machine.get_transaction(second).unwrap();
+            assert_eq!(0, info.operations);
+            assert!(info.documents.is_empty());
+
+            // a committed transaction disappears from the list
+            apply(&mut state_machine, &Message::CommitTransaction(first));
+            assert!(state_machine.get_transaction(first).is_none());
+
+            let transactions: Vec<TransactionInfo> =
+                decode(&query(&state_machine, &Message::GetTransactions)).unwrap();
+            assert_eq!(vec![second],
+                       transactions.iter().map(|info| info.id).collect::<Vec<_>>());
+
+            // and so does a transaction which raft rolled back
+            revert(&mut state_machine, &commands[3]);
+            assert!(state_machine.get_transactions().is_empty());
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_restore_open_transaction() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let session = TransactionId::new();
+            let doc = document(b"old");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+
+            let commands = vec![Message::BeginTransaction(session, "test".to_string(), 0),
+                                Message::Put(session, doc.id, b"new".to_vec()),
+                                Message::NextSequence("orders".to_string())];
+
+            for command in &commands {
+                apply(&mut state_machine, command);
+            }
+
+            // a restarted node or a follower which catches up by snapshot
+            let (map, log) = state_machine.snapshot();
+            let mut restored = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            restored.restore_snapshot(map, log);
+
+            assert_eq!(vec![session],
+                       restored.get_transactions()
+                           .into_iter()
+                           .map(|info| info.id)
+                           .collect::<Vec<_>>());
+
+            for command in commands.iter().rev() {
+                revert(&mut restored, command);
+            }
+
+            assert_eq!(b"old".to_vec(), restored.get_document(doc.id).unwrap().payload);
+            assert!(restored.get_transactions().is_empty());
+
+            let response: Result<u64, DocumentError> =
+                decode(&apply(&mut restored, &Message::NextSequence("orders".to_string())))
+                    .unwrap();
+            assert_eq!(Ok(2), response);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_schema_validation() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let schema = r#"{"type": "object", "required": ["name"]}"#.to_string();
+
+            apply(&mut state_machine, &Message::SetSchema(Some(schema)));
+
+            let invalid = document(b"{}");
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::Post(TransactionId::new(), invalid.clone())))
+                    .unwrap();
+
+            match response {
+                Err(DocumentError::InvalidDocument(id, violations)) => {
+                    assert_eq!(invalid.id, id);
+                    assert_eq!("/name", violations[0].path);
+                }
+                other => panic!("Unexpected response {:?}", other),
+            }
+
+            let valid = document(br#"{"name": "paenko"}"#);
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::Post(TransactionId::new(), valid.clone())))
+                    .unwrap();
+            assert_eq!(Ok(valid), response);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_quota() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            apply(&mut state_machine,
+                  &Message::SetQuota(Quota {
+                      max_documents: Some(1),
+                      max_payload_size: None,
+                      max_total_bytes: Some(8),
+                  }));
+
+            let doc = document(b"12345");
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+
+            let other = document(b"1");
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::Post(TransactionId::new(), other.clone())))
+                    .unwrap();
+            assert_eq!(Err(DocumentError::QuotaExceeded(other.id, QuotaLimit::Documents(1))),
+                       response);
+
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine,
+                              &Message::Put(TransactionId::new(), doc.id, b"123456789".to_vec())))
+                    .unwrap();
+            assert_eq!(Err(DocumentError::QuotaExceeded(doc.id, QuotaLimit::TotalBytes(8))),
+                       response);
+
+            assert_eq!(5, state_machine.get_usage().total_bytes);
+
+            // the quota is replicated, so it is part of the snapshot
+            let (map, log) = state_machine.snapshot();
+            let mut restored = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            restored.restore_snapshot(map, log);
+            assert_eq!(Some(1), restored.get_usage().quota.max_documents);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_only() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let doc = document(b"payload");
+
+            apply(&mut state_machine,
+                  &Message::SetStorageFull("127.0.0.1:8000".to_string(), true));
+            assert!(state_machine.is_read_only());
+
+            let post = Message::Post(TransactionId::new(), doc.clone());
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine, &post)).unwrap();
+            assert_eq!(Err(DocumentError::ReadOnly), response);
+
+            apply(&mut state_machine,
+                  &Message::SetStorageFull("127.0.0.1:8000".to_string(), false));
+
+            let post = Message::Post(TransactionId::new(), doc.clone());
+            let response: Result<Document, DocumentError> =
+                decode(&apply(&mut state_machine, &post)).unwrap();
+            assert_eq!(Ok(doc), response);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_get_peers() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let node = "127.0.0.1:9000".parse().unwrap();
+            let peer = "127.0.0.1:9001".parse().unwrap();
+
+            let mut peers = HashMap::new();
+            peers.insert(ServerId::from(2), peer);
+            state_machine.set_cluster(node, Arc::new(RwLock::new(peers)));
+
+            let response = query(&state_machine, &Message::GetPeers);
+            let nodes: Vec<SocketAddr> = decode(&response).unwrap();
+
+            assert_eq!(vec![node, peer], nodes);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_unversioned_commands() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let doc = document(b"test");
+
+            // a `Post` of the first format, which was written before the format was
+            // versioned, is the variant index and the document
+            let post = encode(&(1u32, &doc), SizeLimit::Infinite).unwrap();
+            state_machine.apply(&post);
+
+            let response: Result<Document, DocumentError> =
+                decode(&query(&state_machine, &Message::Get(doc.id))).unwrap();
+            assert_eq!(Ok(doc), response);
+
+            let mut future = b"PKDB\x00\x02".to_vec();
+            future.extend(encode(&Message::GetTombstones, SizeLimit::Infinite).unwrap());
+
+            let response = state_machine.query(&future);
+            let response: Result<(), DocumentError> =
+                decode(wire::decode_response(&response).unwrap()).unwrap();
+            match response {
+                Err(DocumentError::InvalidCommand(_)) => {}
+                other => panic!("Unexpected response {:?}", other),
+            }
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_get_many() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let first = document(b"first");
+            let second = document(b"second");
+            let missing = Uuid::new_v4();
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), first.clone()));
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), second.clone()));
+
+            let response: MultiGet =
+                decode(&query(&state_machine,
+                              &Message::GetMany(vec![second.id, missing, first.id])))
+                    .unwrap();
+
+            assert_eq!(vec![second, first], response.documents);
+            assert_eq!(vec![missing], response.missing);
+
+            dir.close().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_version_of_writes() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let mut state_machine = DocumentStateMachine::new(dir.path().to_str().unwrap());
+            let session = TransactionId::new();
+            let doc = document(b"first");
+
+            apply(&mut state_machine, &Message::Post(TransactionId::new(), doc.clone()));
+            assert_eq!(Ok(1), state_machine.get_version(doc.id));
+
+            let put = Message::Put(TransactionId::new(), doc.id, b"second".to_vec());
+            apply(&mut state_machine, &put);
+            assert_eq!(Ok(2), state_machine.get_version(doc.id));
+
+            let put = Message::Put(session, doc.id, b"third".to_vec());
+            apply(&mut state_machine,
+                  &Message::BeginTransaction(session, "test".to_string(), 0));
+            apply(&mut state_machine, &put);
+            revert(&mut state_machine, &put);
+
+            // a reverted write does not restore the old version
+            let response: Result<usize, DocumentError> =
+                decode(&query(&state_machine, &Message::GetVersion(doc.id))).unwrap();
+            assert_eq!(Ok(4), response);
+            assert_eq!(b"second".to_vec(),
+                       state_machine.get_document(doc.id).unwrap().payload);
+
+            let missing = Uuid::new_v4();
+            let response: Result<usize, DocumentError> =
+                decode(&query(&state_machine, &Message::GetVersion(missing))).unwrap();
+            assert_eq!(Err(DocumentError::NotFound(missing)), response);
+
+            dir.close().unwrap();
+        }
     }
 }
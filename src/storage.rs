@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+use fs2;
+
+use raft::LogId;
+
+use client::PaenkoClient;
+use statemachine::DocumentStateMachine;
+
+/// Seconds between two checks of the free space
+pub const CHECK_INTERVAL: u64 = 10;
+
+/// The storage state of a log which is returned to clients
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct StorageState {
+    /// True if writes are rejected because a node ran out of space
+    pub read_only: bool,
+    /// The nodes whose volume is below the watermark
+    pub full_nodes: Vec<String>,
+    /// The watermark of free bytes of this node
+    pub min_free_space: Option<u64>,
+    /// The free bytes on the volume of this node
+    pub free_space: Option<u64>,
+}
+
+/// Returns the free bytes of the volume which contains the path
+pub fn available_space(path: &str) -> io::Result<u64> {
+    fs2::available_space(path)
+}
+
+/// A log whose volume is watched
+pub struct WatchedLog {
+    pub lid: LogId,
+    pub path: String,
+    /// The minimum number of free bytes before the log becomes read-only
+    pub min_free_space: u64,
+}
+
+/// Checks the volumes of the logs periodically. If a volume falls below its watermark,
+/// the node proposes to switch the log into read-only mode. The mode is replicated, so
+/// every replica rejects the same writes. Once space is freed, the node proposes to
+/// resume writes.
+pub fn watch(node: String,
+             addr: SocketAddr,
+             username: String,
+             password: String,
+             logs: Vec<WatchedLog>,
+             state_machines: HashMap<LogId, Arc<RwLock<DocumentStateMachine>>>) {
+    spawn(move || {
+        let mut client = match logs.first() {
+            Some(log) => PaenkoClient::new(vec![addr], &username, &password, log.lid),
+            None => return,
+        };
+
+        loop {
+            for log in &logs {
+                let full = match available_space(&log.path) {
+                    Ok(free) => free < log.min_free_space,
+                    Err(err) => {
+                        println!("Cannot check the free space of {}: {}", log.path, err);
+                        continue;
+                    }
+                };
+
+                let reported = match state_machines.get(&log.lid) {
+                    Some(state_machine) => state_machine.read().unwrap().is_node_full(&node),
+                    None => continue,
+                };
+
+                if full != reported {
+                    // Failed proposals are retried with the next check
+                    client.set_lid(log.lid);
+
+                    if let Err(err) = client.set_storage_full(node.clone(), full) {
+                        println!("Cannot change the storage state of {}: {}", log.path, err);
+                    }
+                }
+            }
+
+            sleep(Duration::from_secs(CHECK_INTERVAL));
+        }
+    });
+}
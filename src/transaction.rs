@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+
+use raft::TransactionId;
+
+use blobs::Hash;
+use document::{DocumentId, DocumentRecord};
+
+/// Summary of an open transaction which is returned to clients
+#[derive(Debug,Clone,Serialize,Deserialize,PartialEq)]
+pub struct TransactionInfo {
+    pub id: TransactionId,
+    /// The user who began the transaction
+    pub author: String,
+    /// Unix timestamp in seconds when the transaction was started
+    pub started: u64,
+    /// Seconds since the transaction was started
+    pub age: u64,
+    /// The number of commands which were applied in this transaction
+    pub operations: usize,
+    /// All documents which were written by this transaction
+    pub documents: Vec<DocumentId>,
+}
+
+/// Bookkeeping of an open transaction in the `DocumentStateMachine`
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct Transaction {
+    /// The user who began the transaction
+    author: String,
+    /// Unix timestamp in seconds when the transaction was started
+    started: u64,
+    /// The number of commands which were applied in this transaction
+    operations: usize,
+    /// All documents which were written by this transaction
+    write_set: HashSet<DocumentId>,
+    /// The undo records of the transaction together with the index of their operation
+    records: Vec<(usize, DocumentRecord)>,
+    /// The named savepoints together with the index of the operation which created them
+    savepoints: Vec<(String, usize)>,
+}
+
+impl Transaction {
+    pub fn new(author: String, started: u64) -> Self {
+        Transaction {
+            author: author,
+            started: started,
+            operations: 0,
+            write_set: HashSet::new(),
+            records: Vec::new(),
+            savepoints: Vec::new(),
+        }
+    }
+
+    /// Returns the summary of the transaction at the time `now`
+    pub fn info(&self, id: TransactionId, now: u64) -> TransactionInfo {
+        TransactionInfo {
+            id: id,
+            author: self.author.clone(),
+            started: self.started,
+            age: now.saturating_sub(self.started),
+            operations: self.operations,
+            documents: self.write_set.iter().cloned().collect(),
+        }
+    }
+
+    /// Returns true if the document was written by this transaction
+    pub fn has_written(&self, id: &DocumentId) -> bool {
+        self.write_set.contains(id)
+    }
+
+    /// Returns all documents which were written by this transaction
+    pub fn get_write_set(&self) -> &HashSet<DocumentId> {
+        &self.write_set
+    }
+
+    /// Counts a new operation and returns its index
+    pub fn next_operation(&mut self) -> usize {
+        self.operations += 1;
+        self.operations
+    }
+
+    /// Returns the old payloads which are referenced by the undo records
+    pub fn get_old_hashes(&self) -> Vec<Hash> {
+        self.records.iter().filter_map(|&(_, ref record)| record.get_old_hash()).collect()
+    }
+
+    /// Saves the undo record for the current operation
+    pub fn push_record(&mut self, record: DocumentRecord) {
+        self.write_set.insert(record.get_id());
+        self.records.push((self.operations, record));
+    }
+
+    /// Creates a savepoint at the current operation. A savepoint with the same name
+    /// is shadowed until the new one is reverted.
+    pub fn create_savepoint(&mut self, name: String) {
+        self.savepoints.push((name, self.operations));
+    }
+
+    /// Undoes all operations after the savepoint and drops the savepoints which were created
+    /// after it. Returns the undo records of these operations in reverse order, or `None`
+    /// if there is no savepoint with this name.
+    pub fn rollback_to_savepoint(&mut self, name: &str) -> Option<Vec<DocumentRecord>> {
+        let position = match self.savepoints.iter().rposition(|&(ref n, _)| n == name) {
+            Some(position) => position,
+            None => return None,
+        };
+
+        let index = self.savepoints[position].1;
+        self.savepoints.truncate(position + 1);
+
+        let split = self.records
+            .iter()
+            .position(|&(i, _)| i > index)
+            .unwrap_or(self.records.len());
+
+        let records = self.records
+            .split_off(split)
+            .into_iter()
+            .rev()
+            .map(|(_, record)| record)
+            .collect();
+
+        self.update_write_set();
+
+        Some(records)
+    }
+
+    /// Removes the last operation. Returns its undo record if the operation changed
+    /// a document. Rejected operations and operations which were already undone by
+    /// `rollback_to_savepoint` have no record.
+    pub fn pop_operation(&mut self) -> Option<DocumentRecord> {
+        if self.operations == 0 {
+            return None;
+        }
+
+        let index = self.operations;
+        self.operations -= 1;
+
+        match self.savepoints.last() {
+            Some(&(_, i)) if i == index => {
+                self.savepoints.pop();
+                return None;
+            }
+            _ => {}
+        }
+
+        match self.records.last() {
+            Some(&(i, _)) if i == index => {}
+            _ => return None,
+        }
+
+        let (_, record) = self.records.pop().unwrap();
+
+        self.update_write_set();
+
+        Some(record)
+    }
+
+    fn update_write_set(&mut self) {
+        self.write_set = self.records.iter().map(|&(_, ref r)| r.get_id()).collect();
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use uuid::Uuid;
+
+use blobs::Hash;
+use document::{DocumentId, DocumentError};
+
+/// The id of a chunked upload
+pub type UploadId = Uuid;
+
+/// The size of the chunks in which large documents are uploaded and downloaded. Every chunk
+/// is replicated as its own entry.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Pending uploads which were started longer ago in seconds are dropped when the next
+/// upload of the log begins
+pub const UPLOAD_TTL: u64 = 24 * 60 * 60;
+
+/// The chunks of a document which were uploaded, but not committed yet. Only the sizes of
+/// the chunks are part of the snapshot, their bytes are staged as files in the volume of
+/// every replica, see `stage_chunk`.
+#[derive(Debug,Clone,Serialize,Deserialize)]
+pub struct Upload {
+    /// The document which is created or replaced by the commit
+    pub document: DocumentId,
+    /// Unix timestamp in seconds when the upload began. Uploads which were begun with
+    /// `Message::BeginUpload` have no timestamp and never expire.
+    pub started: Option<u64>,
+    chunks: BTreeMap<u64, u64>,
+}
+
+impl Upload {
+    pub fn new(document: DocumentId, started: Option<u64>) -> Self {
+        Upload {
+            document: document,
+            started: started,
+            chunks: BTreeMap::new(),
+        }
+    }
+
+    /// Returns true if the upload began more than `UPLOAD_TTL` seconds before `now`
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.started.map_or(false, |started| started.saturating_add(UPLOAD_TTL) < now)
+    }
+
+    /// Returns the number of bytes of all chunks
+    pub fn size(&self) -> u64 {
+        self.chunks.values().cloned().sum()
+    }
+
+    /// Returns the number of bytes of the chunk with the `index`
+    pub fn chunk_size(&self, index: u64) -> u64 {
+        self.chunks.get(&index).cloned().unwrap_or(0)
+    }
+
+    /// Counts a chunk which was staged with `stage_chunk`. A chunk which is sent again
+    /// replaces the previous one.
+    pub fn insert(&mut self, index: u64, size: u64) {
+        self.chunks.insert(index, size);
+    }
+
+    /// Fails with the index of the first missing chunk
+    pub fn check_complete(&self, id: UploadId) -> Result<(), DocumentError> {
+        for (expected, index) in self.chunks.keys().enumerate() {
+            if *index != expected as u64 {
+                return Err(DocumentError::IncompleteUpload(id, expected as u64));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the hash of the payload of a complete upload. The staged chunks are read one
+    /// after another, so the payload is not assembled for it.
+    pub fn hash(&self, volume: &str, id: UploadId) -> io::Result<Hash> {
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+
+        for index in self.chunks.keys() {
+            let mut file = try!(File::open(chunk_path(volume, id, *index)));
+
+            loop {
+                let read = try!(file.read(&mut buffer));
+                if read == 0 {
+                    break;
+                }
+
+                hasher.input(&buffer[..read]);
+            }
+        }
+
+        Ok(hasher.result_str())
+    }
+
+    /// Joins the staged chunks of a complete upload to the payload
+    pub fn read(&self, volume: &str, id: UploadId) -> io::Result<Vec<u8>> {
+        let mut payload = Vec::with_capacity(self.size() as usize);
+
+        for index in self.chunks.keys() {
+            let mut file = try!(File::open(chunk_path(volume, id, *index)));
+            try!(file.read_to_end(&mut payload));
+        }
+
+        Ok(payload)
+    }
+}
+
+/// Returns the directory of the staged chunks of an upload
+fn upload_dir(volume: &str, id: UploadId) -> String {
+    format!("{}/uploads/{}", volume, id)
+}
+
+fn chunk_path(volume: &str, id: UploadId, index: u64) -> String {
+    format!("{}/{}", upload_dir(volume, id), index)
+}
+
+/// Writes the bytes of a chunk to the volume. A chunk which is sent again is overwritten.
+pub fn stage_chunk(volume: &str, id: UploadId, index: u64, bytes: &[u8]) -> io::Result<()> {
+    try!(fs::create_dir_all(upload_dir(volume, id)));
+
+    let mut file = try!(File::create(chunk_path(volume, id, index)));
+    file.write_all(bytes)
+}
+
+/// Deletes the staged chunks of an upload. An upload without chunks has nothing to delete.
+pub fn remove_chunks(volume: &str, id: UploadId) -> io::Result<()> {
+    match fs::remove_dir_all(upload_dir(volume, id)) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use super::*;
+    use blobs;
+    use uuid::Uuid;
+    use self::tempdir::TempDir;
+
+    #[test]
+    fn test_assemble() {
+        if let Ok(dir) = TempDir::new("tmp") {
+            let volume = dir.path().to_str().unwrap();
+            let id = Uuid::new_v4();
+            let mut upload = Upload::new(Uuid::new_v4(), Some(0));
+
+            for &(index, bytes) in &[(1, &b"world"[..]), (0, &b"hello "[..]), (3, &b"?"[..])] {
+                stage_chunk(volume, id, index, bytes).unwrap();
+                upload.insert(index, bytes.len() as u64);
+            }
+
+            assert_eq!(Err(DocumentError::IncompleteUpload(id, 2)), upload.check_complete(id));
+
+            stage_chunk(volume, id, 2, b"!").unwrap();
+            upload.insert(2, 1);
+            stage_chunk(volume, id, 3, b"").unwrap();
+            upload.insert(3, 0);
+
+            assert_eq!(Ok(()), upload.check_complete(id));
+            assert_eq!(b"hello world!".to_vec(), upload.read(volume, id).unwrap());
+            assert_eq!(blobs::hash(b"hello world!"), upload.hash(volume, id).unwrap());
+            assert_eq!(12, upload.size());
+            assert_eq!(5, upload.chunk_size(1));
+
+            assert!(!upload.is_expired(UPLOAD_TTL));
+            assert!(upload.is_expired(UPLOAD_TTL + 1));
+            assert!(!Upload::new(Uuid::new_v4(), None).is_expired(UPLOAD_TTL + 1));
+
+            remove_chunks(volume, id).unwrap();
+            assert!(upload.read(volume, id).is_err());
+            remove_chunks(volume, id).unwrap();
+
+            dir.close().unwrap();
+        }
+    }
+}
@@ -0,0 +1,304 @@
+use std::error;
+use std::fmt;
+
+use bincode::serde::serialize as encode;
+use bincode::serde::deserialize as decode;
+use bincode::SizeLimit;
+
+use document::{Document, DocumentId};
+use handler::Message;
+use raft::TransactionId;
+
+/// The version of the layout of `handler::Message` and of the responses of the state
+/// machine. It has to be increased whenever the layout changes, e.g. a variant is
+/// inserted or a field is added, together with a conversion from the older layout.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Marks an encoded command or response which starts with a format version. A command
+/// without the header starts with the big-endian variant index of the message, so its
+/// first byte is always 0.
+const MAGIC: &'static [u8; 4] = b"PKDB";
+
+const HEADER_LEN: usize = 6;
+
+/// The session of the commands of the first format which had no transactions
+const LEGACY_SESSION: &'static str = "00000000-0000-0000-0000-000000000000";
+
+/// Errors of decoding a command or a response
+#[derive(Debug,Clone,PartialEq)]
+pub enum WireError {
+    /// The bytes were encoded by a newer node with an unknown format version
+    UnsupportedVersion(u16),
+    /// The bytes match no known format
+    Malformed(String),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WireError::UnsupportedVersion(version) => {
+                write!(f,
+                       "The format version {} is not supported, the latest is {}",
+                       version,
+                       FORMAT_VERSION)
+            }
+            WireError::Malformed(ref reason) => write!(f, "Malformed message: {}", reason),
+        }
+    }
+}
+
+impl error::Error for WireError {
+    fn description(&self) -> &str {
+        match *self {
+            WireError::UnsupportedVersion(_) => "unsupported format version",
+            WireError::Malformed(_) => "malformed message",
+        }
+    }
+}
+
+/// The commands of the first format, before transactions and tombstones were added
+#[derive(Debug,Serialize,Deserialize)]
+enum MessageV0 {
+    Get(DocumentId),
+    Post(Document),
+    Remove(DocumentId),
+    Put(DocumentId, Vec<u8>),
+}
+
+impl MessageV0 {
+    fn upgrade(self) -> Message {
+        let session = TransactionId::from(LEGACY_SESSION).unwrap();
+
+        match self {
+            MessageV0::Get(id) => Message::Get(id),
+            MessageV0::Post(document) => Message::Post(session, document),
+            // the author and the time of the removal are unknown
+            MessageV0::Remove(id) => Message::Remove(session, id, String::new(), 0),
+            MessageV0::Put(id, payload) => Message::Put(session, id, payload),
+        }
+    }
+}
+
+/// Prefixes the body with the header of the current format version
+fn seal(body: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+
+    bytes.extend_from_slice(MAGIC);
+    bytes.push((FORMAT_VERSION >> 8) as u8);
+    bytes.push(FORMAT_VERSION as u8);
+    bytes.extend(body);
+
+    bytes
+}
+
+/// Returns the format version and the body, or `None` if the bytes have no header
+fn open(bytes: &[u8]) -> Option<(u16, &[u8])> {
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != &MAGIC[..] {
+        return None;
+    }
+
+    let version = ((bytes[4] as u16) << 8) | bytes[5] as u16;
+
+    Some((version, &bytes[HEADER_LEN..]))
+}
+
+/// Decodes a value which has to fill the bytes completely. bincode ignores trailing
+/// bytes, so a value of another layout could otherwise be decoded from a prefix.
+fn decode_exact<T>(bytes: &[u8]) -> Option<T>
+    where T: ::serde::Serialize + ::serde::Deserialize
+{
+    let value: T = match decode(bytes) {
+        Ok(value) => value,
+        Err(_) => return None,
+    };
+
+    match encode(&value, SizeLimit::Infinite) {
+        Ok(ref encoded) if encoded.as_slice() == bytes => Some(value),
+        _ => None,
+    }
+}
+
+/// Encodes a command with the current format version
+pub fn encode_command(message: &Message) -> Result<Vec<u8>, WireError> {
+    encode(message, SizeLimit::Infinite)
+        .map(seal)
+        .map_err(|err| WireError::Malformed(format!("{}", err)))
+}
+
+/// Decodes a command of the current or of an older format. Commands without a header
+/// were written before the format was versioned, so they have the layout of the first
+/// format.
+pub fn decode_command(bytes: &[u8]) -> Result<Message, WireError> {
+    match open(bytes) {
+        Some((1, body)) => {
+            decode(body).map_err(|err| WireError::Malformed(format!("{}", err)))
+        }
+        Some((version, _)) => Err(WireError::UnsupportedVersion(version)),
+        None => {
+            decode_exact::<MessageV0>(bytes)
+                .map(MessageV0::upgrade)
+                .ok_or_else(|| WireError::Malformed("unknown command".to_string()))
+        }
+    }
+}
+
+/// Prefixes a response of the state machine with the current format version
+pub fn encode_response(body: Vec<u8>) -> Vec<u8> {
+    seal(body)
+}
+
+/// Returns the body of a response. Responses without a header were sent by nodes
+/// before the format was versioned and are returned unchanged.
+pub fn decode_response(bytes: &[u8]) -> Result<&[u8], WireError> {
+    match open(bytes) {
+        Some((version, body)) if version <= FORMAT_VERSION => Ok(body),
+        Some((version, _)) => Err(WireError::UnsupportedVersion(version)),
+        None => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::MessageV0;
+    use document::{Document, DocumentError};
+    use handler::Message;
+    use bincode::serde::serialize as encode;
+    use bincode::serde::deserialize as decode;
+    use bincode::SizeLimit;
+    use uuid::Uuid;
+
+    const ID: &'static str = "3d30aa56-98b2-4891-aec5-847cee6e1703";
+
+    /// The bincode encoding of `ID`, a string with a length of 36
+    fn id_bytes() -> Vec<u8> {
+        let mut bytes = b"\x00\x00\x00\x00\x00\x00\x00\x24".to_vec();
+        bytes.extend_from_slice(ID.as_bytes());
+        bytes
+    }
+
+    fn fixture(parts: &[&[u8]]) -> Vec<u8> {
+        parts.iter().fold(Vec::new(), |mut bytes, part| {
+            bytes.extend_from_slice(part);
+            bytes
+        })
+    }
+
+    fn id() -> Uuid {
+        Uuid::parse_str(ID).unwrap()
+    }
+
+    #[test]
+    fn test_decode_first_format() {
+        let get = fixture(&[b"\x00\x00\x00\x00", &id_bytes()]);
+        match decode_command(&get) {
+            Ok(Message::Get(decoded)) => assert_eq!(id(), decoded),
+            other => panic!("Unexpected command {:?}", other),
+        }
+
+        let post = fixture(&[b"\x00\x00\x00\x01",
+                             &id_bytes(),
+                             b"\x00\x00\x00\x00\x00\x00\x00\x04test",
+                             b"\x00\x00\x00\x00\x00\x00\x00\x01"]);
+        match decode_command(&post) {
+            Ok(Message::Post(_, document)) => {
+                assert_eq!(Document {
+                               id: id(),
+                               payload: b"test".to_vec(),
+                               version: 1,
+                           },
+                           document)
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+
+        let remove = fixture(&[b"\x00\x00\x00\x02", &id_bytes()]);
+        match decode_command(&remove) {
+            Ok(Message::Remove(_, decoded, author, deleted_at)) => {
+                assert_eq!(id(), decoded);
+                assert_eq!("", author);
+                assert_eq!(0, deleted_at);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+
+        let put = fixture(&[b"\x00\x00\x00\x03",
+                            &id_bytes(),
+                            b"\x00\x00\x00\x00\x00\x00\x00\x03new"]);
+        match decode_command(&put) {
+            Ok(Message::Put(_, decoded, payload)) => {
+                assert_eq!(id(), decoded);
+                assert_eq!(b"new".to_vec(), payload);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_first_format_put() {
+        // the payload of the put looks like an uuid, so it has the layout of a current
+        // command with a session as well
+        let put = encode(&MessageV0::Put(id(), ID.as_bytes().to_vec()), SizeLimit::Infinite)
+            .unwrap();
+        match decode_command(&put) {
+            Ok(Message::Put(_, decoded, payload)) => {
+                assert_eq!(id(), decoded);
+                assert_eq!(ID.as_bytes().to_vec(), payload);
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+
+        let remove = encode(&MessageV0::Remove(id()), SizeLimit::Infinite).unwrap();
+        match decode_command(&remove) {
+            Ok(Message::Remove(_, decoded, _, _)) => assert_eq!(id(), decoded),
+            other => panic!("Unexpected command {:?}", other),
+        }
+
+        // the current layout is only decoded with a header
+        let current = encode(&Message::GetTombstones, SizeLimit::Infinite).unwrap();
+        assert!(decode_command(&current).is_err());
+    }
+
+    #[test]
+    fn test_decode_versioned_commands() {
+        let get = fixture(&[b"PKDB\x00\x01\x00\x00\x00\x00", &id_bytes()]);
+        match decode_command(&get) {
+            Ok(Message::Get(decoded)) => assert_eq!(id(), decoded),
+            other => panic!("Unexpected command {:?}", other),
+        }
+
+        let encoded = encode_command(&Message::Purge(60, 130)).unwrap();
+        assert_eq!(b"PKDB\x00\x01", &encoded[..6]);
+
+        match decode_command(&encoded) {
+            Ok(Message::Purge(60, 130)) => {}
+            other => panic!("Unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reject_unknown_commands() {
+        assert_eq!(Some(WireError::UnsupportedVersion(2)),
+                   decode_command(b"PKDB\x00\x02\x00\x00\x00\x05").err());
+
+        match decode_command(b"\x00\x00\x01\x00") {
+            Err(WireError::Malformed(_)) => {}
+            other => panic!("Unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_responses() {
+        // a `Get` response without a header
+        let legacy = fixture(&[b"\x00\x00\x00\x01\x00\x00\x00\x00", &id_bytes()]);
+        let response: Result<Document, DocumentError> =
+            decode(decode_response(&legacy).unwrap()).unwrap();
+        assert_eq!(Err(DocumentError::NotFound(id())), response);
+
+        let versioned = encode_response(legacy.clone());
+        assert_eq!(&legacy[..], decode_response(&versioned).unwrap());
+
+        assert_eq!(Err(WireError::UnsupportedVersion(2)),
+                   decode_response(b"PKDB\x00\x02\x00\x00\x00\x00"));
+    }
+}
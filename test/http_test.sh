@@ -27,6 +27,13 @@ curl --fail --verbose -b session_cookie -X GET "$url/document/$lid/$doc_id" && e
 echo "$normal"
 sleep 1
 
+curl --fail --verbose -b session_cookie -X POST -H "Content-Type: application/json" -H "Cache-Control: no-cache" -d '{
+	"ids":["'"$doc_id"'"]
+}' "$url/document/$lid/_mget" && echo "$green multi-get successful" || echo "$red multi-get failed"
+
+echo "$normal"
+sleep 1
+
 curl --fail --verbose -b session_cookie -X PUT -H "Content-Type: application/json" -H "Cache-Control: no-cache" -d '{
 	"payload":"dXBkYXRlZA=="
 }' "$url/document/$lid/document/$doc_id" && echo "$green updating document successful" || echo "$red updating document failed"